@@ -6,6 +6,7 @@ use protobuf_codegen::CustomizeCallback;
 
 fn main() {
     println!("cargo:rerun-if-changed=src/config/protos/geosite.proto");
+    println!("cargo:rerun-if-changed=src/config/protos/geoip.proto");
 
     struct GenSerde;
 
@@ -37,6 +38,7 @@ fn main() {
         .pure()
         .include("src/config/protos")
         .input("src/config/protos/geosite.proto")
+        .input("src/config/protos/geoip.proto")
         .cargo_out_dir("protos")
         .customize_callback(GenSerde)
         .run_from_script();
@@ -0,0 +1,400 @@
+use std::{
+    fmt::Write as _,
+    future::Future,
+    io::Write,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+    time::{Instant, SystemTime},
+};
+
+use anyhow::Context as _;
+use pin_project::pin_project;
+use serde::Serialize;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tracing::warn;
+use tracing_appender::{
+    non_blocking::{NonBlocking, WorkerGuard},
+    rolling::{RollingFileAppender, Rotation},
+};
+
+use crate::{
+    config::access_log::{AccessLogConfig, AccessLogFormat, AccessLogRotation},
+    proxy::{NetLocation, ProxyResponse, SendError},
+    stats::TransferMonitor,
+};
+
+#[derive(Debug, Clone, Copy, Serialize, strum::Display)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum CloseReason {
+    /// both sides finished
+    Finished,
+    /// the egress or the forwarding failed
+    Error,
+    /// closed through the control api or on shutdown
+    Aborted,
+    /// refused by the source acl of the ingress
+    Refused,
+    /// routed to `reject`
+    Rejected,
+}
+
+/// One connection, written once it's closed.
+#[derive(Debug, Serialize)]
+pub struct AccessRecord {
+    /// when the connection was dispatched, in RFC 3339
+    pub timestamp: String,
+    pub ingress: String,
+    pub client: Option<SocketAddr>,
+    /// destination as requested by the client
+    pub destination: String,
+    /// domain found by sniffing or a fake ip, if it differs from destination
+    pub host: Option<String>,
+    pub rule: Option<String>,
+    pub egress: Option<String>,
+    pub duration_ms: u64,
+    pub upload_bytes: u64,
+    pub download_bytes: u64,
+    pub close_reason: CloseReason,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl AccessRecord {
+    fn text(&self) -> String {
+        let mut line = format!(
+            "{} ingress={} client={} destination={}",
+            self.timestamp,
+            self.ingress,
+            self.client.map_or("-".to_string(), |v| v.to_string()),
+            self.destination,
+        );
+        let optional = [
+            ("host", &self.host),
+            ("rule", &self.rule),
+            ("egress", &self.egress),
+        ];
+        for (key, value) in optional {
+            let _ = write!(line, " {}={}", key, value.as_deref().unwrap_or("-"));
+        }
+        let _ = write!(
+            line,
+            " duration={}ms upload={} download={} close={}",
+            self.duration_ms, self.upload_bytes, self.download_bytes, self.close_reason,
+        );
+        if let Some(error) = &self.error {
+            let _ = write!(line, " error={:?}", error);
+        }
+        line
+    }
+}
+
+/// Writes access records to a rolling file from a background thread. The
+/// thread buffers up to 128k lines, records are dropped beyond that rather
+/// than blocking the connections that write them.
+#[derive(Debug)]
+pub struct AccessLog {
+    format: AccessLogFormat,
+    writer: NonBlocking,
+    _guard: WorkerGuard,
+}
+
+impl AccessLog {
+    pub fn new(config: AccessLogConfig) -> Result<Self, anyhow::Error> {
+        Self::check(&config)?;
+        let rotation = match config.rotation {
+            AccessLogRotation::Minutely => Rotation::MINUTELY,
+            AccessLogRotation::Hourly => Rotation::HOURLY,
+            AccessLogRotation::Daily => Rotation::DAILY,
+            AccessLogRotation::Never => Rotation::NEVER,
+        };
+        let appender = RollingFileAppender::builder()
+            .rotation(rotation)
+            .filename_prefix(config.name.unwrap_or("access.log".to_string()))
+            .build(&config.path)
+            .with_context(|| format!("Failed to open access log in {}", config.path.display()))?;
+        let (writer, guard) = tracing_appender::non_blocking(appender);
+        Ok(Self {
+            format: config.format,
+            writer,
+            _guard: guard,
+        })
+    }
+
+    /// The directory is created when missing, anything else in its place
+    /// is an error.
+    pub fn check(config: &AccessLogConfig) -> Result<(), anyhow::Error> {
+        if config.path.exists() && !config.path.is_dir() {
+            anyhow::bail!(
+                "access log path {} isn't a directory",
+                config.path.display()
+            );
+        }
+        Ok(())
+    }
+
+    pub fn write(&self, record: &AccessRecord) {
+        let mut line = match self.format {
+            AccessLogFormat::Json => match serde_json::to_string(record) {
+                Ok(line) => line,
+                Err(e) => {
+                    warn!("serialize access record failed: {:?}", e);
+                    return;
+                }
+            },
+            AccessLogFormat::Text => record.text(),
+        };
+        line.push('\n');
+        // a line is a single message to the worker, lines never interleave
+        if let Err(e) = self.writer.clone().write_all(line.as_bytes()) {
+            warn!("write access log failed: {:?}", e);
+        }
+    }
+}
+
+/// A connection being forwarded, its record is written when the entry is
+/// finished or dropped.
+#[derive(Debug)]
+pub struct AccessEntry {
+    log: Arc<AccessLog>,
+    started_at: SystemTime,
+    start: Instant,
+    ingress: String,
+    client: Option<SocketAddr>,
+    destination: NetLocation,
+    remote: NetLocation,
+    rule: Option<String>,
+    egress: Option<String>,
+    monitor: Option<Arc<TransferMonitor>>,
+    written: bool,
+}
+
+impl AccessEntry {
+    pub fn new(
+        log: Arc<AccessLog>,
+        ingress: String,
+        client: Option<SocketAddr>,
+        destination: NetLocation,
+        remote: NetLocation,
+    ) -> Self {
+        Self {
+            log,
+            started_at: SystemTime::now(),
+            start: Instant::now(),
+            ingress,
+            client,
+            destination,
+            remote,
+            rule: None,
+            egress: None,
+            monitor: None,
+            written: false,
+        }
+    }
+
+    pub fn route(mut self, rule: String, egress: String) -> Self {
+        self.rule = Some(rule);
+        self.egress = Some(egress);
+        self
+    }
+
+    /// Live counters of the connection, reported if it's aborted.
+    pub fn monitor(mut self, monitor: Arc<TransferMonitor>) -> Self {
+        self.monitor = Some(monitor);
+        self
+    }
+
+    /// Logs the response of `f` once it completes, or an aborted connection
+    /// if it's dropped before.
+    pub fn watch<F>(self, f: F) -> Watched<F>
+    where
+        F: Future<Output = Result<ProxyResponse, SendError>>,
+    {
+        Watched {
+            entry: Some(self),
+            f,
+        }
+    }
+
+    /// Logs a connection that was never forwarded.
+    pub fn close(mut self, reason: CloseReason, error: Option<String>) {
+        self.write(reason, 0, 0, error);
+    }
+
+    fn write(&mut self, reason: CloseReason, upload: u64, download: u64, error: Option<String>) {
+        let host = (self.remote != self.destination).then(|| self.remote.address.to_string());
+        let record = AccessRecord {
+            timestamp: OffsetDateTime::from(self.started_at)
+                .format(&Rfc3339)
+                .unwrap_or_default(),
+            ingress: self.ingress.clone(),
+            client: self.client,
+            destination: self.destination.to_string(),
+            host,
+            rule: self.rule.clone(),
+            egress: self.egress.clone(),
+            duration_ms: self.start.elapsed().as_millis() as u64,
+            upload_bytes: upload,
+            download_bytes: download,
+            close_reason: reason,
+            error,
+        };
+        self.log.write(&record);
+        self.written = true;
+    }
+
+    fn transferred(&self) -> (u64, u64) {
+        let stats = self
+            .monitor
+            .as_ref()
+            .and_then(|monitor| monitor.try_get_transfer_stats())
+            .unwrap_or_default();
+        (stats.tx as u64, stats.rx as u64)
+    }
+}
+
+impl Drop for AccessEntry {
+    fn drop(&mut self) {
+        if !self.written {
+            let (upload, download) = self.transferred();
+            self.write(CloseReason::Aborted, upload, download, None);
+        }
+    }
+}
+
+/// The forwarding of a connection with its access entry. The entry is
+/// dropped before the forwarding, so the counters are still alive then.
+#[pin_project]
+#[derive(Debug)]
+pub struct Watched<F> {
+    entry: Option<AccessEntry>,
+    #[pin]
+    f: F,
+}
+
+impl<F> Future for Watched<F>
+where
+    F: Future<Output = Result<ProxyResponse, SendError>>,
+{
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let result = ready!(this.f.poll(cx));
+        if let Some(mut entry) = this.entry.take() {
+            match &result {
+                Ok(resp) => entry.write(
+                    CloseReason::Finished,
+                    resp.upload_bytes,
+                    resp.download_bytes,
+                    None,
+                ),
+                Err(e) => {
+                    let (upload, download) = entry.transferred();
+                    entry.write(CloseReason::Error, upload, download, Some(e.to_string()))
+                }
+            }
+        }
+        Poll::Ready(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, str::FromStr};
+
+    use super::*;
+
+    fn log(format: AccessLogFormat, name: &str) -> (Arc<AccessLog>, std::path::PathBuf) {
+        let path = std::env::temp_dir().join(format!("mproxy-access-log-{}", std::process::id()));
+        let _ = fs::remove_file(path.join(name));
+        let log = Arc::new(
+            AccessLog::new(AccessLogConfig {
+                path: path.clone(),
+                name: Some(name.to_string()),
+                format,
+                rotation: AccessLogRotation::Never,
+            })
+            .unwrap(),
+        );
+        (log, path.join(name))
+    }
+
+    fn entry(log: Arc<AccessLog>) -> AccessEntry {
+        AccessEntry::new(
+            log,
+            "socks".to_string(),
+            Some("127.0.0.1:4000".parse().unwrap()),
+            NetLocation::from_str("1.1.1.1:443").unwrap(),
+            NetLocation::from_str("example.com:443").unwrap(),
+        )
+        .route("default".to_string(), "direct".to_string())
+    }
+
+    #[tokio::test]
+    async fn json_lines() {
+        let (log, file) = log(AccessLogFormat::Json, "json.log");
+        let resp = entry(log.clone())
+            .watch(async {
+                Ok(ProxyResponse {
+                    upload_bytes: 10,
+                    download_bytes: 20,
+                })
+            })
+            .await;
+        assert!(resp.is_ok());
+        // dropped before forwarding finished
+        drop(entry(log.clone()).watch(std::future::pending::<Result<ProxyResponse, SendError>>()));
+        drop(log);
+
+        let content = fs::read_to_string(file).unwrap();
+        let lines: Vec<serde_json::Value> = content
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["destination"], "1.1.1.1:443");
+        assert_eq!(lines[0]["host"], "example.com");
+        assert_eq!(lines[0]["rule"], "default");
+        assert_eq!(lines[0]["upload_bytes"], 10);
+        assert_eq!(lines[0]["download_bytes"], 20);
+        assert_eq!(lines[0]["close_reason"], "finished");
+        assert_eq!(lines[1]["close_reason"], "aborted");
+    }
+
+    #[tokio::test]
+    async fn text() {
+        let (log, file) = log(AccessLogFormat::Text, "text.log");
+        entry(log.clone()).close(CloseReason::Rejected, None);
+        drop(log);
+
+        let content = fs::read_to_string(file).unwrap();
+        // the duration depends on the clock
+        let (head, tail) = content.split_once(" duration=").unwrap();
+        assert!(head.ends_with(
+            " ingress=socks client=127.0.0.1:4000 destination=1.1.1.1:443 host=example.com \
+             rule=default egress=direct"
+        ));
+        assert_eq!(
+            tail.split_once("ms ").unwrap().1,
+            "upload=0 download=0 close=rejected\n"
+        );
+    }
+
+    #[test]
+    fn not_a_directory() {
+        let path = std::env::temp_dir().join(format!("mproxy-access-file-{}", std::process::id()));
+        fs::write(&path, "").unwrap();
+        let config = AccessLogConfig {
+            path: path.clone(),
+            name: None,
+            format: AccessLogFormat::Json,
+            rotation: AccessLogRotation::Never,
+        };
+        assert!(AccessLog::check(&config).is_err());
+        assert!(AccessLog::new(config).is_err());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -1,68 +1,633 @@
 use anyhow::Context;
 use futures::{future::try_join_all, FutureExt};
-use std::{sync::Arc, time::Instant};
-use tokio::sync::mpsc;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::SocketAddr,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+use tokio::{sync::mpsc, task::JoinHandle};
 use tokio_stream::StreamExt;
 use tracing::{info, info_span, warn, Instrument};
 
 use super::{
-    proxy::{Egress, Ingress, ProxyRequest, ProxyResponse},
-    router::Router,
-    stats::Stats,
+    access_log::{AccessEntry, AccessLog, CloseReason},
+    check::check,
+    config::{
+        egress::{ClientConfig, EgressConfig},
+        health::HealthCheckConfig,
+        ingress::IngressConfig,
+        limit::RateLimitConfig,
+        routing::RoutingConfig,
+    },
+    conntrack::{ConnectionInfo, ConnectionTracker},
+    control::ControlServer,
+    dns::DnsServer,
+    health::HealthChecker,
+    io::{RateLimitedIO, RateLimiter},
+    proxy::{
+        sniff, Address, Admission, Admitted, Egress, EgressGroup, Ingress, NetLocation, Outbound,
+        PacSource, ProxyConn, ProxyRequest, ProxyResponse, SendError, SourceAcl, TcpForwarder,
+        REJECT,
+    },
+    router::{RouteDecision, Router},
+    stats::{HealthStatus, Stats, TopTable, TransferMonitor, TransferStats},
     AppConfig,
 };
 
+/// How long a replaced ingress may take to release its listener on reload.
+const REBIND_TIMEOUT: Duration = Duration::from_secs(1);
+const REBIND_INTERVAL: Duration = Duration::from_millis(50);
+/// Keys kept per traffic table, and how many of them `stats` reports.
+const TRAFFIC_TABLE_CAPACITY: usize = 1024;
+const STATS_TOP_N: usize = 10;
+/// How often the bytes of a live connection are credited, and how long it
+/// takes the credited bytes to count half.
+const CREDIT_INTERVAL: Duration = Duration::from_secs(5);
+const TRAFFIC_HALF_LIFE: Duration = Duration::from_secs(600);
+
+/// Configs are compared by their serialized form on reload, one that can't be
+/// serialized is always rebuilt.
+fn fingerprint<T: Serialize>(config: &T) -> Option<toml::Value> {
+    toml::Value::try_from(config).ok()
+}
+
+/// Egresses and router, swapped as a whole on reload. Requests in flight
+/// keep the egress they were dispatched to.
 #[derive(Debug)]
-pub struct App {
-    ingress: Vec<Arc<Ingress>>,
+struct Outbounds {
     egress: Vec<Arc<Egress>>,
-    router: Router,
+    groups: Vec<Arc<EgressGroup>>,
+    /// by egress or group id
+    limiters: HashMap<String, Arc<RateLimiter>>,
+    /// by rule id
+    rule_limiters: HashMap<String, Arc<RateLimiter>>,
+    router: Arc<Router>,
+    routing: RoutingConfig,
+    fingerprints: HashMap<String, toml::Value>,
+}
+
+impl Outbounds {
+    async fn new(
+        egress: Vec<EgressConfig>,
+        routing: RoutingConfig,
+        old: Option<&Outbounds>,
+    ) -> Result<Self, anyhow::Error> {
+        let limiters = limiters_by_id(
+            egress
+                .iter()
+                .map(|config| (&config.id, config.limit.as_ref())),
+            old.map(|old| &old.limiters),
+        )?;
+        let rule_limiters = limiters_by_id(
+            routing
+                .rule
+                .iter()
+                .map(|config| (&config.id, config.limit.as_ref())),
+            old.map(|old| &old.rule_limiters),
+        )?;
+
+        let (groups, egress): (Vec<_>, Vec<_>) = egress
+            .into_iter()
+            .partition(|config| matches!(config.client, ClientConfig::Group(_)));
+
+        let mut fingerprints = HashMap::new();
+        for config in &egress {
+            if let Some(fingerprint) = fingerprint(config) {
+                fingerprints.insert(config.id.clone(), fingerprint);
+            }
+        }
+
+        let egress = try_join_all(egress.into_iter().map(|config| {
+            let unchanged = old.and_then(|old| {
+                old.fingerprints
+                    .get(&config.id)
+                    .filter(|v| fingerprints.get(&config.id) == Some(*v))
+                    .and_then(|_| old.egress.iter().find(|egress| egress.id == config.id))
+                    .cloned()
+            });
+            async move {
+                match unchanged {
+                    Some(egress) => Ok(egress),
+                    None => Egress::new(config).await.map(Arc::new),
+                }
+            }
+        }))
+        .await?;
+        let groups = groups
+            .into_iter()
+            .map(|config| match config.client {
+                ClientConfig::Group(group) => {
+                    EgressGroup::new(config.id, group, &egress, &limiters).map(Arc::new)
+                }
+                _ => unreachable!(),
+            })
+            .try_collect()?;
+        let router = Arc::new(Router::new(routing.clone())?);
+
+        Ok(Self {
+            egress,
+            groups,
+            limiters,
+            rule_limiters,
+            router,
+            routing,
+            fingerprints,
+        })
+    }
+
+    fn outbound(&self, id: &str) -> Option<Outbound> {
+        self.egress
+            .iter()
+            .find(|egress| egress.id == id)
+            .map(|egress| Outbound::Egress(egress.clone()))
+            .or_else(|| {
+                self.groups
+                    .iter()
+                    .find(|group| group.id == id)
+                    .map(|group| Outbound::Group(group.clone()))
+            })
+    }
+}
+
+/// Limiters by id. One whose limit didn't change is taken over from the old
+/// config, connections from before and after a reload share its budget.
+fn limiters_by_id<'a>(
+    limits: impl Iterator<Item = (&'a String, Option<&'a RateLimitConfig>)>,
+    old: Option<&HashMap<String, Arc<RateLimiter>>>,
+) -> Result<HashMap<String, Arc<RateLimiter>>, anyhow::Error> {
+    limits
+        .filter_map(|(id, limit)| Some((id, limit?)))
+        .map(|(id, limit)| {
+            let old = old
+                .and_then(|old| old.get(id))
+                .filter(|limiter| limiter.config() == limit);
+            let limiter = match old {
+                Some(limiter) => limiter.clone(),
+                None => Arc::new(RateLimiter::new(limit).with_context(|| format!("{} limit", id))?),
+            };
+            Ok((id.clone(), limiter))
+        })
+        .collect()
+}
+
+type SharedOutbounds = Arc<RwLock<Arc<Outbounds>>>;
+
+/// Lets an ingress refuse requests from sources outside of its acl and to
+/// destinations routed to `reject`, the route of an admitted request goes
+/// along with it. Fake ips aren't resolved here, such requests are routed
+/// and closed at dispatch instead. Also renders the rules as PAC.
+#[derive(Debug)]
+struct Gate {
+    ingress: String,
+    /// also checked at dispatch for requests the ingress didn't ask about
+    acl: Arc<SourceAcl>,
+    outbounds: SharedOutbounds,
+}
+
+impl Admission for Gate {
+    fn admit(&self, source: SocketAddr, remote: &NetLocation) -> Option<Admitted> {
+        if !self.permits(source) {
+            return None;
+        }
+        let outbounds = self.outbounds.read().unwrap().clone();
+        // one that can't be routed is admitted, dispatch reports the error
+        let route = outbounds.router.explain(&self.ingress, remote).ok();
+        if route.as_ref().is_some_and(|route| route.dest == REJECT) {
+            return None;
+        }
+        Some(Admitted { route })
+    }
+
+    fn permits(&self, source: SocketAddr) -> bool {
+        self.acl.permits(Some(source.ip()))
+    }
+}
+
+impl PacSource for Gate {
+    /// Direct egresses are left to the browser, everything else including
+    /// rejected hosts goes through the proxy to be routed here.
+    fn pac(&self, proxy: &str) -> String {
+        let outbounds = self.outbounds.read().unwrap().clone();
+        let proxy = format!("PROXY {}", proxy);
+        outbounds.router.pac(&self.ingress, |dest| {
+            match outbounds.egress.iter().find(|egress| egress.id == dest) {
+                Some(egress) if egress.is_direct() => "DIRECT".to_string(),
+                _ => proxy.clone(),
+            }
+        })
+    }
+}
+
+/// Bytes forwarded, credited to the matched rule and to the destination host
+/// while connections are going. Older bytes count less and less, so the
+/// tables follow recent traffic.
+#[derive(Debug)]
+struct Traffic {
+    rules: TopTable,
+    hosts: TopTable,
+    decayed_at: Instant,
+}
+
+impl Traffic {
+    fn new() -> Self {
+        Self {
+            rules: TopTable::new(TRAFFIC_TABLE_CAPACITY),
+            hosts: TopTable::new(TRAFFIC_TABLE_CAPACITY),
+            decayed_at: Instant::now(),
+        }
+    }
+
+    fn credit(&mut self, rule: &str, host: &str, stats: TransferStats) {
+        #[cfg(feature = "telemetry")]
+        crate::metrics::record_rule_transfer(rule, &stats);
+
+        self.decay();
+        self.rules.add(rule, stats.clone());
+        self.hosts.add(host, stats);
+    }
+
+    /// Halves the tables once per half-life passed since the last time.
+    fn decay(&mut self) {
+        while self.decayed_at.elapsed() >= TRAFFIC_HALF_LIFE {
+            self.decayed_at += TRAFFIC_HALF_LIFE;
+            self.rules.decay();
+            self.hosts.decay();
+        }
+    }
+
+    fn top_rules(&mut self, n: usize) -> Vec<(String, TransferStats)> {
+        self.decay();
+        self.rules.top(n)
+    }
+
+    fn top_hosts(&mut self, n: usize) -> Vec<(String, TransferStats)> {
+        self.decay();
+        self.hosts.top(n)
+    }
+}
+
+/// Runs the forwarding of a connection, crediting the bytes its forwarders
+/// moved every `CREDIT_INTERVAL` and the rest once it's done.
+async fn credit_while_sending(
+    send: impl Future<Output = Result<ProxyResponse, SendError>>,
+    monitor: &TransferMonitor,
+    traffic: &Mutex<Traffic>,
+    rule: &str,
+    host: &str,
+) -> Result<ProxyResponse, SendError> {
+    let mut credited = TransferStats::new();
+    let mut credit = |total: TransferStats| {
+        let mut delta = total.clone();
+        delta -= credited.clone();
+        // forwarders that are done leave the monitor, never credit twice
+        credited.tx = credited.tx.max(total.tx);
+        credited.rx = credited.rx.max(total.rx);
+        if delta.tx + delta.rx > 0 {
+            traffic.lock().unwrap().credit(rule, host, delta);
+        }
+    };
+
+    let mut interval = tokio::time::interval(CREDIT_INTERVAL);
+    tokio::pin!(send);
+    let result = loop {
+        tokio::select! {
+            result = &mut send => break result,
+            _ = interval.tick() => {
+                if let Some(stats) = monitor.try_get_transfer_stats() {
+                    credit(stats);
+                }
+            }
+        }
+    };
+    if let Ok(response) = &result {
+        credit(TransferStats {
+            tx: response.upload_bytes as usize,
+            rx: response.download_bytes as usize,
+        });
+    }
+    result
+}
+
+/// An ingress whose requests are being forwarded to dispatch, dropping it
+/// closes the listener while accepted connections keep going.
+#[derive(Debug)]
+struct RunningIngress {
+    ingress: Arc<Ingress>,
+    gate: Arc<Gate>,
+    fingerprint: Option<toml::Value>,
+    handle: JoinHandle<()>,
+}
+
+impl Drop for RunningIngress {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+#[derive(Debug)]
+struct RunningHealthChecker {
+    checker: Arc<HealthChecker>,
+    handle: JoinHandle<()>,
+}
+
+impl Drop for RunningHealthChecker {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+#[derive(Debug)]
+pub struct App {
+    ingress: Mutex<Vec<RunningIngress>>,
+    outbounds: SharedOutbounds,
+    dns: Option<Arc<DnsServer>>,
+    health: Mutex<Option<RunningHealthChecker>>,
+    connections: Arc<ConnectionTracker>,
+    traffic: Arc<Mutex<Traffic>>,
+    control: Option<ControlServer>,
+    access_log: Option<Arc<AccessLog>>,
+    tx: mpsc::UnboundedSender<(String, ProxyRequest)>,
+    rx: tokio::sync::Mutex<Option<mpsc::UnboundedReceiver<(String, ProxyRequest)>>>,
+    /// serializes reloads
+    reloading: tokio::sync::Mutex<()>,
 }
 
 impl App {
     pub async fn new(config: AppConfig) -> Result<Self, anyhow::Error> {
+        let outbounds = Outbounds::new(config.egress, config.routing, None).await?;
+        let dns = match config.dns {
+            Some(config) => Some(Arc::new(
+                DnsServer::new(config, outbounds.router.clone(), &outbounds.egress).await?,
+            )),
+            None => None,
+        };
+        let health = config
+            .health_check
+            .map(|config| Self::start_health_checker(config, &outbounds.egress))
+            .transpose()?;
+
+        let control = match config.control {
+            Some(config) => Some(ControlServer::new(config, &outbounds.routing).await?),
+            None => None,
+        };
+
+        let traffic = Arc::new(Mutex::new(Traffic::new()));
+        #[cfg(feature = "telemetry")]
+        {
+            let traffic = Arc::downgrade(&traffic);
+            crate::metrics::set_top_hosts(move || {
+                traffic
+                    .upgrade()
+                    .map(|traffic| traffic.lock().unwrap().top_hosts(STATS_TOP_N))
+                    .unwrap_or_default()
+            });
+        }
+
+        let outbounds = Arc::new(RwLock::new(Arc::new(outbounds)));
+        let (tx, rx) = mpsc::unbounded_channel();
+        let ingress = try_join_all(
+            config
+                .ingress
+                .into_iter()
+                .map(|config| Self::start_ingress(config, tx.clone(), outbounds.clone())),
+        )
+        .await?;
+
         Ok(Self {
-            ingress: try_join_all(
-                config
-                    .ingress
-                    .into_iter()
-                    .map(|config| Ingress::new(config).map(|v| v.map(|v| Arc::new(v)))),
-            )
-            .await?,
-            egress: try_join_all(
-                config
-                    .egress
-                    .into_iter()
-                    .map(|config| Egress::new(config).map(|v| v.map(|v| Arc::new(v)))),
-            )
-            .await?,
-            router: Router::new(config.routing)?,
+            ingress: Mutex::new(ingress),
+            outbounds,
+            dns,
+            health: Mutex::new(health),
+            connections: Arc::new(ConnectionTracker::new()),
+            traffic,
+            control,
+            access_log: config
+                .access_log
+                .map(AccessLog::new)
+                .transpose()?
+                .map(Arc::new),
+            tx,
+            rx: tokio::sync::Mutex::new(Some(rx)),
+            reloading: tokio::sync::Mutex::new(()),
         })
     }
 
     pub async fn run(&self) -> Result<(), anyhow::Error> {
-        let (tx, rx) = mpsc::unbounded_channel();
-        self.run_ingress(tx);
-        self.dispatch(rx).await
+        let rx = self
+            .rx
+            .lock()
+            .await
+            .take()
+            .context("App is already running")?;
+        if let Some(dns) = &self.dns {
+            tokio::spawn(dns.clone().run());
+        }
+        match &self.control {
+            Some(control) => tokio::select! {
+                result = self.dispatch(rx) => result,
+                _ = control.serve(self) => unreachable!("control server stopped"),
+            },
+            None => self.dispatch(rx).await,
+        }
+    }
+
+    /// Applies a new config in place. Unchanged ingresses and egresses are
+    /// kept, changed ones are rebuilt and the router is replaced at once.
+    /// Connections already dispatched finish on their old egress. The dns
+    /// server only picks up the new router, its own settings and the access
+    /// log need a restart. A config that doesn't pass `check` is refused.
+    pub async fn reload(&self, config: AppConfig) -> Result<(), anyhow::Error> {
+        let _reloading = self.reloading.lock().await;
+
+        // e.g. a rule pointing at a removed egress would fail every request
+        // it matches
+        let errors = check(&config);
+        if !errors.is_empty() {
+            anyhow::bail!(
+                "Invalid config: {}",
+                errors
+                    .iter()
+                    .map(|e| format!("{:#}", e))
+                    .collect::<Vec<_>>()
+                    .join("; ")
+            );
+        }
+
+        // build everything fallible before touching the running state
+        let old = self.outbounds();
+        let outbounds = Arc::new(Outbounds::new(config.egress, config.routing, Some(&old)).await?);
+        let health = config
+            .health_check
+            .map(|config| Self::start_health_checker(config, &outbounds.egress))
+            .transpose()?;
+
+        *self.outbounds.write().unwrap() = outbounds.clone();
+        if let Some(dns) = &self.dns {
+            dns.set_router(outbounds.router.clone());
+        }
+        *self.health.lock().unwrap() = health;
+
+        let mut ids = Vec::new();
+        let mut errors = Vec::new();
+        for config in config.ingress {
+            ids.push(config.id.clone());
+            let fingerprint = fingerprint(&config);
+            let unchanged = fingerprint.is_some()
+                && self.ingress.lock().unwrap().iter().any(|running| {
+                    running.ingress.id == config.id && running.fingerprint == fingerprint
+                });
+            if unchanged {
+                continue;
+            }
+
+            // release the listener of the old one before binding again
+            self.ingress
+                .lock()
+                .unwrap()
+                .retain(|running| running.ingress.id != config.id);
+            info!("Starting ingress {}", config.id);
+            match Self::rebind_ingress(config, self.tx.clone(), self.outbounds.clone()).await {
+                Ok(v) => self.ingress.lock().unwrap().push(v),
+                Err(e) => errors.push(e),
+            }
+        }
+        self.ingress.lock().unwrap().retain(|running| {
+            let keep = ids.contains(&running.ingress.id);
+            if !keep {
+                info!("Stopping ingress {}", running.ingress.id);
+            }
+            keep
+        });
+
+        match errors.into_iter().next() {
+            Some(e) => Err(e.context("Some ingress failed to restart")),
+            None => Ok(()),
+        }
     }
 
-    pub fn router(&self) -> &Router {
-        &self.router
+    /// Rebuilds the router from the current routing config, re-reading its
+    /// resource files.
+    pub async fn rebuild_router(&self) -> Result<(), anyhow::Error> {
+        let _reloading = self.reloading.lock().await;
+
+        let old = self.outbounds();
+        let outbounds = Arc::new(Outbounds {
+            egress: old.egress.clone(),
+            groups: old.groups.clone(),
+            limiters: old.limiters.clone(),
+            rule_limiters: old.rule_limiters.clone(),
+            router: Arc::new(Router::new(old.routing.clone())?),
+            routing: old.routing.clone(),
+            fingerprints: old.fingerprints.clone(),
+        });
+        *self.outbounds.write().unwrap() = outbounds.clone();
+        if let Some(dns) = &self.dns {
+            dns.set_router(outbounds.router.clone());
+        }
+        Ok(())
+    }
+
+    fn outbounds(&self) -> Arc<Outbounds> {
+        self.outbounds.read().unwrap().clone()
+    }
+
+    pub fn router(&self) -> Arc<Router> {
+        self.outbounds().router.clone()
+    }
+
+    pub fn ingress(&self) -> Vec<Arc<Ingress>> {
+        self.ingress
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|running| running.ingress.clone())
+            .collect()
+    }
+
+    pub fn egress(&self) -> Vec<Arc<Egress>> {
+        self.outbounds().egress.clone()
+    }
+
+    pub fn groups(&self) -> Vec<Arc<EgressGroup>> {
+        self.outbounds().groups.clone()
     }
 
     pub async fn stats(&self) -> Result<Stats, anyhow::Error> {
         let mut stats = Stats::default();
-        for egress in self.egress.iter() {
+        for egress in self.outbounds().egress.iter() {
             stats
                 .transfer
                 .insert(egress.id.clone(), egress.get_transfor_stats().await?);
         }
+        stats.rules = self.top_rules(STATS_TOP_N);
+        stats.hosts = self.top_hosts(STATS_TOP_N);
         Ok(stats)
     }
 
-    fn run_ingress(&self, tx: mpsc::UnboundedSender<(String, ProxyRequest)>) {
-        for ingress in &self.ingress {
-            let tx = tx.clone();
+    /// The `n` rules that forwarded the most bytes, busiest first.
+    pub fn top_rules(&self, n: usize) -> Vec<(String, TransferStats)> {
+        self.traffic.lock().unwrap().top_rules(n)
+    }
+
+    /// The `n` destination hosts that forwarded the most bytes, busiest
+    /// first.
+    pub fn top_hosts(&self, n: usize) -> Vec<(String, TransferStats)> {
+        self.traffic.lock().unwrap().top_hosts(n)
+    }
+
+    /// Last health check result of every egress, empty if health checking
+    /// isn't configured.
+    pub async fn health(&self) -> HashMap<String, HealthStatus> {
+        let checker = self
+            .health
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|running| running.checker.clone());
+        match checker {
+            Some(checker) => checker.status().await,
+            None => HashMap::new(),
+        }
+    }
+
+    /// Connections being forwarded, with their byte counters.
+    pub async fn connections(&self) -> Vec<ConnectionInfo> {
+        self.connections.list().await
+    }
+
+    /// Closes a connection by id, returns false if it has already finished or
+    /// hasn't started yet.
+    pub fn close_connection(&self, id: u64) -> bool {
+        self.connections.close(id)
+    }
+
+    fn start_health_checker(
+        config: HealthCheckConfig,
+        egress: &[Arc<Egress>],
+    ) -> Result<RunningHealthChecker, anyhow::Error> {
+        let checker = Arc::new(HealthChecker::new(config, egress)?);
+        Ok(RunningHealthChecker {
+            handle: tokio::spawn(checker.clone().run()),
+            checker,
+        })
+    }
+
+    async fn start_ingress(
+        config: IngressConfig,
+        tx: mpsc::UnboundedSender<(String, ProxyRequest)>,
+        outbounds: SharedOutbounds,
+    ) -> Result<RunningIngress, anyhow::Error> {
+        let fingerprint = fingerprint(&config);
+        let gate = Arc::new(Gate {
+            ingress: config.id.clone(),
+            acl: Arc::new(SourceAcl::new(config.allow.clone(), config.deny.clone())),
+            outbounds,
+        });
+        let ingress = Arc::new(Ingress::new(config, gate.clone(), gate.clone()).await?);
+        let handle = {
             let ingress = ingress.clone();
             tokio::spawn(async move {
                 match ingress.incoming().await {
@@ -77,7 +642,32 @@ impl App {
                         warn!("{:?}", e);
                     }
                 }
-            });
+            })
+        };
+        Ok(RunningIngress {
+            ingress,
+            gate,
+            fingerprint,
+            handle,
+        })
+    }
+
+    /// The accept loop of a stopped ingress closes its listener only once it
+    /// notices, binding the same address may fail until then.
+    async fn rebind_ingress(
+        config: IngressConfig,
+        tx: mpsc::UnboundedSender<(String, ProxyRequest)>,
+        outbounds: SharedOutbounds,
+    ) -> Result<RunningIngress, anyhow::Error> {
+        let deadline = Instant::now() + REBIND_TIMEOUT;
+        loop {
+            match Self::start_ingress(config.clone(), tx.clone(), outbounds.clone()).await {
+                Err(e) if Instant::now() < deadline => {
+                    warn!("Failed to start ingress {}, retrying: {:?}", config.id, e);
+                    tokio::time::sleep(REBIND_INTERVAL).await;
+                }
+                result => return result,
+            }
         }
     }
 
@@ -85,52 +675,365 @@ impl App {
         &self,
         mut rx: mpsc::UnboundedReceiver<(String, ProxyRequest)>,
     ) -> Result<(), anyhow::Error> {
-        while let Some((source, req)) = rx.recv().await {
-            match self.router.route(&source, &req.remote.address) {
-                Ok(dest) => {
-                    let egress = self
-                        .egress
-                        .iter()
-                        .find(|egress| egress.id == dest)
-                        .context(format!("Egress {} isn't exist", dest))?
-                        .clone();
+        // sniffing waits on the client, requests come back here once it's done
+        let (sniffed_tx, mut sniffed_rx) = mpsc::unbounded_channel();
+        loop {
+            let (source, mut req, destination) = tokio::select! {
+                incoming = rx.recv() => {
+                    let Some((source, mut req)) = incoming else {
+                        break;
+                    };
+                    let destination = req.remote.clone();
+                    if let (Some(dns), Address::Ip(ip)) = (&self.dns, &req.remote.address) {
+                        if let Some(domain) = dns.lookup_fake_ip(ip) {
+                            req.remote.address = Address::Hostname(domain);
+                            req.route = None;
+                        }
+                    }
+
+                    if matches!(req.remote.address, Address::Ip(_))
+                        && matches!(req.conn, ProxyConn::ForwardTcp(_))
+                        && self.ingress.lock().unwrap().iter().any(|running| {
+                            running.ingress.id == source && running.ingress.sniff
+                        })
+                    {
+                        let tx = sniffed_tx.clone();
+                        tokio::spawn(async move {
+                            let _ = tx.send((source, sniff(req).await, destination));
+                        });
+                        continue;
+                    }
+                    (source, req, destination)
+                }
+                Some(sniffed) = sniffed_rx.recv() => sniffed,
+            };
+
+            // a routed request was admitted by the ingress, acl included
+            let refused = req.route.is_none()
+                && self.ingress.lock().unwrap().iter().any(|running| {
+                    running.ingress.id == source
+                        && !running.gate.acl.permits(req.source.map(|v| v.ip()))
+                });
+            let entry = self.access_log.clone().map(|log| {
+                AccessEntry::new(
+                    log,
+                    source.clone(),
+                    req.source,
+                    destination,
+                    req.remote.clone(),
+                )
+            });
+            if refused {
+                warn!(source, client = ?req.source, "request refused by ingress acl");
+                reject(req);
+                if let Some(entry) = entry {
+                    entry.close(CloseReason::Refused, None);
+                }
+                continue;
+            }
+
+            let outbounds = self.outbounds();
+            let route = match req.route.take() {
+                Some(route) => Ok(route),
+                None => outbounds.router.explain(&source, &req.remote),
+            };
+            match route {
+                Ok(RouteDecision { rule, dest, .. }) if dest == REJECT => {
+                    info!(
+                        source,
+                        remote = req.remote.to_string(),
+                        "request rejected by rule"
+                    );
+                    if let Some(entry) = entry {
+                        entry.route(rule, dest).close(CloseReason::Rejected, None);
+                    }
+                    reject(req);
+                }
+                Ok(RouteDecision { rule, dest, .. }) => {
+                    let Some(outbound) = outbounds.outbound(&dest) else {
+                        warn!(source, dest, "request dropped, egress isn't exist");
+                        if let Some(entry) = entry {
+                            entry.close(
+                                CloseReason::Error,
+                                Some(format!("egress {} isn't exist", dest)),
+                            );
+                        }
+                        reject(req);
+                        continue;
+                    };
 
                     let remote = req.remote.clone();
+                    let tracked = self.connections.track(
+                        req.source,
+                        source.clone(),
+                        remote.clone(),
+                        rule.clone(),
+                        dest.clone(),
+                    );
+                    let traffic = self.traffic.clone();
+
+                    let limiters: Vec<_> = self
+                        .ingress
+                        .lock()
+                        .unwrap()
+                        .iter()
+                        .find(|running| running.ingress.id == source)
+                        .and_then(|running| running.ingress.limiter.clone())
+                        .into_iter()
+                        .chain(outbounds.rule_limiters.get(&rule).cloned())
+                        .chain(outbounds.limiters.get(&dest).cloned())
+                        .collect();
+                    let req = limit_rate(req, limiters);
+                    let id = tracked.id();
+                    let entry = entry.map(|entry| {
+                        entry
+                            .route(rule.clone(), dest.clone())
+                            .monitor(tracked.monitor())
+                    });
 
                     let span = {
                         let dest = dest.clone();
                         info_span!(
                             "handle_proxy_request",
+                            id,
                             remote = remote.to_string(),
                             source,
                             dest,
                         )
                     };
-                    tokio::spawn(
-                        async move {
-                            info!("start processing proxy request");
-                            let now = Instant::now();
-                            match egress.send(req).await {
-                                Ok(ProxyResponse {
-                                    upload_bytes,
-                                    download_bytes,
-                                }) => {
-                                    info!(
-                                        spent_time = format!("{}ms", now.elapsed().as_millis()),
-                                        upload_bytes, download_bytes, "proxy request finished"
-                                    );
+                    let monitor = tracked.monitor();
+                    let handle = tokio::spawn(
+                        tracked
+                            .scope(async move {
+                                info!("start processing proxy request");
+                                let now = Instant::now();
+                                let send = async {
+                                    match entry {
+                                        Some(entry) => entry.watch(outbound.send(req)).await,
+                                        None => outbound.send(req).await,
+                                    }
+                                };
+                                let host = remote.address.to_string();
+                                let result =
+                                    credit_while_sending(send, &monitor, &traffic, &rule, &host)
+                                        .await;
+                                match result {
+                                    Ok(ProxyResponse {
+                                        upload_bytes,
+                                        download_bytes,
+                                    }) => {
+                                        info!(
+                                            spent_time = format!("{}ms", now.elapsed().as_millis()),
+                                            upload_bytes, download_bytes, "proxy request finished"
+                                        );
+                                    }
+                                    Err(e) => {
+                                        warn!("proxy request error: {:?}", e);
+                                    }
                                 }
-                                Err(e) => {
-                                    warn!("proxy request error: {:?}", e);
-                                }
-                            }
-                        }
-                        .instrument(span),
+                            })
+                            .instrument(span),
                     );
+                    self.connections.set_abort_handle(id, handle.abort_handle());
+                }
+                Err(e) => {
+                    warn!("routing failed: {}", e);
+                    if let Some(entry) = entry {
+                        entry.close(CloseReason::Error, Some(e.to_string()));
+                    }
                 }
-                Err(e) => warn!("routing failed: {}", e),
             }
         }
         Ok(())
     }
 }
+
+/// Refuses a request, http clients get 403 and other connections are closed.
+fn reject(req: ProxyRequest) {
+    if let ProxyConn::ForwardHttp(forwarder) = req.conn {
+        forwarder.reject();
+    }
+}
+
+/// Throttles the client stream of a tcp request and the remote connection of
+/// an http request. Udp requests aren't limited.
+fn limit_rate(req: ProxyRequest, limiters: Vec<Arc<RateLimiter>>) -> ProxyRequest {
+    if limiters.is_empty() {
+        return req;
+    }
+    let ProxyRequest {
+        remote,
+        source,
+        route,
+        conn,
+    } = req;
+    let conn = match conn {
+        ProxyConn::ForwardTcp(TcpForwarder { stream }) => ProxyConn::ForwardTcp(TcpForwarder {
+            stream: Box::new(RateLimitedIO::new(stream, limiters)),
+        }),
+        ProxyConn::ForwardHttp(mut forwarder) => {
+            forwarder.limiters.extend(limiters);
+            ProxyConn::ForwardHttp(forwarder)
+        }
+        conn => conn,
+    };
+    ProxyRequest {
+        remote,
+        source,
+        route,
+        conn,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use crate::{proxy::NetLocation, stats::Copyed};
+
+    use super::*;
+
+    fn config(egress: &str, default: &str) -> AppConfig {
+        toml::from_str(&format!(
+            r#"
+            [[ingress]]
+            id = "http"
+            type = "http"
+            transport = "tcp"
+            listen = "127.0.0.1:0"
+
+            [[egress]]
+            id = "direct"
+            type = "direct"
+            {egress}
+
+            [routing]
+            resource = []
+            default_rule = "default"
+            rule = [{{ id = "default", target = [], dest = "{default}" }}]
+            "#
+        ))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn reload() {
+        let app = App::new(config("", "direct")).await.unwrap();
+        let ingress = app.ingress.lock().unwrap()[0].ingress.clone();
+        let direct = app.outbounds().egress[0].clone();
+
+        let other = r#"
+            [[egress]]
+            id = "other"
+            type = "direct"
+        "#;
+        app.reload(config(other, "other")).await.unwrap();
+
+        let outbounds = app.outbounds();
+        assert!(Arc::ptr_eq(&outbounds.egress[0], &direct));
+        assert_eq!(outbounds.egress[1].id, "other");
+        assert!(Arc::ptr_eq(
+            &app.ingress.lock().unwrap()[0].ingress,
+            &ingress
+        ));
+
+        let remote: NetLocation = "example.com:443".parse().unwrap();
+        assert_eq!(
+            app.router().route(&"http".to_string(), &remote).unwrap(),
+            "other"
+        );
+
+        // a broken config leaves the running one untouched
+        let broken = r#"
+            [[egress]]
+            id = "group"
+            type = "group"
+            members = ["missing"]
+            policy = "failover"
+        "#;
+        assert!(app.reload(config(broken, "group")).await.is_err());
+        assert_eq!(app.outbounds().egress.len(), 2);
+        assert_eq!(
+            app.router().route(&"http".to_string(), &remote).unwrap(),
+            "other"
+        );
+
+        // so does a rule pointing at a missing egress
+        assert!(app.reload(config("", "missing")).await.is_err());
+        assert_eq!(app.outbounds().egress.len(), 2);
+        assert_eq!(
+            app.router().route(&"http".to_string(), &remote).unwrap(),
+            "other"
+        );
+    }
+
+    #[tokio::test]
+    async fn reload_limiters() {
+        let limited = "limit = { upload = 1000 }";
+        let app = App::new(config(limited, "direct")).await.unwrap();
+        let limiter = app.outbounds().limiters["direct"].clone();
+
+        app.reload(config(limited, "direct")).await.unwrap();
+        assert!(Arc::ptr_eq(&app.outbounds().limiters["direct"], &limiter));
+
+        app.reload(config("limit = { upload = 2000 }", "direct"))
+            .await
+            .unwrap();
+        assert!(!Arc::ptr_eq(&app.outbounds().limiters["direct"], &limiter));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn credit_live_connection() {
+        let traffic = Mutex::new(Traffic::new());
+        let monitor = TransferMonitor::new();
+        let (tx, rx) = (Arc::new(AtomicU64::new(0)), Arc::new(AtomicU64::new(0)));
+        // the monitor only keeps a weak reference
+        let copyed = Arc::new(Copyed::new((tx.clone(), rx.clone())));
+        monitor.bind(copyed.clone()).await;
+
+        let send = async {
+            tx.store(100, Ordering::Relaxed);
+            rx.store(200, Ordering::Relaxed);
+            tokio::time::sleep(CREDIT_INTERVAL * 2).await;
+            // credited before the connection is done
+            let hosts = traffic.lock().unwrap().top_hosts(1);
+            assert_eq!((hosts[0].1.tx, hosts[0].1.rx), (100, 200));
+
+            Ok(ProxyResponse {
+                upload_bytes: 150,
+                download_bytes: 200,
+            })
+        };
+        credit_while_sending(send, &monitor, &traffic, "proxy", "example.com")
+            .await
+            .unwrap();
+
+        let mut traffic = traffic.lock().unwrap();
+        let hosts = traffic.top_hosts(1);
+        assert_eq!(hosts[0].0, "example.com");
+        assert_eq!((hosts[0].1.tx, hosts[0].1.rx), (150, 200));
+        let rules = traffic.top_rules(1);
+        assert_eq!(rules[0].0, "proxy");
+        assert_eq!((rules[0].1.tx, rules[0].1.rx), (150, 200));
+    }
+
+    #[tokio::test]
+    async fn gate() {
+        let app = App::new(config("", "reject")).await.unwrap();
+        let gate = Gate {
+            ingress: "http".to_string(),
+            acl: Arc::new(SourceAcl::new(vec![], vec!["10.0.0.0/8".parse().unwrap()])),
+            outbounds: app.outbounds.clone(),
+        };
+        let remote: NetLocation = "example.com:443".parse().unwrap();
+        let source: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        assert!(gate.admit(source, &remote).is_none());
+
+        app.reload(config("", "direct")).await.unwrap();
+        assert!(gate.admit(source, &remote).is_some());
+        assert!(gate
+            .admit("10.0.0.1:4000".parse().unwrap(), &remote)
+            .is_none());
+        assert!(!gate.permits("10.0.0.1:4000".parse().unwrap()));
+    }
+}
@@ -0,0 +1,236 @@
+use std::collections::HashSet;
+
+use anyhow::anyhow;
+use itertools::Itertools;
+
+use crate::{
+    access_log::AccessLog,
+    config::{
+        egress::ClientConfig,
+        ingress::ServerConfig,
+        routing::ANY_SRC,
+        tls::TlsConfig,
+        transport::{AcceptorConfig, ConnectorConfigInner},
+    },
+    io::RateLimiter,
+    proxy::REJECT,
+    router::Router,
+    AppConfig,
+};
+
+/// Finds the problems `App::new` or the first requests would run into,
+/// without binding or connecting anything. All of them are reported at once.
+pub fn check(config: &AppConfig) -> Vec<anyhow::Error> {
+    let mut errors = Vec::new();
+
+    let ingress: HashSet<_> = config.ingress.iter().map(|v| v.id.as_str()).collect();
+    let egress: HashSet<_> = config.egress.iter().map(|v| v.id.as_str()).collect();
+    let groups: HashSet<_> = config
+        .egress
+        .iter()
+        .filter(|v| matches!(v.client, ClientConfig::Group(_)))
+        .map(|v| v.id.as_str())
+        .collect();
+
+    for id in config.ingress.iter().map(|v| &v.id).duplicates() {
+        errors.push(anyhow!("ingress {} is defined more than once", id));
+    }
+    for id in config.egress.iter().map(|v| &v.id).duplicates() {
+        errors.push(anyhow!("egress {} is defined more than once", id));
+    }
+
+    for config in &config.egress {
+        if let ClientConfig::Group(group) = &config.client {
+            for member in &group.members {
+                if groups.contains(member.as_str()) {
+                    errors.push(anyhow!(
+                        "group {} has group {} as member, groups can't be nested",
+                        config.id,
+                        member
+                    ));
+                } else if !egress.contains(member.as_str()) {
+                    errors.push(anyhow!("group {} member {} isn't exist", config.id, member));
+                }
+            }
+        }
+    }
+
+    // the control api creates its resource file on first use
+    let control_resource = config.control.as_ref().and_then(|v| v.resource.as_ref());
+    if let Some(file) = control_resource {
+        if !config.routing.resource.contains(file) {
+            errors.push(anyhow!(
+                "control resource {} isn't a routing resource",
+                file.display()
+            ));
+        }
+    }
+    for file in &config.routing.resource {
+        if !file.exists() && Some(file) != control_resource {
+            errors.push(anyhow!("geosite {} isn't exist", file.display()));
+        }
+    }
+    errors.extend(Router::check(&config.routing));
+
+    let rules = config.routing.rule.iter();
+    let limits = config
+        .ingress
+        .iter()
+        .map(|v| ("ingress", &v.id, &v.limit))
+        .chain(config.egress.iter().map(|v| ("egress", &v.id, &v.limit)))
+        .chain(rules.map(|v| ("rule", &v.id, &v.limit)));
+    for (kind, id, limit) in limits {
+        if let Some(Err(e)) = limit.as_ref().map(RateLimiter::check) {
+            errors.push(e.context(format!("{} {} limit", kind, id)));
+        }
+    }
+
+    let dns = config.dns.as_ref().map(|v| v.id.as_str());
+    for rule in &config.routing.rule {
+        if rule.dest != REJECT && !egress.contains(rule.dest.as_str()) {
+            errors.push(anyhow!("rule {} dest {} isn't exist", rule.id, rule.dest));
+        }
+        for src in &rule.src {
+            if src != ANY_SRC && !ingress.contains(src.as_str()) && Some(src.as_str()) != dns {
+                errors.push(anyhow!("rule {} src {} isn't exist", rule.id, src));
+            }
+        }
+    }
+
+    if let Some(access_log) = &config.access_log {
+        if let Err(e) = AccessLog::check(access_log) {
+            errors.push(e);
+        }
+    }
+
+    if let Some(dns) = &config.dns {
+        let upstreams: HashSet<_> = dns.upstream.iter().map(|v| v.id.as_str()).collect();
+        for upstream in &dns.upstream {
+            if let Some(id) = &upstream.egress {
+                if !egress.contains(id.as_str()) {
+                    errors.push(anyhow!(
+                        "dns upstream {} egress {} isn't exist",
+                        upstream.id,
+                        id
+                    ));
+                }
+            }
+        }
+        let upstream_refs = dns
+            .rule
+            .iter()
+            .map(|v| &v.upstream)
+            .chain([&dns.default_upstream]);
+        for upstream in upstream_refs {
+            if !upstreams.contains(upstream.as_str()) {
+                errors.push(anyhow!("dns upstream {} isn't exist", upstream));
+            }
+        }
+        for rule in &dns.rule {
+            if !config.routing.rule.iter().any(|v| v.id == rule.rule) {
+                errors.push(anyhow!("dns rule {} isn't exist", rule.rule));
+            }
+        }
+    }
+
+    for config in &config.ingress {
+        let acceptor = match &config.server {
+            ServerConfig::Http(v) => Some(&v.acceptor),
+            ServerConfig::Socks(v) => Some(&v.acceptor),
+            ServerConfig::Mixed(v) => Some(&v.acceptor),
+            _ => None,
+        };
+        for tls in acceptor.map(AcceptorConfig::tls).unwrap_or_default() {
+            check_tls(tls, &mut errors, || format!("ingress {} tls", config.id));
+        }
+    }
+    for config in &config.egress {
+        let connector = match &config.client {
+            ClientConfig::Http(v) => Some(&v.connector.inner),
+            ClientConfig::Socks(v) => Some(&v.connector.inner),
+            _ => None,
+        };
+        for tls in connector.map(ConnectorConfigInner::tls).unwrap_or_default() {
+            check_tls(tls, &mut errors, || format!("egress {} tls", config.id));
+        }
+    }
+
+    errors
+}
+
+/// Both sides authenticate each other, every file is needed.
+fn check_tls(tls: &TlsConfig, errors: &mut Vec<anyhow::Error>, context: impl Fn() -> String) {
+    if let Err(e) = tls.root_cert_store() {
+        errors.push(e.context(context()));
+    }
+    if let Err(e) = tls.cert_chain() {
+        errors.push(e.context(context()));
+    }
+    if let Err(e) = tls.key() {
+        errors.push(e.context(context()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_all() {
+        let config: AppConfig = toml::from_str(
+            r#"
+            [[ingress]]
+            id = "socks"
+            type = "socks"
+            transport = "tls"
+            next_layer = { transport = "tcp", listen = "127.0.0.1:0" }
+            tls = { ca_cert = "/nonexistent/ca.pem", cert = "/nonexistent/cert.pem" }
+
+            [[egress]]
+            id = "direct"
+            type = "direct"
+            limit = { upload = 0 }
+
+            [[egress]]
+            id = "group"
+            type = "group"
+            members = ["direct", "missing"]
+            policy = "failover"
+
+            [routing]
+            resource = ["/nonexistent/geosite.dat"]
+            default_rule = "fallback"
+            rule = [
+                { id = "block", target = ["d:example.com", "cidr:bad"], dest = "reject" },
+                { id = "lan", target = ["geoip:private"], src = ["http"], dest = "direct" },
+                { id = "default", target = [], dest = "proxy" },
+            ]
+
+            [control]
+            listen = "127.0.0.1:0"
+            resource = "/nonexistent/control.dat"
+            "#,
+        )
+        .unwrap();
+
+        let errors: Vec<_> = check(&config).iter().map(|e| format!("{:#}", e)).collect();
+        let has = |s: &str| errors.iter().any(|e| e.contains(s));
+        assert!(
+            has("group group member missing isn't exist"),
+            "{:?}",
+            errors
+        );
+        assert!(has("geosite /nonexistent/geosite.dat isn't exist"));
+        assert!(has("rule block target cidr:bad"));
+        assert!(has("rule lan target geoip:private"));
+        assert!(has("default rule fallback is not exist"));
+        assert!(has("rule default dest proxy isn't exist"));
+        assert!(has("rule lan src http isn't exist"));
+        assert!(has("ingress socks tls"));
+        assert!(has("egress direct limit: upload rate can't be 0"));
+        assert!(has(
+            "control resource /nonexistent/control.dat isn't a routing resource"
+        ));
+        assert!(!has("rule block dest"));
+    }
+}
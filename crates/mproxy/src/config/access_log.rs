@@ -0,0 +1,35 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AccessLogConfig {
+    /// directory the log files are written to
+    pub path: PathBuf,
+    /// file name prefix, `access.log` if absent
+    pub name: Option<String>,
+    #[serde(default)]
+    pub format: AccessLogFormat,
+    #[serde(default)]
+    pub rotation: AccessLogRotation,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessLogFormat {
+    /// one json object per line
+    #[default]
+    Json,
+    /// space separated `key=value` pairs
+    Text,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessLogRotation {
+    Minutely,
+    Hourly,
+    #[default]
+    Daily,
+    Never,
+}
@@ -0,0 +1,20 @@
+use std::{net::SocketAddr, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ControlConfig {
+    pub listen: ControlListen,
+    /// geosite file keeping the targets added through the api, grouped by
+    /// rule id. A rule listing `geosite:<rule id>` keeps them across restarts.
+    /// It has to be listed in the routing resources too.
+    pub resource: Option<PathBuf>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ControlListen {
+    Tcp(SocketAddr),
+    /// path of a unix socket
+    Unix(PathBuf),
+}
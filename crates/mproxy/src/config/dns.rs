@@ -0,0 +1,38 @@
+use std::net::SocketAddr;
+
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DnsConfig {
+    /// source id matched against the `src` of routing rules
+    pub id: String,
+    pub listen: SocketAddr,
+    pub upstream: Vec<UpstreamConfig>,
+    #[serde(default)]
+    pub rule: Vec<DnsRuleConfig>,
+    pub default_upstream: String,
+    pub cache_size: Option<usize>,
+    pub fake_ip: Option<FakeIpConfig>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FakeIpConfig {
+    pub range: IpNet,
+    pub ttl: Option<u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpstreamConfig {
+    pub id: String,
+    pub address: SocketAddr,
+    /// tunnel queries through this egress over tcp, plain udp if absent
+    pub egress: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DnsRuleConfig {
+    /// id of a routing rule
+    pub rule: String,
+    pub upstream: String,
+}
@@ -1,8 +1,12 @@
 use serde::{Deserialize, Serialize};
 
+use super::limit::RateLimitConfig;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct EgressConfig {
     pub id: String,
+    /// shared by all connections sent through this egress or group
+    pub limit: Option<RateLimitConfig>,
     #[serde(flatten)]
     pub client: ClientConfig,
 }
@@ -13,6 +17,8 @@ pub struct EgressConfig {
 pub enum ClientConfig {
     Http(http::ClientConfig),
     Direct(direct::ClientConfig),
+    Socks(socks::ClientConfig),
+    Group(group::GroupConfig),
 }
 
 pub mod http {
@@ -28,8 +34,77 @@ pub mod http {
 }
 
 pub mod direct {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Serialize};
+    use serde_with::serde_as;
+
+    #[derive(Debug, Serialize, Deserialize)]
+    #[serde_as]
+    #[serde(default)]
+    pub struct ClientConfig {
+        #[serde_as(as = "DurationSeconds")]
+        pub udp_idle_timeout: Duration,
+
+        /// mark of the outgoing sockets, set it to the `fwmark` of a tun
+        /// ingress capturing all traffic
+        pub fwmark: Option<u32>,
+    }
+
+    impl Default for ClientConfig {
+        fn default() -> Self {
+            Self {
+                udp_idle_timeout: Duration::from_secs(60),
+                fwmark: None,
+            }
+        }
+    }
+}
+
+pub mod socks {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Serialize};
+    use serde_with::serde_as;
+
+    use crate::config::{ingress::socks::AuthType, transport::ConnectorConfig};
+
+    #[derive(Debug, Serialize, Deserialize)]
+    #[serde_as]
+    pub struct ClientConfig {
+        #[serde(flatten)]
+        pub connector: ConnectorConfig,
+
+        pub auth: Option<AuthType>,
+
+        #[serde_as(as = "DurationSeconds")]
+        #[serde(default = "default_udp_idle_timeout")]
+        pub udp_idle_timeout: Duration,
+    }
+
+    fn default_udp_idle_timeout() -> Duration {
+        Duration::from_secs(60)
+    }
+}
+
+pub mod group {
     use serde::{Deserialize, Serialize};
 
+    #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+    #[serde(rename_all = "kebab-case")]
+    pub enum Policy {
+        /// first reachable member in declared order
+        Failover,
+        RoundRobin,
+        LeastConnections,
+        /// member with the lowest latency measured by health checks
+        LowestLatency,
+    }
+
     #[derive(Debug, Serialize, Deserialize)]
-    pub struct ClientConfig {}
+    pub struct GroupConfig {
+        /// egress ids, groups can't be nested
+        pub members: Vec<String>,
+        pub policy: Policy,
+    }
 }
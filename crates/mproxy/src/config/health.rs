@@ -0,0 +1,31 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde_as]
+#[serde(default)]
+pub struct HealthCheckConfig {
+    /// `host:port` the probe connects to through every egress
+    pub target: String,
+    /// bytes written after connecting, a `HEAD` request to `target` if absent
+    pub payload: Option<String>,
+
+    #[serde_as(as = "DurationSeconds")]
+    pub interval: Duration,
+
+    #[serde_as(as = "DurationSeconds")]
+    pub timeout: Duration,
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> Self {
+        Self {
+            target: "www.gstatic.com:80".to_string(),
+            payload: None,
+            interval: Duration::from_secs(60),
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
@@ -1,19 +1,41 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize)]
+use ipnet::IpNet;
+
+use super::limit::RateLimitConfig;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct IngressConfig {
     pub id: String,
 
+    /// recover the domain of tcp requests to raw ips from TLS SNI or HTTP Host
+    #[serde(default)]
+    pub sniff: bool,
+
+    /// shared by all connections accepted by this ingress
+    pub limit: Option<RateLimitConfig>,
+
+    /// source networks allowed to connect, any if empty
+    #[serde(default)]
+    pub allow: Vec<IpNet>,
+    /// source networks refused even if allowed
+    #[serde(default)]
+    pub deny: Vec<IpNet>,
+
     #[serde(flatten)]
     pub server: ServerConfig,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 #[serde(rename_all = "lowercase")]
 pub enum ServerConfig {
     Http(http::ServerConfig),
     Socks(socks::ServerConfig),
+    Mixed(mixed::ServerConfig),
+    Redirect(redirect::ServerConfig),
+    Tproxy(tproxy::ServerConfig),
+    Tun(tun::ServerConfig),
 }
 
 pub mod http {
@@ -21,10 +43,22 @@ pub mod http {
 
     use crate::config::transport::AcceptorConfig;
 
-    #[derive(Debug, Serialize, Deserialize)]
+    #[derive(Debug, Clone, Serialize, Deserialize)]
     pub struct ServerConfig {
         #[serde(flatten)]
         pub acceptor: AcceptorConfig,
+
+        /// serve the routing rules as a proxy auto-config script
+        pub pac: Option<PacConfig>,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct PacConfig {
+        /// request path of the script, like `/proxy.pac`
+        pub path: String,
+        /// `host:port` browsers send proxied requests to, the `Host` the
+        /// script was fetched from if absent
+        pub proxy: Option<String>,
     }
 }
 
@@ -32,20 +66,37 @@ pub mod socks {
     use serde::{Deserialize, Serialize};
 
     use crate::config::transport::AcceptorConfig;
-    #[derive(Debug, Serialize, Deserialize)]
+    #[derive(Debug, Clone, Serialize, Deserialize)]
     #[serde(tag = "type")]
     #[serde(rename_all = "lowercase")]
     pub enum AuthType {
         Simple { user: String, password: String },
     }
 
-    #[derive(Debug, Serialize, Deserialize)]
+    #[derive(Debug, Clone, Serialize, Deserialize)]
     pub struct Socks5Config {
         pub allow_udp: Option<bool>,
         pub auth: Option<AuthType>,
     }
 
-    #[derive(Debug, Serialize, Deserialize)]
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct ServerConfig {
+        #[serde(flatten)]
+        pub acceptor: AcceptorConfig,
+
+        #[serde(flatten)]
+        pub socks5: Socks5Config,
+    }
+}
+
+pub mod mixed {
+    use serde::{Deserialize, Serialize};
+
+    use crate::config::transport::AcceptorConfig;
+
+    use super::socks::Socks5Config;
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
     pub struct ServerConfig {
         #[serde(flatten)]
         pub acceptor: AcceptorConfig,
@@ -54,3 +105,49 @@ pub mod socks {
         pub socks5: Socks5Config,
     }
 }
+
+pub mod redirect {
+    use std::net::SocketAddr;
+
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct ServerConfig {
+        pub listen: SocketAddr,
+    }
+}
+
+pub mod tproxy {
+    use std::net::SocketAddr;
+
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct ServerConfig {
+        pub listen: SocketAddr,
+        pub allow_udp: Option<bool>,
+    }
+}
+
+pub mod tun {
+    use ipnet::IpNet;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct ServerConfig {
+        /// device name, `%d` is replaced by the kernel
+        pub name: String,
+        pub address: Vec<IpNet>,
+        /// routes pointed at the device, e.g. `0.0.0.0/1` and `128.0.0.0/1`
+        /// to capture all traffic
+        #[serde(default)]
+        pub route: Vec<IpNet>,
+        /// routes are put in the routing table of this number instead of the
+        /// main one, only packets without this mark look it up. Egresses
+        /// set the same `fwmark` so their own traffic isn't captured, which
+        /// is required when routes capture all traffic.
+        pub fwmark: Option<u32>,
+        pub mtu: Option<usize>,
+        pub allow_udp: Option<bool>,
+    }
+}
@@ -0,0 +1,12 @@
+use serde::{Deserialize, Serialize};
+
+/// Bytes per second shared by every connection the limit applies to, a
+/// direction without a rate isn't limited. Only tcp and http requests are
+/// limited, udp datagrams pass as they come.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RateLimitConfig {
+    /// from the client to the remote
+    pub upload: Option<u64>,
+    /// from the remote back to the client
+    pub download: Option<u64>,
+}
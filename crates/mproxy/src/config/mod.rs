@@ -1,12 +1,20 @@
 cfg_if::cfg_if! {
     if #[cfg(not(target_family = "wasm"))] {
+        pub mod access_log;
+        pub mod control;
+        pub mod dns;
         pub mod egress;
+        pub mod health;
         pub mod ingress;
+        pub mod limit;
         pub mod routing;
         pub mod transport;
         pub mod tls;
 
-        use self::{egress::EgressConfig, ingress::IngressConfig, routing::RoutingConfig};
+        use self::{
+            access_log::AccessLogConfig, control::ControlConfig, dns::DnsConfig, egress::EgressConfig,
+            health::HealthCheckConfig, ingress::IngressConfig, routing::RoutingConfig,
+        };
         use serde::{Deserialize, Serialize};
 
         #[cfg(not(target_family = "wasm"))]
@@ -15,6 +23,10 @@ cfg_if::cfg_if! {
             pub ingress: Vec<IngressConfig>,
             pub egress: Vec<EgressConfig>,
             pub routing: RoutingConfig,
+            pub dns: Option<DnsConfig>,
+            pub health_check: Option<HealthCheckConfig>,
+            pub control: Option<ControlConfig>,
+            pub access_log: Option<AccessLogConfig>,
         }
     }
 }
@@ -2,17 +2,37 @@ use std::path::PathBuf;
 
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize)]
+use super::limit::RateLimitConfig;
+
+/// A rule `src` matching every ingress.
+pub const ANY_SRC: &str = "*";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RoutingConfig {
     pub resource: Vec<PathBuf>,
+    /// v2ray-format geoip.dat files
+    #[serde(default)]
+    pub geoip: Vec<PathBuf>,
     pub rule: Vec<RuleConfig>,
     pub default_rule: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RuleConfig {
     pub id: String,
     pub target: Vec<String>,
+    /// ingress ids, `*` matches any ingress and is taken when `src` is
+    /// left out; an empty list matches no ingress
+    #[serde(default = "any_src")]
     pub src: Vec<String>,
+    /// destination ports like `443` or `8000-9000`, any port if empty
+    #[serde(default)]
+    pub port: Vec<String>,
     pub dest: String,
+    /// shared by all connections matching this rule
+    pub limit: Option<RateLimitConfig>,
+}
+
+fn any_src() -> Vec<String> {
+    vec![ANY_SRC.to_string()]
 }
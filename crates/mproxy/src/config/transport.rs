@@ -1,8 +1,9 @@
-use std::time::Duration;
+use std::{net::SocketAddr, time::Duration};
 
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
 
+use super::tls::TlsConfig;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "transport")]
@@ -12,6 +13,36 @@ pub enum AcceptorConfig {
     Tcp(tcp::AcceptorConfig),
     Kcp(kcp::AcceptorConfig),
     Tls(Box<tls::AcceptorConfig>),
+    Mux(Box<mux::AcceptorConfig>),
+    Ws(Box<ws::AcceptorConfig>),
+}
+
+impl AcceptorConfig {
+    pub fn listen(&self) -> SocketAddr {
+        match self {
+            AcceptorConfig::Quic(config) => config.listen,
+            AcceptorConfig::Tcp(config) => config.listen,
+            AcceptorConfig::Kcp(config) => config.listen,
+            AcceptorConfig::Tls(config) => config.next_layer.listen(),
+            AcceptorConfig::Mux(config) => config.next_layer.listen(),
+            AcceptorConfig::Ws(config) => config.next_layer.listen(),
+        }
+    }
+
+    /// Tls settings of every layer, outermost first.
+    pub fn tls(&self) -> Vec<&TlsConfig> {
+        match self {
+            AcceptorConfig::Quic(config) => vec![&config.tls],
+            AcceptorConfig::Tcp(_) | AcceptorConfig::Kcp(_) => vec![],
+            AcceptorConfig::Tls(config) => {
+                let mut tls = vec![&config.tls];
+                tls.extend(config.next_layer.tls());
+                tls
+            }
+            AcceptorConfig::Mux(config) => config.next_layer.tls(),
+            AcceptorConfig::Ws(config) => config.next_layer.tls(),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -22,6 +53,36 @@ pub enum ConnectorConfigInner {
     Tcp(tcp::ConnectorConfig),
     Kcp(kcp::ConnectorConfig),
     Tls(Box<tls::ConnectorConfig>),
+    Mux(Box<mux::ConnectorConfig>),
+    Ws(Box<ws::ConnectorConfig>),
+}
+
+impl ConnectorConfigInner {
+    pub fn endpoint(&self) -> &Endpoint {
+        match self {
+            ConnectorConfigInner::Quic(config) => &config.endpoint,
+            ConnectorConfigInner::Tcp(config) => &config.endpoint,
+            ConnectorConfigInner::Kcp(config) => &config.endpoint,
+            ConnectorConfigInner::Tls(config) => config.next_layer.inner.endpoint(),
+            ConnectorConfigInner::Mux(config) => config.next_layer.inner.endpoint(),
+            ConnectorConfigInner::Ws(config) => config.next_layer.inner.endpoint(),
+        }
+    }
+
+    /// Tls settings of every layer, outermost first.
+    pub fn tls(&self) -> Vec<&TlsConfig> {
+        match self {
+            ConnectorConfigInner::Quic(config) => vec![&config.tls],
+            ConnectorConfigInner::Tcp(_) | ConnectorConfigInner::Kcp(_) => vec![],
+            ConnectorConfigInner::Tls(config) => {
+                let mut tls = vec![&config.tls];
+                tls.extend(config.next_layer.inner.tls());
+                tls
+            }
+            ConnectorConfigInner::Mux(config) => config.next_layer.inner.tls(),
+            ConnectorConfigInner::Ws(config) => config.next_layer.inner.tls(),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -33,6 +94,14 @@ pub struct TransportConfig {
 
     #[serde_as(as = "DurationSeconds")]
     pub write_timeout: Duration,
+
+    /// streams carried by a shared connection at once, another connection
+    /// is opened beyond it; mux and quic only
+    pub max_streams: usize,
+
+    /// shared connections without streams for this long are closed
+    #[serde_as(as = "DurationSeconds")]
+    pub idle_timeout: Duration,
 }
 
 impl Default for TransportConfig {
@@ -40,6 +109,8 @@ impl Default for TransportConfig {
         Self {
             read_timeout: Duration::from_secs(10),
             write_timeout: Duration::from_secs(10),
+            max_streams: 64,
+            idle_timeout: Duration::from_secs(60),
         }
     }
 }
@@ -62,6 +133,15 @@ pub enum Endpoint {
     Multi { address: String, port_range: String },
 }
 
+impl Endpoint {
+    pub fn address(&self) -> &str {
+        match self {
+            Endpoint::Single { address, .. } => address,
+            Endpoint::Multi { address, .. } => address,
+        }
+    }
+}
+
 pub mod quic {
     use std::net::SocketAddr;
 
@@ -106,6 +186,8 @@ pub mod quic {
     pub struct ConnectorConfig {
         pub endpoint: Endpoint,
         pub local: SocketAddr,
+        /// mark of the local socket, see the `fwmark` of tun ingress
+        pub fwmark: Option<u32>,
         pub server_name: String,
         pub tls: TlsConfig,
 
@@ -131,6 +213,8 @@ pub mod tcp {
     #[derive(Serialize, Deserialize, Debug, Clone)]
     pub struct ConnectorConfig {
         pub endpoint: Endpoint,
+        /// mark of the outgoing sockets, see the `fwmark` of tun ingress
+        pub fwmark: Option<u32>,
     }
 }
 
@@ -220,3 +304,58 @@ pub mod tls {
     }
     
 }
+
+/// Many streams over one connection of the next layer, the limits are taken
+/// from the `TransportConfig` of the mux layer.
+pub mod mux {
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct AcceptorConfig {
+        pub next_layer: super::AcceptorConfig,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct ConnectorConfig {
+        pub next_layer: super::ConnectorConfig,
+    }
+}
+
+/// A byte stream in WebSocket binary messages, to pass reverse proxies only
+/// forwarding HTTP.
+pub mod ws {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Serialize};
+    use serde_with::serde_as;
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    #[serde_as]
+    pub struct AcceptorConfig {
+        pub next_layer: super::AcceptorConfig,
+        /// upgrade requests for other paths are refused
+        pub path: String,
+
+        /// connections not upgraded in time are dropped
+        #[serde_as(as = "DurationSeconds")]
+        #[serde(default = "default_handshake_timeout")]
+        pub handshake_timeout: Duration,
+    }
+
+    fn default_handshake_timeout() -> Duration {
+        Duration::from_secs(10)
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    #[serde_as]
+    pub struct ConnectorConfig {
+        pub next_layer: super::ConnectorConfig,
+        pub path: String,
+        /// `Host` header of the upgrade request, the endpoint address if
+        /// absent
+        pub host: Option<String>,
+
+        #[serde_as(as = "DurationSeconds")]
+        pub handshake_timeout: Duration,
+    }
+}
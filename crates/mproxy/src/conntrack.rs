@@ -0,0 +1,228 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::SystemTime,
+};
+
+use tokio::task::AbortHandle;
+
+use crate::{
+    proxy::NetLocation,
+    stats::{GetTransferStats, TransferMonitor},
+};
+
+tokio::task_local! {
+    /// Monitor of the connection forwarded by the current task.
+    static MONITOR: Arc<TransferMonitor>;
+}
+
+/// Makes the counters of a forwarder visible to the connection table. Does
+/// nothing outside of a tracked connection.
+pub(crate) async fn bind(copyed: Arc<dyn GetTransferStats>) {
+    if let Ok(monitor) = MONITOR.try_with(|v| v.clone()) {
+        monitor.bind(copyed).await;
+    }
+}
+
+/// A dispatched request as seen by the connection table.
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    pub id: u64,
+    /// address of the client, if the ingress knows it
+    pub source: Option<SocketAddr>,
+    pub ingress: String,
+    pub remote: NetLocation,
+    /// id of the matched routing rule
+    pub rule: String,
+    /// egress or group the rule points at
+    pub egress: String,
+    pub started_at: SystemTime,
+    pub upload_bytes: u64,
+    pub download_bytes: u64,
+}
+
+#[derive(Debug)]
+struct Connection {
+    info: ConnectionInfo,
+    monitor: Arc<TransferMonitor>,
+    abort: Option<AbortHandle>,
+}
+
+/// Table of the connections being forwarded.
+#[derive(Debug, Default)]
+pub struct ConnectionTracker {
+    next_id: AtomicU64,
+    connections: Mutex<HashMap<u64, Connection>>,
+}
+
+impl ConnectionTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a connection, it stays in the table until the returned guard is
+    /// dropped.
+    pub fn track(
+        self: &Arc<Self>,
+        source: Option<SocketAddr>,
+        ingress: String,
+        remote: NetLocation,
+        rule: String,
+        egress: String,
+    ) -> Tracked {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let monitor = Arc::new(TransferMonitor::new());
+        self.connections.lock().unwrap().insert(
+            id,
+            Connection {
+                info: ConnectionInfo {
+                    id,
+                    source,
+                    ingress,
+                    remote,
+                    rule,
+                    egress,
+                    started_at: SystemTime::now(),
+                    upload_bytes: 0,
+                    download_bytes: 0,
+                },
+                monitor: monitor.clone(),
+                abort: None,
+            },
+        );
+        Tracked {
+            id,
+            monitor,
+            tracker: self.clone(),
+        }
+    }
+
+    /// Remembers the task forwarding the connection so it can be closed.
+    pub fn set_abort_handle(&self, id: u64, abort: AbortHandle) {
+        if let Some(conn) = self.connections.lock().unwrap().get_mut(&id) {
+            conn.abort = Some(abort);
+        }
+    }
+
+    /// Connections in the order they were dispatched, with live byte counts.
+    pub async fn list(&self) -> Vec<ConnectionInfo> {
+        let connections: Vec<_> = self
+            .connections
+            .lock()
+            .unwrap()
+            .values()
+            .map(|conn| (conn.info.clone(), conn.monitor.clone()))
+            .collect();
+
+        let mut list = Vec::with_capacity(connections.len());
+        for (mut info, monitor) in connections {
+            if let Ok(stats) = monitor.get_transfer_stats().await {
+                info.upload_bytes = stats.tx as u64;
+                info.download_bytes = stats.rx as u64;
+            }
+            list.push(info);
+        }
+        list.sort_by_key(|info| info.id);
+        list
+    }
+
+    /// Aborts the forwarding of a connection, returns false if it's unknown
+    /// or its task isn't known yet.
+    pub fn close(&self, id: u64) -> bool {
+        let mut connections = self.connections.lock().unwrap();
+        match connections.get_mut(&id).and_then(|conn| conn.abort.take()) {
+            Some(abort) => {
+                connections.remove(&id);
+                abort.abort();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Entry of a connection in the table, removed on drop.
+#[derive(Debug)]
+pub struct Tracked {
+    id: u64,
+    monitor: Arc<TransferMonitor>,
+    tracker: Arc<ConnectionTracker>,
+}
+
+impl Tracked {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn monitor(&self) -> Arc<TransferMonitor> {
+        self.monitor.clone()
+    }
+
+    /// Runs the forwarding of the connection, counters bound by forwarders
+    /// inside of it are reported for this connection.
+    pub async fn scope<F: Future>(self, f: F) -> F::Output {
+        MONITOR.scope(self.monitor.clone(), f).await
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        self.tracker.connections.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{str::FromStr, time::Duration};
+
+    use crate::stats::Copyed;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn track_and_close() {
+        let tracker = Arc::new(ConnectionTracker::new());
+        let tracked = tracker.track(
+            Some("127.0.0.1:4000".parse().unwrap()),
+            "socks".to_string(),
+            NetLocation::from_str("example.com:443").unwrap(),
+            "default".to_string(),
+            "direct".to_string(),
+        );
+        let id = tracked.id();
+
+        // nothing to abort yet
+        assert!(!tracker.close(id));
+
+        let (bound_tx, bound_rx) = tokio::sync::oneshot::channel();
+        let handle = tokio::spawn(tracked.scope(async move {
+            let copyed = Arc::new(Copyed::new((
+                Arc::new(AtomicU64::new(10)),
+                Arc::new(AtomicU64::new(20)),
+            )));
+            bind(copyed.clone()).await;
+            bound_tx.send(()).unwrap();
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            drop(copyed);
+        }));
+        tracker.set_abort_handle(id, handle.abort_handle());
+        bound_rx.await.unwrap();
+
+        let list = tracker.list().await;
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].id, id);
+        assert_eq!(list[0].ingress, "socks");
+        assert_eq!(list[0].upload_bytes, 10);
+        assert_eq!(list[0].download_bytes, 20);
+
+        assert!(tracker.close(id));
+        assert!(handle.await.unwrap_err().is_cancelled());
+        assert!(tracker.list().await.is_empty());
+        assert!(!tracker.close(id));
+    }
+}
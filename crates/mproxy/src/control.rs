@@ -0,0 +1,416 @@
+use std::{
+    collections::HashMap, convert::Infallible, path::PathBuf, str::FromStr, time::UNIX_EPOCH,
+};
+
+use anyhow::Context;
+use futures::{stream::FuturesUnordered, StreamExt};
+use http_body_util::{BodyExt, Full};
+use hyper::{
+    body::{Bytes, Incoming},
+    header::CONTENT_TYPE,
+    server::conn::http1,
+    service::service_fn,
+    Method, Request, Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::net::TcpListener;
+use tracing::{info, instrument, warn};
+
+use crate::{
+    config::{
+        control::{ControlConfig, ControlListen},
+        routing::RoutingConfig,
+    },
+    io::BoxedAsyncIO,
+    proxy::NetLocation,
+    router::GeositeFile,
+    App,
+};
+
+#[derive(Debug)]
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+impl Listener {
+    async fn accept(&self) -> Result<BoxedAsyncIO, anyhow::Error> {
+        Ok(match self {
+            Listener::Tcp(listener) => Box::new(listener.accept().await?.0),
+            #[cfg(unix)]
+            Listener::Unix(listener) => Box::new(listener.accept().await?.0),
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TargetRequest {
+    target: String,
+}
+
+/// A REST/JSON api to inspect and steer a running app.
+#[derive(Debug)]
+pub struct ControlServer {
+    listener: Listener,
+    resource: Option<PathBuf>,
+}
+
+impl ControlServer {
+    /// The resource file has to be a routing resource, removing a target
+    /// rebuilds the router from those files.
+    pub async fn new(
+        config: ControlConfig,
+        routing: &RoutingConfig,
+    ) -> Result<Self, anyhow::Error> {
+        if let Some(resource) = &config.resource {
+            if !routing.resource.contains(resource) {
+                anyhow::bail!(
+                    "control resource {} isn't a routing resource",
+                    resource.display()
+                );
+            }
+        }
+
+        let listener = match &config.listen {
+            ControlListen::Tcp(addr) => Listener::Tcp(TcpListener::bind(addr).await?),
+            #[cfg(unix)]
+            ControlListen::Unix(path) => {
+                // left behind by a previous run
+                if path.exists() {
+                    std::fs::remove_file(path)?;
+                }
+                Listener::Unix(tokio::net::UnixListener::bind(path)?)
+            }
+            #[cfg(not(unix))]
+            ControlListen::Unix(_) => anyhow::bail!("unix socket is not supported"),
+        };
+        info!("Control api listening on {:?}", config.listen);
+
+        Ok(Self {
+            listener,
+            resource: config.resource,
+        })
+    }
+
+    #[cfg(test)]
+    fn local_addr(&self) -> Option<std::net::SocketAddr> {
+        match &self.listener {
+            Listener::Tcp(listener) => listener.local_addr().ok(),
+            #[cfg(unix)]
+            Listener::Unix(_) => None,
+        }
+    }
+
+    /// Serves connections on the calling task, they borrow the app. Never
+    /// returns, failed accepts are logged and skipped.
+    pub async fn serve(&self, app: &App) {
+        let mut connections = FuturesUnordered::new();
+        loop {
+            tokio::select! {
+                stream = self.listener.accept() => match stream {
+                    Ok(stream) => connections.push(self.serve_connection(app, stream)),
+                    Err(e) => warn!("Failed to accept control connection: {:?}", e),
+                },
+                Some(()) = connections.next() => {}
+            }
+        }
+    }
+
+    async fn serve_connection(&self, app: &App, stream: BoxedAsyncIO) {
+        let service = service_fn(|req| async move {
+            Ok::<_, Infallible>(match self.handle(app, req).await {
+                Ok(response) => response,
+                Err(e) => reply(
+                    StatusCode::BAD_REQUEST,
+                    json!({ "error": format!("{:#}", e) }),
+                ),
+            })
+        });
+
+        if let Err(e) = http1::Builder::new()
+            .serve_connection(TokioIo::new(stream), service)
+            .await
+        {
+            warn!("Failed to serve control connection: {:?}", e);
+        }
+    }
+
+    #[instrument(name = "control_request", skip_all)]
+    async fn handle(
+        &self,
+        app: &App,
+        req: Request<Incoming>,
+    ) -> Result<Response<Full<Bytes>>, anyhow::Error> {
+        let path: Vec<String> = req
+            .uri()
+            .path()
+            .split('/')
+            .filter(|v| !v.is_empty())
+            .map(|v| v.to_string())
+            .collect();
+        let path: Vec<&str> = path.iter().map(|v| v.as_str()).collect();
+
+        Ok(match (req.method().clone(), path.as_slice()) {
+            (Method::GET, ["ingress"]) => reply(
+                StatusCode::OK,
+                app.ingress()
+                    .iter()
+                    .map(|ingress| json!({ "id": ingress.id, "sniff": ingress.sniff }))
+                    .collect(),
+            ),
+            (Method::GET, ["egress"]) => {
+                let health = app.health().await;
+                let mut egress: Vec<Value> = app
+                    .egress()
+                    .iter()
+                    .map(|egress| {
+                        json!({
+                            "id": egress.id,
+                            "latency_ms": egress.latency().map(|v| v.as_millis() as u64),
+                            "healthy": health.get(&egress.id).map(|v| v.healthy),
+                        })
+                    })
+                    .collect();
+                egress.extend(
+                    app.groups()
+                        .iter()
+                        .map(|group| json!({ "id": group.id, "group": true })),
+                );
+                reply(StatusCode::OK, Value::Array(egress))
+            }
+            (Method::GET, ["rules"]) => reply(
+                StatusCode::OK,
+                app.router()
+                    .rules()
+                    .into_iter()
+                    .map(|(id, dest)| json!({ "id": id, "dest": dest }))
+                    .collect(),
+            ),
+            (Method::GET, ["stats"]) => {
+                let stats = app.stats().await?;
+                reply(
+                    StatusCode::OK,
+                    stats
+                        .transfer
+                        .into_iter()
+                        .map(|(id, v)| (id, json!({ "tx": v.tx, "rx": v.rx })))
+                        .collect(),
+                )
+            }
+            (Method::GET, ["connections"]) => reply(
+                StatusCode::OK,
+                app.connections()
+                    .await
+                    .into_iter()
+                    .map(|conn| {
+                        json!({
+                            "id": conn.id,
+                            "source": conn.source.map(|v| v.to_string()),
+                            "ingress": conn.ingress,
+                            "remote": conn.remote.to_string(),
+                            "rule": conn.rule,
+                            "egress": conn.egress,
+                            "started_at": conn
+                                .started_at
+                                .duration_since(UNIX_EPOCH)
+                                .map(|v| v.as_secs())
+                                .unwrap_or_default(),
+                            "upload_bytes": conn.upload_bytes,
+                            "download_bytes": conn.download_bytes,
+                        })
+                    })
+                    .collect(),
+            ),
+            (Method::DELETE, ["connections", id]) => {
+                if app.close_connection(id.parse()?) {
+                    reply(StatusCode::OK, json!({ "id": id }))
+                } else {
+                    reply(
+                        StatusCode::NOT_FOUND,
+                        json!({ "error": "no such connection" }),
+                    )
+                }
+            }
+            (Method::GET, ["route"]) => {
+                let query: HashMap<_, _> =
+                    url::form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes())
+                        .collect();
+                let host = query.get("host").context("missing host")?;
+                let port = query.get("port").map_or("443", |v| v.as_ref());
+                let src = query.get("src").map(|v| v.to_string()).unwrap_or_default();
+                let remote = NetLocation::from_str(&format!("{}:{}", host, port))?;
+
+                let decision = app.router().explain(&src, &remote)?;
+                reply(
+                    StatusCode::OK,
+                    json!({
+                        "rule": decision.rule,
+                        "dest": decision.dest,
+                    }),
+                )
+            }
+            (Method::POST, ["rules", id, "targets"]) => {
+                let target = read_target(req).await?;
+                app.router().add_rule_target(id, &target)?;
+                if let Some(resource) = &self.resource {
+                    let mut gs = GeositeFile::new(resource)?;
+                    gs.insert_target(id, &target)?;
+                    gs.store()?;
+                }
+                reply(StatusCode::OK, json!({ "id": id, "target": target }))
+            }
+            (Method::DELETE, ["rules", id, "targets"]) => {
+                let target = read_target(req).await?;
+                // a matcher can't forget a target, the router is rebuilt
+                // from the resource files instead
+                let resource = self
+                    .resource
+                    .as_ref()
+                    .context("removing targets needs a control resource file")?;
+                let mut gs = GeositeFile::new(resource)?;
+                gs.remove_target(id, &target)?;
+                gs.store()?;
+                app.rebuild_router().await?;
+                reply(StatusCode::OK, json!({ "id": id, "target": target }))
+            }
+            _ => reply(StatusCode::NOT_FOUND, json!({ "error": "not found" })),
+        })
+    }
+}
+
+async fn read_target(req: Request<Incoming>) -> Result<String, anyhow::Error> {
+    let body = req.into_body().collect().await?.to_bytes();
+    Ok(serde_json::from_slice::<TargetRequest>(&body)?.target)
+}
+
+fn reply(status: StatusCode, body: Value) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Full::new(Bytes::from(body.to_string())))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use super::*;
+
+    async fn request(addr: std::net::SocketAddr, method: &str, path: &str, body: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(
+                format!(
+                    "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+                    method,
+                    path,
+                    body.len(),
+                    body
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn api() {
+        let resource =
+            std::env::temp_dir().join(format!("mproxy-control-test-{}.dat", std::process::id()));
+        let _ = std::fs::remove_file(&resource);
+
+        let config = toml::from_str(&format!(
+            r#"
+            ingress = []
+
+            [[egress]]
+            id = "direct"
+            type = "direct"
+
+            [[egress]]
+            id = "proxy"
+            type = "direct"
+
+            [routing]
+            resource = ["{}"]
+            default_rule = "default"
+            rule = [
+                {{ id = "proxy", target = ["geosite:proxy"], dest = "proxy" }},
+                {{ id = "default", target = [], dest = "direct" }},
+            ]
+            "#,
+            resource.display()
+        ))
+        .unwrap();
+        let app = App::new(config).await.unwrap();
+        let control = ControlServer::new(
+            ControlConfig {
+                listen: ControlListen::Tcp("127.0.0.1:0".parse().unwrap()),
+                resource: Some(resource.clone()),
+            },
+            &app.outbounds().routing,
+        )
+        .await
+        .unwrap();
+        let addr = control.local_addr().unwrap();
+
+        let client = async {
+            let rules = request(addr, "GET", "/rules", "").await;
+            assert!(rules.contains(r#""id":"proxy""#));
+
+            let route = "/route?host=example.com&port=443";
+            assert!(request(addr, "GET", route, "")
+                .await
+                .contains(r#""rule":"default""#));
+
+            let target = r#"{"target":"d:example.com"}"#;
+            assert!(request(addr, "POST", "/rules/proxy/targets", target)
+                .await
+                .starts_with("HTTP/1.1 200"));
+            let decision = request(addr, "GET", route, "").await;
+            assert!(decision.contains(r#""rule":"proxy""#));
+            assert!(decision.contains(r#""dest":"proxy""#));
+            // the query is percent decoded
+            assert!(request(addr, "GET", "/route?host=example%2Ecom", "")
+                .await
+                .contains(r#""rule":"proxy""#));
+
+            assert!(request(addr, "DELETE", "/rules/proxy/targets", target)
+                .await
+                .starts_with("HTTP/1.1 200"));
+            assert!(request(addr, "GET", route, "")
+                .await
+                .contains(r#""rule":"default""#));
+
+            assert_eq!(
+                request(addr, "GET", "/connections", "")
+                    .await
+                    .lines()
+                    .last(),
+                Some("[]")
+            );
+            assert!(request(addr, "DELETE", "/connections/1", "")
+                .await
+                .starts_with("HTTP/1.1 404"));
+
+            let stats = request(addr, "GET", "/stats", "").await;
+            assert!(stats.contains(r#""direct":{"#));
+        };
+
+        tokio::select! {
+            _ = control.serve(&app) => panic!("control server exited"),
+            _ = client => {}
+        }
+        std::fs::remove_file(resource).unwrap();
+    }
+}
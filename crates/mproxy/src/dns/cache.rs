@@ -0,0 +1,164 @@
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+use hickory_proto::{
+    op::{Message, Query, ResponseCode},
+    rr::{DNSClass, Record, RecordType},
+};
+use tokio::time::Instant;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Key {
+    name: String,
+    query_type: RecordType,
+    query_class: DNSClass,
+}
+
+impl From<&Query> for Key {
+    fn from(query: &Query) -> Self {
+        Self {
+            name: query.name().to_lowercase().to_utf8(),
+            query_type: query.query_type(),
+            query_class: query.query_class(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    message: Message,
+    expires: Instant,
+    inserted: Instant,
+}
+
+/// Response cache honoring the smallest TTL of the cached records.
+#[derive(Debug)]
+pub struct Cache {
+    entries: Mutex<HashMap<Key, Entry>>,
+    capacity: usize,
+}
+
+impl Cache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            capacity,
+        }
+    }
+
+    /// Returns the cached response with the TTLs reduced by the time spent
+    /// in the cache.
+    pub fn get(&self, query: &Query) -> Option<Message> {
+        let key = Key::from(query);
+        let now = Instant::now();
+
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get(&key)?;
+        if entry.expires <= now {
+            entries.remove(&key);
+            return None;
+        }
+
+        let elapsed = now.duration_since(entry.inserted).as_secs() as u32;
+        let mut message = entry.message.clone();
+        let decrease = |records: &mut Vec<Record>| {
+            for record in records.iter_mut() {
+                record.set_ttl(record.ttl().saturating_sub(elapsed));
+            }
+        };
+        decrease(message.answers_mut());
+        decrease(message.name_servers_mut());
+        decrease(message.additionals_mut());
+        Some(message)
+    }
+
+    pub fn insert(&self, query: &Query, message: &Message) {
+        if message.response_code() != ResponseCode::NoError || self.capacity == 0 {
+            return;
+        }
+        let Some(ttl) = message
+            .answers()
+            .iter()
+            .chain(message.name_servers())
+            .chain(message.additionals())
+            .map(|record| record.ttl())
+            .min()
+        else {
+            return;
+        };
+        if ttl == 0 {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.capacity {
+            entries.retain(|_, entry| entry.expires > now);
+        }
+        if entries.len() >= self.capacity {
+            // evict the entry closest to expiry
+            if let Some(key) = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.expires)
+                .map(|(key, _)| key.clone())
+            {
+                entries.remove(&key);
+            }
+        }
+        entries.insert(
+            Key::from(query),
+            Entry {
+                message: message.clone(),
+                expires: now + Duration::from_secs(ttl as u64),
+                inserted: now,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, str::FromStr};
+
+    use hickory_proto::rr::{rdata::A, Name, RData};
+
+    use super::*;
+
+    fn response(name: &str, ttl: u32) -> (Query, Message) {
+        let name = Name::from_str(name).unwrap();
+        let query = Query::query(name.clone(), RecordType::A);
+        let mut message = Message::new();
+        message.add_query(query.clone());
+        message.add_answer(Record::from_rdata(
+            name,
+            ttl,
+            RData::A(A(Ipv4Addr::new(10, 0, 0, 1))),
+        ));
+        (query, message)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ttl_expiry() {
+        let cache = Cache::new(16);
+        let (query, message) = response("example.com.", 60);
+        cache.insert(&query, &message);
+
+        tokio::time::advance(Duration::from_secs(20)).await;
+        let cached = cache.get(&query).unwrap();
+        assert_eq!(cached.answers()[0].ttl(), 40);
+
+        tokio::time::advance(Duration::from_secs(40)).await;
+        assert!(cache.get(&query).is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn capacity() {
+        let cache = Cache::new(1);
+        let (a, message_a) = response("a.example.com.", 60);
+        let (b, message_b) = response("b.example.com.", 60);
+        cache.insert(&a, &message_a);
+        cache.insert(&b, &message_b);
+
+        assert!(cache.get(&a).is_none());
+        assert!(cache.get(&b).is_some());
+    }
+}
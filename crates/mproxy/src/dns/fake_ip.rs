@@ -0,0 +1,129 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::Mutex,
+};
+
+use ipnet::IpNet;
+
+#[derive(Debug, Default)]
+struct Table {
+    /// offset of the next address handed out
+    next: u128,
+    ip_to_domain: HashMap<IpAddr, String>,
+    domain_to_ip: HashMap<String, IpAddr>,
+}
+
+/// Pool of synthetic addresses with a bidirectional ip <-> domain table.
+///
+/// The network address and the first host are never handed out, the latter
+/// is usually taken by the gateway (e.g. the tun device). Once the pool is
+/// exhausted addresses are recycled in allocation order.
+#[derive(Debug)]
+pub struct FakeIpPool {
+    range: IpNet,
+    size: u128,
+    table: Mutex<Table>,
+}
+
+const RESERVED: u128 = 2;
+
+impl FakeIpPool {
+    pub fn new(range: IpNet) -> Result<Self, anyhow::Error> {
+        let bits = range.max_prefix_len() - range.prefix_len();
+        let size = 1u128
+            .checked_shl(bits as u32)
+            .unwrap_or(u128::MAX)
+            .saturating_sub(RESERVED);
+        if size == 0 {
+            anyhow::bail!("fake ip range {} is too small", range);
+        }
+
+        Ok(Self {
+            range: range.trunc(),
+            size,
+            table: Mutex::new(Table::default()),
+        })
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.range.contains(ip)
+    }
+
+    pub fn is_ipv4(&self) -> bool {
+        matches!(self.range, IpNet::V4(_))
+    }
+
+    /// Returns the address of the domain, allocating one if needed.
+    pub fn allocate(&self, domain: &str) -> IpAddr {
+        let domain = domain.trim_end_matches('.').to_lowercase();
+
+        let mut table = self.table.lock().unwrap();
+        if let Some(ip) = table.domain_to_ip.get(&domain) {
+            return *ip;
+        }
+
+        let ip = self.nth(table.next);
+        table.next = (table.next + 1) % self.size;
+
+        if let Some(old) = table.ip_to_domain.insert(ip, domain.clone()) {
+            table.domain_to_ip.remove(&old);
+        }
+        table.domain_to_ip.insert(domain, ip);
+        ip
+    }
+
+    pub fn lookup(&self, ip: &IpAddr) -> Option<String> {
+        if !self.contains(ip) {
+            return None;
+        }
+        self.table.lock().unwrap().ip_to_domain.get(ip).cloned()
+    }
+
+    fn nth(&self, n: u128) -> IpAddr {
+        match self.range {
+            IpNet::V4(net) => {
+                IpAddr::V4(Ipv4Addr::from(u32::from(net.network()) + (n + RESERVED) as u32))
+            }
+            IpNet::V6(net) => {
+                IpAddr::V6(Ipv6Addr::from(u128::from(net.network()) + n + RESERVED))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocate_and_lookup() {
+        let pool = FakeIpPool::new("198.18.0.0/16".parse().unwrap()).unwrap();
+
+        let a = pool.allocate("example.com.");
+        let b = pool.allocate("example.org");
+        assert_eq!(a, "198.18.0.2".parse::<IpAddr>().unwrap());
+        assert_eq!(b, "198.18.0.3".parse::<IpAddr>().unwrap());
+        assert_eq!(pool.allocate("Example.COM"), a);
+
+        assert_eq!(pool.lookup(&a).as_deref(), Some("example.com"));
+        assert_eq!(pool.lookup(&"198.18.0.4".parse().unwrap()), None);
+        assert_eq!(pool.lookup(&"10.0.0.2".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn recycle() {
+        let pool = FakeIpPool::new("198.18.0.0/30".parse().unwrap()).unwrap();
+
+        let a = pool.allocate("a.com");
+        let b = pool.allocate("b.com");
+        assert_ne!(a, b);
+
+        // the pool holds two addresses, a.com is evicted
+        let c = pool.allocate("c.com");
+        assert_eq!(c, a);
+        assert_eq!(pool.lookup(&a).as_deref(), Some("c.com"));
+        assert_eq!(pool.lookup(&b).as_deref(), Some("b.com"));
+        assert_ne!(pool.allocate("a.com"), a);
+    }
+}
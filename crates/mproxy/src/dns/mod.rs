@@ -0,0 +1,559 @@
+mod cache;
+mod fake_ip;
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use anyhow::Context;
+use hickory_proto::{
+    op::{Message, MessageType, ResponseCode},
+    rr::{
+        rdata::{A, AAAA},
+        RData, Record, RecordType,
+    },
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    config::dns::DnsConfig,
+    proxy::{Address, Egress, NetLocation, ProxyConn, ProxyRequest, TcpForwarder},
+    router::Router,
+};
+
+use self::{cache::Cache, fake_ip::FakeIpPool};
+
+const MAX_MESSAGE_SIZE: usize = 65535;
+const DNS_PORT: u16 = 53;
+const DEFAULT_CACHE_SIZE: usize = 4096;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_FAKE_IP_TTL: u32 = 1;
+
+#[derive(Debug)]
+struct Upstream {
+    address: SocketAddr,
+    egress: Option<Arc<Egress>>,
+}
+
+/// DNS server answering over udp and tcp, the upstream of each query is
+/// chosen by the routing rule matching the queried name.
+#[derive(Debug)]
+pub struct DnsServer {
+    id: String,
+    udp: Arc<UdpSocket>,
+    tcp: TcpListener,
+    upstream: HashMap<String, Upstream>,
+    rules: HashMap<String, String>,
+    default_upstream: String,
+    cache: Cache,
+    fake_ip: Option<(FakeIpPool, u32)>,
+    router: RwLock<Arc<Router>>,
+}
+
+impl DnsServer {
+    pub async fn new(
+        config: DnsConfig,
+        router: Arc<Router>,
+        egress: &[Arc<Egress>],
+    ) -> Result<Self, anyhow::Error> {
+        let upstream = config
+            .upstream
+            .into_iter()
+            .map(|upstream| {
+                let egress = match upstream.egress {
+                    Some(id) => Some(
+                        egress
+                            .iter()
+                            .find(|egress| egress.id == id)
+                            .context(format!("Egress {} isn't exist", id))?
+                            .clone(),
+                    ),
+                    None => None,
+                };
+                Ok::<_, anyhow::Error>((
+                    upstream.id,
+                    Upstream {
+                        address: upstream.address,
+                        egress,
+                    },
+                ))
+            })
+            .try_collect::<HashMap<_, _>>()?;
+
+        for id in config
+            .rule
+            .iter()
+            .map(|rule| &rule.upstream)
+            .chain([&config.default_upstream])
+        {
+            if !upstream.contains_key(id) {
+                anyhow::bail!("Upstream {} isn't exist", id);
+            }
+        }
+
+        info!("Listening on {}", config.listen);
+
+        let udp = UdpSocket::bind(config.listen).await?;
+        // share the port picked for udp when listening on port 0
+        let tcp = TcpListener::bind(udp.local_addr()?).await?;
+
+        Ok(Self {
+            id: config.id,
+            udp: Arc::new(udp),
+            tcp,
+            upstream,
+            rules: config
+                .rule
+                .into_iter()
+                .map(|rule| (rule.rule, rule.upstream))
+                .collect(),
+            default_upstream: config.default_upstream,
+            cache: Cache::new(config.cache_size.unwrap_or(DEFAULT_CACHE_SIZE)),
+            fake_ip: match config.fake_ip {
+                Some(fake_ip) => Some((
+                    FakeIpPool::new(fake_ip.range)?,
+                    fake_ip.ttl.unwrap_or(DEFAULT_FAKE_IP_TTL),
+                )),
+                None => None,
+            },
+            router: RwLock::new(router),
+        })
+    }
+
+    /// Replaces the router used to pick upstreams, used on reload.
+    pub fn set_router(&self, router: Arc<Router>) {
+        *self.router.write().unwrap() = router;
+    }
+
+    /// Returns the domain a fake ip was handed out for.
+    pub fn lookup_fake_ip(&self, ip: &IpAddr) -> Option<String> {
+        self.fake_ip.as_ref().and_then(|(pool, _)| pool.lookup(ip))
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, anyhow::Error> {
+        Ok(self.udp.local_addr()?)
+    }
+
+    pub async fn run(self: Arc<Self>) {
+        tokio::spawn(self.clone().run_udp());
+        self.run_tcp().await
+    }
+
+    async fn run_udp(self: Arc<Self>) {
+        let mut buf = vec![0u8; MAX_MESSAGE_SIZE];
+        loop {
+            match self.udp.recv_from(&mut buf).await {
+                Ok((n, src)) => {
+                    let this = self.clone();
+                    let query = buf[..n].to_vec();
+                    tokio::spawn(async move {
+                        let response = this.handle(&query).await;
+                        if response.is_empty() {
+                            return;
+                        }
+                        if let Err(e) = this.udp.send_to(&response, src).await {
+                            warn!("reply to {} error: {:?}", src, e);
+                        }
+                    });
+                }
+                Err(e) => {
+                    warn!("udp recv error: {:?}", e);
+                    break;
+                }
+            }
+        }
+    }
+
+    async fn run_tcp(self: Arc<Self>) {
+        loop {
+            match self.tcp.accept().await {
+                Ok((stream, _)) => {
+                    let this = self.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = this.serve_tcp(stream).await {
+                            debug!("{:?}", e);
+                        }
+                    });
+                }
+                Err(e) => {
+                    warn!("tcp accept error: {:?}", e);
+                    break;
+                }
+            }
+        }
+    }
+
+    async fn serve_tcp(&self, mut stream: TcpStream) -> Result<(), anyhow::Error> {
+        loop {
+            let len = match stream.read_u16().await {
+                Ok(len) => len,
+                Err(_) => return Ok(()),
+            };
+            let mut query = vec![0u8; len as usize];
+            stream.read_exact(&mut query).await?;
+
+            let response = self.handle(&query).await;
+            if response.is_empty() {
+                return Ok(());
+            }
+            stream.write_u16(response.len() as u16).await?;
+            stream.write_all(&response).await?;
+        }
+    }
+
+    /// Answers a query, failures are turned into SERVFAIL.
+    async fn handle(&self, query: &[u8]) -> Vec<u8> {
+        let request = match Message::from_vec(query) {
+            Ok(request) => request,
+            Err(e) => {
+                debug!("invalid dns query: {:?}", e);
+                return vec![];
+            }
+        };
+
+        match self.resolve(&request).await {
+            Ok(response) => response,
+            Err(e) => {
+                warn!("dns query error: {:?}", e);
+                let mut response =
+                    Message::error_msg(request.id(), request.op_code(), ResponseCode::ServFail);
+                response.add_queries(request.queries().to_vec());
+                response.to_vec().unwrap_or_default()
+            }
+        }
+    }
+
+    async fn resolve(&self, request: &Message) -> Result<Vec<u8>, anyhow::Error> {
+        let query = request.queries().first().context("empty dns query")?;
+
+        if let Some(response) = self.resolve_fake_ip(request) {
+            return Ok(response.to_vec()?);
+        }
+
+        if let Some(mut response) = self.cache.get(query) {
+            response
+                .set_id(request.id())
+                .set_message_type(MessageType::Response);
+            return Ok(response.to_vec()?);
+        }
+
+        let name = query.name().to_utf8();
+        let rule = self.router.read().unwrap().match_rule(
+            &self.id,
+            &NetLocation {
+                address: Address::Hostname(name.trim_end_matches('.').to_string()),
+                port: DNS_PORT,
+            },
+        );
+        let upstream = self
+            .rules
+            .get(&rule)
+            .and_then(|id| self.upstream.get(id))
+            .or(self.upstream.get(&self.default_upstream))
+            .context("default upstream is not exist")?;
+
+        debug!(name, rule, upstream = upstream.address.to_string(), "dns query");
+
+        let buf = request.to_vec()?;
+        let response = tokio::time::timeout(QUERY_TIMEOUT, async {
+            match &upstream.egress {
+                Some(egress) => query_via_egress(egress.clone(), upstream.address, &buf).await,
+                None => query_udp(upstream.address, &buf).await,
+            }
+        })
+        .await
+        .context("dns query timeout")??;
+
+        let message = Message::from_vec(&response)?;
+        self.cache.insert(query, &message);
+
+        Ok(response)
+    }
+
+    /// Answers A/AAAA queries from the fake ip pool, queries of the other
+    /// family get an empty answer so clients fall back to the pool family.
+    fn resolve_fake_ip(&self, request: &Message) -> Option<Message> {
+        let (pool, ttl) = self.fake_ip.as_ref()?;
+        let query = request.queries().first()?;
+
+        let matched = match query.query_type() {
+            RecordType::A => pool.is_ipv4(),
+            RecordType::AAAA => !pool.is_ipv4(),
+            _ => return None,
+        };
+
+        let mut response = Message::new();
+        response
+            .set_id(request.id())
+            .set_message_type(MessageType::Response)
+            .set_op_code(request.op_code())
+            .set_recursion_desired(request.recursion_desired())
+            .set_recursion_available(true)
+            .add_query(query.clone());
+
+        if matched {
+            let rdata = match pool.allocate(&query.name().to_utf8()) {
+                IpAddr::V4(ip) => RData::A(A(ip)),
+                IpAddr::V6(ip) => RData::AAAA(AAAA(ip)),
+            };
+            response.add_answer(Record::from_rdata(query.name().clone(), *ttl, rdata));
+        }
+        Some(response)
+    }
+}
+
+async fn query_udp(upstream: SocketAddr, query: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+    let socket = UdpSocket::bind(match upstream {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    })
+    .await?;
+    socket.connect(upstream).await?;
+    socket.send(query).await?;
+
+    let mut buf = vec![0u8; MAX_MESSAGE_SIZE];
+    let n = socket.recv(&mut buf).await?;
+    buf.truncate(n);
+    Ok(buf)
+}
+
+/// Sends the query over tcp through an egress.
+async fn query_via_egress(
+    egress: Arc<Egress>,
+    upstream: SocketAddr,
+    query: &[u8],
+) -> Result<Vec<u8>, anyhow::Error> {
+    let (stream, mut inner) = tokio::io::duplex(MAX_MESSAGE_SIZE);
+
+    let req = ProxyRequest {
+        remote: upstream.into(),
+        source: None,
+        route: None,
+        conn: ProxyConn::ForwardTcp(TcpForwarder {
+            stream: Box::new(stream),
+        }),
+    };
+    tokio::spawn(async move {
+        if let Err(e) = egress.send(req).await {
+            warn!("dns query through {} error: {:?}", egress.id, e);
+        }
+    });
+
+    inner.write_u16(query.len() as u16).await?;
+    inner.write_all(query).await?;
+
+    let len = inner.read_u16().await?;
+    let mut buf = vec![0u8; len as usize];
+    inner.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::Ipv4Addr,
+        str::FromStr,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use hickory_proto::{op::Query, rr::Name};
+
+    use crate::config::{
+        dns::{DnsRuleConfig, FakeIpConfig, UpstreamConfig},
+        routing::{RoutingConfig, RuleConfig},
+    };
+
+    use super::*;
+
+    /// Stand-in upstream answering every A query with `ip`.
+    async fn upstream(ip: Ipv4Addr) -> (SocketAddr, Arc<AtomicUsize>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let count = Arc::new(AtomicUsize::new(0));
+
+        let counter = count.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; MAX_MESSAGE_SIZE];
+            loop {
+                let (n, src) = socket.recv_from(&mut buf).await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+
+                let request = Message::from_vec(&buf[..n]).unwrap();
+                let mut response = Message::new();
+                response
+                    .set_id(request.id())
+                    .set_message_type(MessageType::Response)
+                    .add_queries(request.queries().to_vec());
+                response.add_answer(Record::from_rdata(
+                    request.queries()[0].name().clone(),
+                    60,
+                    RData::A(A(ip)),
+                ));
+                socket
+                    .send_to(&response.to_vec().unwrap(), src)
+                    .await
+                    .unwrap();
+            }
+        });
+
+        (addr, count)
+    }
+
+    async fn lookup(server: SocketAddr, name: &str) -> Ipv4Addr {
+        let mut request = Message::new();
+        request
+            .set_id(1)
+            .set_recursion_desired(true)
+            .add_query(Query::query(Name::from_str(name).unwrap(), RecordType::A));
+
+        let answer = query_udp(server, &request.to_vec().unwrap()).await.unwrap();
+        let answer = Message::from_vec(&answer).unwrap();
+        assert_eq!(answer.id(), 1);
+        match answer.answers()[0].data() {
+            Some(RData::A(a)) => a.0,
+            _ => panic!("expected A record"),
+        }
+    }
+
+    #[tokio::test]
+    async fn rule_upstream_and_cache() {
+        let (local, local_count) = upstream(Ipv4Addr::new(10, 0, 0, 1)).await;
+        let (remote, remote_count) = upstream(Ipv4Addr::new(10, 0, 0, 2)).await;
+
+        let router = Router::new(RoutingConfig {
+            resource: vec![],
+            geoip: vec![],
+            rule: vec![
+                RuleConfig {
+                    id: "proxy".to_string(),
+                    target: vec!["d:example.com".to_string()],
+                    src: vec!["dns".to_string()],
+                    port: vec![],
+                    dest: "proxy".to_string(),
+                    limit: None,
+                },
+                RuleConfig {
+                    id: "default".to_string(),
+                    target: vec![],
+                    src: vec![],
+                    port: vec![],
+                    dest: "direct".to_string(),
+                    limit: None,
+                },
+            ],
+            default_rule: "default".to_string(),
+        })
+        .unwrap();
+
+        let server = DnsServer::new(
+            DnsConfig {
+                id: "dns".to_string(),
+                listen: "127.0.0.1:0".parse().unwrap(),
+                upstream: vec![
+                    UpstreamConfig {
+                        id: "local".to_string(),
+                        address: local,
+                        egress: None,
+                    },
+                    UpstreamConfig {
+                        id: "remote".to_string(),
+                        address: remote,
+                        egress: None,
+                    },
+                ],
+                rule: vec![DnsRuleConfig {
+                    rule: "proxy".to_string(),
+                    upstream: "remote".to_string(),
+                }],
+                default_upstream: "local".to_string(),
+                cache_size: None,
+                fake_ip: None,
+            },
+            Arc::new(router),
+            &[],
+        )
+        .await
+        .unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(Arc::new(server).run());
+
+        assert_eq!(
+            lookup(addr, "www.example.com.").await,
+            Ipv4Addr::new(10, 0, 0, 2)
+        );
+        assert_eq!(
+            lookup(addr, "www.example.org.").await,
+            Ipv4Addr::new(10, 0, 0, 1)
+        );
+        assert_eq!(
+            lookup(addr, "www.example.com.").await,
+            Ipv4Addr::new(10, 0, 0, 2)
+        );
+
+        assert_eq!(remote_count.load(Ordering::SeqCst), 1);
+        assert_eq!(local_count.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn fake_ip() {
+        let (local, local_count) = upstream(Ipv4Addr::new(10, 0, 0, 1)).await;
+
+        let router = Router::new(RoutingConfig {
+            resource: vec![],
+            geoip: vec![],
+            rule: vec![RuleConfig {
+                id: "default".to_string(),
+                target: vec![],
+                src: vec![],
+                port: vec![],
+                dest: "direct".to_string(),
+                limit: None,
+            }],
+            default_rule: "default".to_string(),
+        })
+        .unwrap();
+
+        let server = Arc::new(
+            DnsServer::new(
+                DnsConfig {
+                    id: "dns".to_string(),
+                    listen: "127.0.0.1:0".parse().unwrap(),
+                    upstream: vec![UpstreamConfig {
+                        id: "local".to_string(),
+                        address: local,
+                        egress: None,
+                    }],
+                    rule: vec![],
+                    default_upstream: "local".to_string(),
+                    cache_size: None,
+                    fake_ip: Some(FakeIpConfig {
+                        range: "198.18.0.0/16".parse().unwrap(),
+                        ttl: None,
+                    }),
+                },
+                Arc::new(router),
+                &[],
+            )
+            .await
+            .unwrap(),
+        );
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(server.clone().run());
+
+        let ip = lookup(addr, "www.example.com.").await;
+        assert!("198.18.0.0/16".parse::<ipnet::IpNet>().unwrap().contains(&ip));
+        assert_eq!(lookup(addr, "www.example.com.").await, ip);
+        assert_eq!(
+            server.lookup_fake_ip(&IpAddr::V4(ip)).as_deref(),
+            Some("www.example.com")
+        );
+        assert_eq!(local_count.load(Ordering::SeqCst), 0);
+    }
+}
@@ -0,0 +1,229 @@
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::Arc,
+    time::{Instant, SystemTime},
+};
+
+use anyhow::bail;
+use futures::future::join_all;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::RwLock,
+    task::JoinHandle,
+};
+use tracing::{debug, warn};
+
+use crate::{
+    config::health::HealthCheckConfig,
+    proxy::{Egress, NetLocation, ProxyConn, ProxyRequest, TcpForwarder},
+    stats::{uncounted, HealthStatus},
+};
+
+/// Periodically opens a connection to the probe target through every egress
+/// and records whether a response came back and how long it took.
+#[derive(Debug)]
+pub struct HealthChecker {
+    config: HealthCheckConfig,
+    target: NetLocation,
+    egress: Vec<Arc<Egress>>,
+    status: RwLock<HashMap<String, HealthStatus>>,
+}
+
+impl HealthChecker {
+    pub fn new(config: HealthCheckConfig, egress: &[Arc<Egress>]) -> Result<Self, anyhow::Error> {
+        Ok(Self {
+            target: NetLocation::from_str(&config.target)?,
+            config,
+            egress: egress.to_vec(),
+            status: RwLock::new(HashMap::new()),
+        })
+    }
+
+    pub async fn run(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(self.config.interval);
+        loop {
+            interval.tick().await;
+            self.check_all().await;
+        }
+    }
+
+    pub async fn status(&self) -> HashMap<String, HealthStatus> {
+        self.status.read().await.clone()
+    }
+
+    pub async fn check_all(&self) {
+        let results = join_all(self.egress.iter().map(|egress| self.check(egress))).await;
+
+        let mut status = self.status.write().await;
+        for (egress, result) in self.egress.iter().zip(results) {
+            status.insert(egress.id.clone(), result);
+        }
+    }
+
+    async fn check(&self, egress: &Arc<Egress>) -> HealthStatus {
+        let now = Instant::now();
+        let result = match tokio::time::timeout(self.config.timeout, self.probe(egress)).await {
+            Ok(result) => result,
+            Err(_) => Err(anyhow::anyhow!("timed out after {:?}", self.config.timeout)),
+        };
+
+        let status = match result {
+            Ok(()) => {
+                let latency = now.elapsed();
+                debug!(
+                    "{} is healthy, latency {}ms",
+                    egress.id,
+                    latency.as_millis()
+                );
+                HealthStatus {
+                    healthy: true,
+                    latency: Some(latency),
+                    checked_at: SystemTime::now(),
+                    error: None,
+                }
+            }
+            Err(e) => {
+                warn!("{} health check failed: {:?}", egress.id, e);
+                HealthStatus {
+                    healthy: false,
+                    latency: None,
+                    checked_at: SystemTime::now(),
+                    error: Some(format!("{:#}", e)),
+                }
+            }
+        };
+        egress.set_latency(status.latency);
+        status
+    }
+
+    /// Sends the payload and waits for the first byte of the response.
+    async fn probe(&self, egress: &Arc<Egress>) -> Result<(), anyhow::Error> {
+        let (stream, mut client) = tokio::io::duplex(4096);
+        let req = ProxyRequest {
+            remote: self.target.clone(),
+            source: None,
+            route: None,
+            conn: ProxyConn::ForwardTcp(TcpForwarder {
+                stream: Box::new(stream),
+            }),
+        };
+        let mut send = {
+            let egress = egress.clone();
+            AbortOnDrop(tokio::spawn(uncounted(
+                async move { egress.send(req).await },
+            )))
+        };
+
+        let payload = match &self.config.payload {
+            Some(payload) => payload.clone(),
+            None => format!(
+                "HEAD / HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+                self.target.address
+            ),
+        };
+        client.write_all(payload.as_bytes()).await?;
+
+        let mut buf = [0u8; 1];
+        let n = client.read(&mut buf).await?;
+        if n == 0 {
+            // the egress gave up on the request, report its reason
+            return match (&mut send.0).await? {
+                Err(e) => bail!("{}", e),
+                Ok(_) => bail!("connection closed without response"),
+            };
+        }
+        Ok(())
+    }
+}
+
+/// Stops the probe's request when the probe is dropped, done or timed out.
+struct AbortOnDrop<T>(JoinHandle<T>);
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::net::TcpListener;
+
+    use crate::{
+        config::egress::ClientConfig,
+        proxy::test_support::{dead_egress, egress, http_egress},
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn probe() {
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut s, _) = echo.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 1024];
+                    let n = s.read(&mut buf).await.unwrap();
+                    s.write_all(&buf[..n]).await.unwrap();
+                });
+            }
+        });
+
+        let direct = egress("direct", ClientConfig::Direct(Default::default())).await;
+        let dead = dead_egress("dead").await;
+
+        let checker = HealthChecker::new(
+            HealthCheckConfig {
+                target: echo_addr.to_string(),
+                payload: Some("ping".to_string()),
+                interval: Duration::from_secs(60),
+                timeout: Duration::from_secs(5),
+            },
+            &[direct.clone(), dead.clone()],
+        )
+        .unwrap();
+        checker.check_all().await;
+
+        let status = checker.status().await;
+        assert!(status["direct"].healthy);
+        assert!(status["direct"].latency.is_some());
+        assert_eq!(direct.latency(), status["direct"].latency);
+
+        assert!(!status["dead"].healthy);
+        assert!(status["dead"].error.is_some());
+        assert_eq!(dead.latency(), None);
+    }
+
+    #[tokio::test]
+    async fn timeout_drops_request() {
+        // a proxy that never answers the CONNECT
+        let silent = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = http_egress("proxy", silent.local_addr().unwrap()).await;
+
+        let checker = HealthChecker::new(
+            HealthCheckConfig {
+                target: "127.0.0.1:80".to_string(),
+                payload: None,
+                interval: Duration::from_secs(60),
+                timeout: Duration::from_millis(100),
+            },
+            &[proxy],
+        )
+        .unwrap();
+        checker.check_all().await;
+        assert!(!checker.status().await["proxy"].healthy);
+
+        // the request is gone with the probe, closing its connection
+        let (mut s, _) = silent.accept().await.unwrap();
+        let mut buf = vec![];
+        tokio::time::timeout(Duration::from_secs(5), s.read_to_end(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
@@ -1,7 +1,13 @@
 mod async_io;
+mod packet_io;
+mod rate_limit;
+mod rewind;
 mod timeout_io;
 mod util;
 
 pub use async_io::*;
+pub use packet_io::*;
+pub use rate_limit::*;
+pub use rewind::*;
 pub use timeout_io::*;
 pub use util::*;
@@ -0,0 +1,18 @@
+use std::io;
+
+use tokio::net::UdpSocket;
+
+pub trait PacketIO {
+    async fn send(&self, buf: &[u8]) -> io::Result<usize>;
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize>;
+}
+
+impl PacketIO for UdpSocket {
+    async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        UdpSocket::send(self, buf).await
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        UdpSocket::recv(self, buf).await
+    }
+}
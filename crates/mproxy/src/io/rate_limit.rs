@@ -0,0 +1,385 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{sleep, Instant, Sleep},
+};
+
+use crate::config::limit::RateLimitConfig;
+
+#[derive(Debug)]
+struct BucketState {
+    tokens: f64,
+    last: Instant,
+}
+
+/// Refilled at `rate` bytes per second up to one second worth of bytes.
+/// Concurrent users may overdraw it, the debt delays whoever comes next.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: u64,
+    state: Mutex<BucketState>,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            state: Mutex::new(BucketState {
+                tokens: rate as f64,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    /// Waiting for single bytes would wake up for every byte, a transfer
+    /// waits until a tenth of a second worth is available instead.
+    fn chunk(&self) -> f64 {
+        (self.rate / 10).clamp(1, 16 * 1024) as f64
+    }
+
+    /// Bytes that may pass now, or how long until enough are available.
+    pub fn available(&self) -> Result<usize, Duration> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.last).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        state.last = now;
+
+        let chunk = self.chunk();
+        if state.tokens >= chunk {
+            Ok(state.tokens as usize)
+        } else {
+            Err(Duration::from_secs_f64(
+                (chunk - state.tokens) / self.rate as f64,
+            ))
+        }
+    }
+
+    pub fn consume(&self, n: usize) {
+        self.state.lock().unwrap().tokens -= n as f64;
+    }
+}
+
+/// Upload and download buckets of an ingress, egress or rule.
+#[derive(Debug)]
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    upload: Option<TokenBucket>,
+    download: Option<TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Result<Self, anyhow::Error> {
+        Self::check(config)?;
+        Ok(Self {
+            config: config.clone(),
+            upload: config.upload.map(TokenBucket::new),
+            download: config.download.map(TokenBucket::new),
+        })
+    }
+
+    /// A bucket refilled at 0 bytes per second would never let anything
+    /// through, leave the direction out to not limit it instead.
+    pub fn check(config: &RateLimitConfig) -> Result<(), anyhow::Error> {
+        if config.upload == Some(0) {
+            anyhow::bail!("upload rate can't be 0");
+        }
+        if config.download == Some(0) {
+            anyhow::bail!("download rate can't be 0");
+        }
+        Ok(())
+    }
+
+    pub fn config(&self) -> &RateLimitConfig {
+        &self.config
+    }
+}
+
+/// A limiter that may change after a stream has been wrapped, such as the
+/// one of the group member a connection is being tried on.
+#[derive(Debug, Default)]
+pub struct LimiterSlot(Mutex<Option<Arc<RateLimiter>>>);
+
+impl LimiterSlot {
+    pub fn set(&self, limiter: Option<Arc<RateLimiter>>) {
+        *self.0.lock().unwrap() = limiter;
+    }
+
+    fn get(&self) -> Option<Arc<RateLimiter>> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+/// Wraps the client side of a connection, reads are charged as upload and
+/// writes as download to every limiter. On the remote side it's the other
+/// way around.
+#[derive(Debug)]
+pub struct RateLimitedIO<T> {
+    inner: T,
+    limiters: Vec<Arc<RateLimiter>>,
+    slot: Option<Arc<LimiterSlot>>,
+    remote: bool,
+    read_sleep: Option<Pin<Box<Sleep>>>,
+    write_sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<T> RateLimitedIO<T> {
+    pub fn new(inner: T, limiters: Vec<Arc<RateLimiter>>) -> Self {
+        Self {
+            inner,
+            limiters,
+            slot: None,
+            remote: false,
+            read_sleep: None,
+            write_sleep: None,
+        }
+    }
+
+    /// Wraps the remote side of a connection.
+    pub fn remote(inner: T, limiters: Vec<Arc<RateLimiter>>) -> Self {
+        Self {
+            remote: true,
+            ..Self::new(inner, limiters)
+        }
+    }
+
+    /// Also charges whatever limiter the slot holds at the time.
+    pub fn with_slot(self, slot: Arc<LimiterSlot>) -> Self {
+        Self {
+            slot: Some(slot),
+            ..self
+        }
+    }
+}
+
+/// Waits until every bucket has tokens, returns how many bytes may pass or
+/// `None` if no bucket applies.
+fn poll_allowance<'a>(
+    cx: &mut Context<'_>,
+    sleeping: &mut Option<Pin<Box<Sleep>>>,
+    buckets: impl Iterator<Item = &'a TokenBucket> + Clone,
+) -> Poll<Option<usize>> {
+    loop {
+        if let Some(sleep) = sleeping {
+            ready!(sleep.as_mut().poll(cx));
+            *sleeping = None;
+        }
+
+        let mut allowed: Option<usize> = None;
+        let mut wait: Option<Duration> = None;
+        for bucket in buckets.clone() {
+            match bucket.available() {
+                Ok(n) => allowed = Some(allowed.map_or(n, |v| v.min(n))),
+                Err(d) => wait = Some(wait.map_or(d, |v| v.max(d))),
+            }
+        }
+        match wait {
+            Some(wait) => *sleeping = Some(Box::pin(sleep(wait))),
+            None => return Poll::Ready(allowed),
+        }
+    }
+}
+
+impl<T> AsyncRead for RateLimitedIO<T>
+where
+    T: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let slotted = this.slot.as_ref().and_then(|v| v.get());
+        let remote = this.remote;
+        let buckets = this.limiters.iter().chain(&slotted).filter_map(move |v| {
+            if remote {
+                v.download.as_ref()
+            } else {
+                v.upload.as_ref()
+            }
+        });
+        let allowed = match ready!(poll_allowance(cx, &mut this.read_sleep, buckets.clone())) {
+            Some(allowed) => allowed,
+            None => return Pin::new(&mut this.inner).poll_read(cx, buf),
+        };
+
+        let mut limited = ReadBuf::new(buf.initialize_unfilled_to(allowed.min(buf.remaining())));
+        ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+        let n = limited.filled().len();
+        buf.advance(n);
+        buckets.for_each(|bucket| bucket.consume(n));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T> AsyncWrite for RateLimitedIO<T>
+where
+    T: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        let this = self.get_mut();
+        let slotted = this.slot.as_ref().and_then(|v| v.get());
+        let remote = this.remote;
+        let buckets = this.limiters.iter().chain(&slotted).filter_map(move |v| {
+            if remote {
+                v.upload.as_ref()
+            } else {
+                v.download.as_ref()
+            }
+        });
+        let allowed = match ready!(poll_allowance(cx, &mut this.write_sleep, buckets.clone())) {
+            Some(allowed) => allowed,
+            None => return Pin::new(&mut this.inner).poll_write(cx, buf),
+        };
+
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..allowed.min(buf.len())]))?;
+        buckets.for_each(|bucket| bucket.consume(n));
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), io::Error>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    fn limiter(upload: Option<u64>, download: Option<u64>) -> Arc<RateLimiter> {
+        Arc::new(RateLimiter::new(&RateLimitConfig { upload, download }).unwrap())
+    }
+
+    #[test]
+    fn zero_rate() {
+        let config = RateLimitConfig {
+            upload: Some(0),
+            download: Some(1000),
+        };
+        assert!(RateLimiter::new(&config).is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn limit_upload() {
+        let (stream, mut client) = tokio::io::duplex(64 * 1024);
+        let mut stream = RateLimitedIO::new(stream, vec![limiter(Some(1000), None)]);
+
+        client.write_all(&[0u8; 3000]).await.unwrap();
+        drop(client);
+
+        let start = Instant::now();
+        let mut buf = vec![];
+        stream.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf.len(), 3000);
+
+        // the first second worth is in the bucket already
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_secs(2), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(2200), "{:?}", elapsed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn limit_download_shared() {
+        let shared = limiter(None, Some(1000));
+        let (a, mut a_client) = tokio::io::duplex(64 * 1024);
+        let (b, mut b_client) = tokio::io::duplex(64 * 1024);
+        let mut a = RateLimitedIO::new(a, vec![shared.clone()]);
+        // the tighter limiter of the two wins
+        let mut b = RateLimitedIO::new(b, vec![shared, limiter(None, Some(100_000))]);
+
+        let start = Instant::now();
+        let write = async {
+            a.write_all(&[0u8; 2000]).await.unwrap();
+            b.write_all(&[0u8; 2000]).await.unwrap();
+        };
+        let read = async {
+            let mut buf = [0u8; 2000];
+            a_client.read_exact(&mut buf).await.unwrap();
+            b_client.read_exact(&mut buf).await.unwrap();
+        };
+        tokio::join!(write, read);
+
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_secs(3), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(3200), "{:?}", elapsed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unlimited_direction() {
+        let (stream, mut client) = tokio::io::duplex(64 * 1024);
+        let mut stream = RateLimitedIO::new(stream, vec![limiter(None, Some(1))]);
+
+        let start = Instant::now();
+        client.write_all(&[0u8; 10_000]).await.unwrap();
+        let mut buf = [0u8; 10_000];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn limit_remote_side() {
+        let (stream, mut remote) = tokio::io::duplex(64 * 1024);
+        // writes to the remote are uploads
+        let mut stream = RateLimitedIO::remote(stream, vec![limiter(Some(1000), None)]);
+
+        let start = Instant::now();
+        let write = async {
+            stream.write_all(&[0u8; 3000]).await.unwrap();
+        };
+        let read = async {
+            let mut buf = [0u8; 3000];
+            remote.read_exact(&mut buf).await.unwrap();
+        };
+        tokio::join!(write, read);
+
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_secs(2), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(2200), "{:?}", elapsed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn limit_slot() {
+        let slot = Arc::new(LimiterSlot::default());
+        let (stream, mut client) = tokio::io::duplex(64 * 1024);
+        let mut stream = RateLimitedIO::new(stream, vec![]).with_slot(slot.clone());
+
+        // an empty slot doesn't limit
+        let start = Instant::now();
+        client.write_all(&[0u8; 3000]).await.unwrap();
+        let mut buf = [0u8; 3000];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        slot.set(Some(limiter(Some(1000), None)));
+        client.write_all(&[0u8; 3000]).await.unwrap();
+        drop(client);
+        let mut buf = vec![];
+        stream.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf.len(), 3000);
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_secs(2), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(2200), "{:?}", elapsed);
+    }
+}
@@ -8,8 +8,16 @@ pub use config::protos;
 
 cfg_if::cfg_if! {
     if #[cfg(not(target_family = "wasm"))] {
+        pub mod access_log;
+        pub mod check;
+        pub mod conntrack;
+        mod control;
+        mod dns;
+        mod health;
         mod io;
         mod net;
+        #[cfg(target_os = "linux")]
+        mod netcfg;
         pub mod proxy;
         pub mod router;
 
@@ -3,21 +3,181 @@ cfg_if::cfg_if! {
         fn main() {
         }
     } else {
-        use std::path::PathBuf;
-
-        use clap::Parser;
-
-        use mproxy::{App, AppConfig};
+        use std::{
+            path::{Path, PathBuf},
+            str::FromStr,
+        };
+
+        use clap::{Parser, Subcommand};
+
+        use anyhow::Context;
+        use mproxy::{
+            check::check,
+            proxy::NetLocation,
+            router::{
+                geosite::{format_domain, GroupDiff},
+                GeositeFile, Router,
+            },
+            App, AppConfig,
+        };
         use tokio::fs;
-        use tracing::debug;
+        use tracing::{debug, info, warn};
 
         use tracing_subscriber::{prelude::*, EnvFilter};
 
         #[derive(Parser, Debug)]
         #[command(author, version, about, long_about = None)]
         struct Args {
+            /// required unless editing geosite files
             #[arg(short, long)]
-            config: PathBuf,
+            config: Option<PathBuf>,
+
+            #[command(subcommand)]
+            command: Option<Command>,
+        }
+
+        #[derive(Subcommand, Debug)]
+        enum Command {
+            /// Check the config and its resource files without starting
+            Check,
+            /// Print the rule and egress a destination is routed to
+            Route {
+                /// `host[:port]`, port 443 if absent
+                destination: String,
+                /// ingress the request comes from, the first one if absent
+                #[arg(long)]
+                src: Option<String>,
+            },
+            /// Inspect and build geosite files
+            Geosite {
+                #[command(subcommand)]
+                command: GeositeCommand,
+            },
+        }
+
+        #[derive(Subcommand, Debug)]
+        enum GeositeCommand {
+            /// List the groups of a file and their sizes
+            List { file: PathBuf },
+            /// Print a group in the domain-list-community text format
+            Dump { file: PathBuf, tag: String },
+            /// Compile a directory of text lists, one group per file
+            Compile {
+                dir: PathBuf,
+                #[arg(short, long)]
+                output: PathBuf,
+            },
+            /// Merge files into one, later files add to earlier ones
+            Merge {
+                #[arg(required = true, num_args = 2..)]
+                files: Vec<PathBuf>,
+                #[arg(short, long)]
+                output: PathBuf,
+            },
+            /// Print the domains added and removed between two files
+            Diff { old: PathBuf, new: PathBuf },
+        }
+
+        async fn load_config(path: &Path) -> Result<AppConfig, anyhow::Error> {
+            let buf = fs::read_to_string(path).await?;
+            Ok(toml::from_str::<AppConfig>(&buf)?)
+        }
+
+        fn check_config(config: &AppConfig) -> Result<(), anyhow::Error> {
+            let errors = check(config);
+            if errors.is_empty() {
+                println!("config is ok");
+                return Ok(());
+            }
+            for e in &errors {
+                println!("error: {:#}", e);
+            }
+            anyhow::bail!("{} problems found", errors.len())
+        }
+
+        fn route(
+            config: AppConfig,
+            destination: &str,
+            src: Option<String>,
+        ) -> Result<(), anyhow::Error> {
+            let remote = NetLocation::from_str(destination)
+                .or_else(|_| NetLocation::from_str(&format!("{}:443", destination)))?;
+            let src = src
+                .or(config.ingress.first().map(|v| v.id.clone()))
+                .unwrap_or_default();
+            let decision = Router::new(config.routing)?.explain(&src, &remote)?;
+
+            let reason = match (&decision.target, decision.fallback) {
+                (_, true) => "no rule matched, default rule".to_string(),
+                (Some(target), _) => format!("matched target {}", target),
+                (None, _) => "matched by port".to_string(),
+            };
+            println!("{} from {}", remote, src);
+            println!("rule:   {} ({})", decision.rule, reason);
+            println!("egress: {}", decision.dest);
+            Ok(())
+        }
+
+        fn geosite(command: GeositeCommand) -> Result<(), anyhow::Error> {
+            match command {
+                GeositeCommand::List { file } => {
+                    for sg in GeositeFile::new(&file)?.site_groups() {
+                        println!("{}\t{}", sg.tag, sg.domain.len());
+                    }
+                }
+                GeositeCommand::Dump { file, tag } => {
+                    let file = GeositeFile::new(&file)?;
+                    let sg = file
+                        .get_site_group(&tag)
+                        .context(format!("geosite:{} isn't exist", tag))?;
+                    for domain in &sg.domain {
+                        println!("{}", format_domain(domain));
+                    }
+                }
+                GeositeCommand::Compile { dir, output } => {
+                    GeositeFile::compile(&dir, output)?.store()?;
+                }
+                GeositeCommand::Merge { files, output } => {
+                    let mut merged = GeositeFile::empty(output);
+                    for file in &files {
+                        merged.merge(&GeositeFile::new(file)?);
+                    }
+                    merged.store()?;
+                }
+                GeositeCommand::Diff { old, new } => {
+                    let diffs = GeositeFile::new(&old)?.diff(&GeositeFile::new(&new)?);
+                    for GroupDiff { tag, added, removed } in diffs {
+                        println!("[{}]", tag);
+                        removed.iter().for_each(|v| println!("- {}", v));
+                        added.iter().for_each(|v| println!("+ {}", v));
+                    }
+                }
+            }
+            Ok(())
+        }
+
+        #[cfg(unix)]
+        async fn reload_on_sighup(app: &App, path: &Path) -> Result<(), anyhow::Error> {
+            use tokio::signal::unix::{signal, SignalKind};
+
+            let mut hangup = signal(SignalKind::hangup())?;
+            while hangup.recv().await.is_some() {
+                info!("Reloading {}", path.display());
+                match load_config(path).await {
+                    Ok(config) => {
+                        if let Err(e) = app.reload(config).await {
+                            warn!("Failed to reload: {:?}", e);
+                        }
+                    }
+                    Err(e) => warn!("Failed to read config: {:?}", e),
+                }
+            }
+            Ok(())
+        }
+
+        #[cfg(not(unix))]
+        async fn reload_on_sighup(_app: &App, _path: &Path) -> Result<(), anyhow::Error> {
+            std::future::pending().await
         }
 
         #[tokio::main]
@@ -37,15 +197,29 @@ cfg_if::cfg_if! {
 
             let args = Args::parse();
 
-            let buf = fs::read_to_string(args.config).await?;
+            if let Some(Command::Geosite { command }) = args.command {
+                return geosite(command);
+            }
 
-            let config = toml::from_str::<AppConfig>(&buf)?;
+            let path = args.config.context("--config is required")?;
+            let config = load_config(&path).await?;
 
             debug!("{:?}", config);
 
+            match args.command {
+                Some(Command::Check) => return check_config(&config),
+                Some(Command::Route { destination, src }) => {
+                    return route(config, &destination, src)
+                }
+                Some(Command::Geosite { .. }) | None => {}
+            }
+
             let app = App::new(config).await?;
 
-            app.run().await?;
+            tokio::select! {
+                result = app.run() => result?,
+                result = reload_on_sighup(&app, &path) => result?,
+            }
 
             Ok(())
         }
@@ -16,13 +16,82 @@ use opentelemetry::{
     KeyValue,
 };
 use opentelemetry_prometheus::PrometheusExporter;
-use prometheus::{Encoder, TextEncoder};
-use std::sync::Arc;
+use prometheus::{Encoder, IntCounterVec, IntGaugeVec, Opts, TextEncoder};
+use std::sync::{Arc, Mutex, OnceLock};
 use tokio::net::TcpListener;
 use tracing::{error, info_span, instrument, Instrument, Subscriber};
 use tracing_opentelemetry::MetricsLayer;
 use tracing_subscriber::Layer;
 
+use crate::stats::TransferStats;
+
+/// Traffic by rule and by host, labels can't be attached to the counters
+/// recorded through tracing.
+struct TrafficMetrics {
+    rule_bytes: IntCounterVec,
+    top_host_bytes: IntGaugeVec,
+}
+
+static TRAFFIC: OnceLock<TrafficMetrics> = OnceLock::new();
+
+fn traffic() -> &'static TrafficMetrics {
+    TRAFFIC.get_or_init(|| TrafficMetrics {
+        rule_bytes: IntCounterVec::new(
+            Opts::new(
+                "mproxy_rule_bytes_total",
+                "Bytes forwarded per routing rule",
+            ),
+            &["rule", "direction"],
+        )
+        .unwrap(),
+        top_host_bytes: IntGaugeVec::new(
+            Opts::new(
+                "mproxy_top_host_bytes",
+                "Bytes forwarded to the busiest destination hosts",
+            ),
+            &["host", "direction"],
+        )
+        .unwrap(),
+    })
+}
+
+pub fn record_rule_transfer(rule: &str, stats: &TransferStats) {
+    let rule_bytes = &traffic().rule_bytes;
+    rule_bytes
+        .with_label_values(&[rule, "upload"])
+        .inc_by(stats.tx as u64);
+    rule_bytes
+        .with_label_values(&[rule, "download"])
+        .inc_by(stats.rx as u64);
+}
+
+type TopHosts = Box<dyn Fn() -> Vec<(String, TransferStats)> + Send + Sync>;
+
+static TOP_HOSTS: Mutex<Option<TopHosts>> = Mutex::new(None);
+
+/// Sets where the exported host table is read from on every scrape.
+pub fn set_top_hosts(hosts: impl Fn() -> Vec<(String, TransferStats)> + Send + Sync + 'static) {
+    *TOP_HOSTS.lock().unwrap() = Some(Box::new(hosts));
+}
+
+/// Replaces the exported host table, hosts that dropped out of it vanish.
+fn update_top_hosts() {
+    let hosts = match TOP_HOSTS.lock().unwrap().as_ref() {
+        Some(hosts) => hosts(),
+        None => return,
+    };
+    let top_host_bytes = &traffic().top_host_bytes;
+    top_host_bytes.reset();
+    for (host, stats) in hosts {
+        top_host_bytes
+            .with_label_values(&[&host, "upload"])
+            .set(stats.tx as i64);
+        top_host_bytes
+            .with_label_values(&[&host, "download"])
+            .set(stats.rx as i64);
+    }
+}
+
 #[instrument(name = "metrics_request", skip_all)]
 async fn serve_req(
     req: Request<hyper::body::Incoming>,
@@ -30,6 +99,7 @@ async fn serve_req(
 ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
     let response = match (req.method(), req.uri().path()) {
         (&Method::GET, "/metrics") => {
+            update_top_hosts();
             let encoder = TextEncoder::new();
             let metric_families = state.exporter.registry().gather();
             let mut result = Vec::new();
@@ -67,6 +137,9 @@ struct AppState {
 #[instrument(name = "prometheus_server", skip_all)]
 pub async fn prometheus_server(controller: BasicController) -> Result<(), anyhow::Error> {
     let exporter = opentelemetry_prometheus::exporter(controller).init();
+    let registry = exporter.registry();
+    registry.register(Box::new(traffic().rule_bytes.clone()))?;
+    registry.register(Box::new(traffic().top_host_bytes.clone()))?;
 
     let state = Arc::new(AppState { exporter });
 
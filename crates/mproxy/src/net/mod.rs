@@ -1,5 +1,5 @@
 pub mod protocol;
 pub mod transport;
 pub mod tool;
-// #[allow(dead_code)]
-// pub mod tun;
+#[allow(dead_code)]
+pub mod tun;
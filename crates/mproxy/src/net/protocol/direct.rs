@@ -1,30 +1,121 @@
-use tokio::net::TcpStream;
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use anyhow::Context;
+use tokio::net::{lookup_host, TcpSocket, TcpStream, UdpSocket};
 
 use crate::{
     config::egress::direct::ClientConfig,
-    proxy::{ProxyConn, ProxyRequest, ProxyResponse},
+    net::tool::mark::set_mark,
+    proxy::{NetLocation, ProxyConn, ProxyRequest, ProxyResponse, SendError, UdpForwarder},
     stats::{TransferMonitor, TransferStats},
 };
 
 #[derive(Debug)]
 pub struct Client {
     monitor: TransferMonitor,
+    udp_idle_timeout: Duration,
+    fwmark: Option<u32>,
 }
 
 impl Client {
-    pub async fn new(_: ClientConfig) -> Self {
+    pub async fn new(config: ClientConfig) -> Self {
         Self {
             monitor: TransferMonitor::new(),
+            udp_idle_timeout: config.udp_idle_timeout,
+            fwmark: config.fwmark,
         }
     }
 
-    pub async fn send(&self, req: ProxyRequest) -> Result<ProxyResponse, anyhow::Error> {
-        let remote = req.remote.to_string();
-        let s = TcpStream::connect(remote).await?;
-        let (upload_bytes, download_bytes) = match req.conn {
-            ProxyConn::ForwardTcp(conn) => conn.forward_with_monitor(s, &self.monitor).await?,
-            ProxyConn::ForwardHttp(conn) => conn.forward_with_monitor(s, &self.monitor).await?,
-            ProxyConn::ForwardUdp(_) => unimplemented!(),
+    async fn connect_tcp(&self, remote: &NetLocation) -> Result<TcpStream, anyhow::Error> {
+        let Some(mark) = self.fwmark else {
+            return Ok(TcpStream::connect(remote.to_string()).await?);
+        };
+
+        let mut last_error = None;
+        for endpoint in lookup_host(remote.to_string()).await? {
+            let socket = match endpoint {
+                SocketAddr::V4(_) => TcpSocket::new_v4()?,
+                SocketAddr::V6(_) => TcpSocket::new_v6()?,
+            };
+            set_mark(&socket, mark)?;
+            match socket.connect(endpoint).await {
+                Ok(s) => return Ok(s),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        match last_error {
+            Some(e) => Err(e.into()),
+            None => anyhow::bail!("Failed to resolve {}", remote),
+        }
+    }
+
+    async fn connect_udp(&self, remote: &NetLocation) -> Result<UdpSocket, anyhow::Error> {
+        let endpoint = lookup_host(remote.to_string())
+            .await?
+            .next()
+            .context(format!("Failed to resolve {}", remote))?;
+
+        let local = match endpoint {
+            SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        };
+
+        let s = UdpSocket::bind(local).await?;
+        if let Some(mark) = self.fwmark {
+            set_mark(&s, mark)?;
+        }
+        s.connect(endpoint).await?;
+        Ok(s)
+    }
+
+    async fn handle_forward_udp(
+        &self,
+        remote: NetLocation,
+        forward_conn: UdpForwarder,
+    ) -> Result<(u64, u64), anyhow::Error> {
+        let s = self.connect_udp(&remote).await?;
+        forward_conn
+            .forward_with_monitor(remote, s, self.udp_idle_timeout, &self.monitor)
+            .await
+    }
+
+    pub async fn send(&self, req: ProxyRequest) -> Result<ProxyResponse, SendError> {
+        let ProxyRequest {
+            remote,
+            source,
+            route,
+            conn,
+        } = req;
+        // the request is given back on connect errors, another egress may take it
+        let give_back = |conn| ProxyRequest {
+            remote: remote.clone(),
+            source,
+            route,
+            conn,
+        };
+        let (upload_bytes, download_bytes) = match conn {
+            ProxyConn::ForwardUdp(conn) => self.handle_forward_udp(remote, conn).await?,
+            ProxyConn::ForwardTcp(conn) => match self.connect_tcp(&remote).await {
+                Ok(s) => conn.forward_with_monitor(s, &self.monitor).await?,
+                Err(e) => {
+                    return Err(SendError::Connect(
+                        give_back(ProxyConn::ForwardTcp(conn)),
+                        e,
+                    ))
+                }
+            },
+            ProxyConn::ForwardHttp(conn) => match self.connect_tcp(&remote).await {
+                Ok(s) => conn.forward_with_monitor(s, &self.monitor).await?,
+                Err(e) => {
+                    return Err(SendError::Connect(
+                        give_back(ProxyConn::ForwardHttp(conn)),
+                        e,
+                    ))
+                }
+            },
         };
         Ok(ProxyResponse {
             upload_bytes,
@@ -1,4 +1,4 @@
-use std::{pin::Pin, str::FromStr, sync::Arc};
+use std::{net::SocketAddr, pin::Pin, str::FromStr, sync::Arc};
 
 use anyhow::{bail, Context as _};
 use futures::Future;
@@ -16,43 +16,82 @@ use tokio_stream::wrappers::UnboundedReceiverStream;
 use tracing::{debug, debug_span, error, instrument, warn, Instrument};
 
 use crate::{
-    config::{egress::http::ClientConfig, ingress::http::ServerConfig},
+    config::{
+        egress::http::ClientConfig,
+        ingress::http::{PacConfig, ServerConfig},
+    },
     io::BoxedAsyncIO,
     net::transport,
     proxy::{
-        Address, HttpForwarder, NetLocation, ProxyConn, ProxyRequest, ProxyResponse, TcpForwarder,
+        Address, Admission, HttpForwarder, NetLocation, PacSource, ProxyConn, ProxyRequest,
+        ProxyResponse, SendError, TcpForwarder,
     },
     stats::{TransferMonitor, TransferStats},
 };
 
+/// Where the PAC script is served and what renders it.
+#[derive(Debug)]
+pub(super) struct Pac {
+    config: PacConfig,
+    source: Arc<dyn PacSource>,
+}
+
 #[derive(Debug)]
 pub struct Server {
     acceptor: Arc<transport::Acceptor>,
+    admission: Arc<dyn Admission>,
+    pac: Option<Arc<Pac>>,
 }
 
 impl Server {
-    pub async fn new(config: ServerConfig) -> Result<Self, anyhow::Error> {
+    pub async fn new(
+        config: ServerConfig,
+        admission: Arc<dyn Admission>,
+        pac: Arc<dyn PacSource>,
+    ) -> Result<Self, anyhow::Error> {
         Ok(Self {
             acceptor: Arc::new(transport::Acceptor::new(config.acceptor).await?),
+            admission,
+            pac: config.pac.map(|config| {
+                Arc::new(Pac {
+                    config,
+                    source: pac,
+                })
+            }),
         })
     }
 
     async fn serve_inner(
         tx: mpsc::UnboundedSender<ProxyRequest>,
         stream: BoxedAsyncIO,
+        source: SocketAddr,
+        admission: Arc<dyn Admission>,
+        pac: Option<Arc<Pac>>,
     ) -> Result<(), anyhow::Error> {
+        let service = ServerService {
+            tx,
+            source,
+            admission,
+            pac,
+        };
         http1::Builder::new()
             .preserve_header_case(true)
             .title_case_headers(true)
-            .serve_connection(TokioIo::new(stream), ServerService { tx })
+            .serve_connection(TokioIo::new(stream), service)
             .with_upgrades()
             .await?;
         Ok(())
     }
 
     #[instrument(skip_all)]
-    async fn serve(tx: mpsc::UnboundedSender<ProxyRequest>, stream: BoxedAsyncIO) {
-        if let Err(e) = Self::serve_inner(tx, stream).await {
+    pub(super) async fn serve(
+        tx: mpsc::UnboundedSender<ProxyRequest>,
+        stream: BoxedAsyncIO,
+        source: SocketAddr,
+        admission: Arc<dyn Admission>,
+        pac: Option<Arc<Pac>>,
+    ) {
+        if let Err(e) = Self::serve_inner(tx, stream, source, admission, pac).await {
             warn!("{:?}", e);
         }
     }
@@ -60,20 +99,37 @@ impl Server {
     pub async fn incoming(&self) -> Result<UnboundedReceiverStream<ProxyRequest>, anyhow::Error> {
         let (tx, rx) = mpsc::unbounded_channel();
 
-        tokio::spawn(Self::run(self.acceptor.clone(), tx));
+        tokio::spawn(Self::run(
+            self.acceptor.clone(),
+            tx,
+            self.admission.clone(),
+            self.pac.clone(),
+        ));
 
         Ok(UnboundedReceiverStream::new(rx))
     }
 
-    async fn run(acceptor: Arc<transport::Acceptor>, tx: mpsc::UnboundedSender<ProxyRequest>) {
+    async fn run(
+        acceptor: Arc<transport::Acceptor>,
+        tx: mpsc::UnboundedSender<ProxyRequest>,
+        admission: Arc<dyn Admission>,
+        pac: Option<Arc<Pac>>,
+    ) {
         loop {
-            match acceptor.accept().await {
-                Ok(stream) => {
+            let accepted = tokio::select! {
+                // nobody takes requests anymore, the ingress has been dropped
+                _ = tx.closed() => break,
+                accepted = acceptor.accept() => accepted,
+            };
+            match accepted {
+                Ok((stream, peer)) => {
                     let tx = tx.clone();
                     let acceptor = acceptor.clone();
+                    let admission = admission.clone();
+                    let pac = pac.clone();
                     tokio::spawn(async move {
                         match acceptor.handshake(stream).await {
-                            Ok(io) => Self::serve(tx, io).await,
+                            Ok(io) => Self::serve(tx, io, peer, admission, pac).await,
                             Err(e) => warn!("{:?}", e),
                         }
                     });
@@ -90,12 +146,47 @@ impl Server {
 #[derive(Clone)]
 struct ServerService {
     tx: mpsc::UnboundedSender<ProxyRequest>,
+    source: SocketAddr,
+    admission: Arc<dyn Admission>,
+    pac: Option<Arc<Pac>>,
 }
 
 impl ServerService {
+    fn forbidden() -> Response<BoxBody<Bytes, hyper::Error>> {
+        let mut resp = Response::new(full("The request is not allowed"));
+        *resp.status_mut() = http::StatusCode::FORBIDDEN;
+        resp
+    }
+
     async fn handle_http(
-        _req: Request<body::Incoming>,
+        self,
+        req: Request<body::Incoming>,
     ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, anyhow::Error> {
+        if let Some(pac) = self
+            .pac
+            .as_ref()
+            .filter(|pac| req.method() == Method::GET && req.uri().path() == pac.config.path)
+        {
+            if !self.admission.permits(self.source) {
+                return Ok(Self::forbidden());
+            }
+            let proxy = match &pac.config.proxy {
+                Some(proxy) => proxy.clone(),
+                None => req
+                    .headers()
+                    .get(http::header::HOST)
+                    .context("host isn't exist")?
+                    .to_str()?
+                    .to_string(),
+            };
+            return Ok(Response::builder()
+                .header(
+                    http::header::CONTENT_TYPE,
+                    "application/x-ns-proxy-autoconfig",
+                )
+                .body(full(pac.source.pac(&proxy)))?);
+        }
+
         let mut resp = Response::new(full("The request is not supported"));
         *resp.status_mut() = http::StatusCode::BAD_REQUEST;
         Ok(resp)
@@ -106,10 +197,15 @@ impl ServerService {
         req: Request<body::Incoming>,
     ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, anyhow::Error> {
         let remote = host_addr(req.uri())?;
+        let Some(admitted) = self.admission.admit(self.source, &remote) else {
+            return Ok(Self::forbidden());
+        };
         let (tx, rx) = oneshot::channel();
         self.tx
             .send(ProxyRequest {
                 remote,
+                source: Some(self.source),
+                route: admitted.route,
                 conn: ProxyConn::ForwardHttp(HttpForwarder::new(req, tx)),
             })
             .map_err(|e| anyhow::anyhow!("{:?}", e))?;
@@ -122,6 +218,9 @@ impl ServerService {
         req: Request<body::Incoming>,
     ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, anyhow::Error> {
         let remote = host_addr(req.uri()).context("socket address is incorrect at CONNECT")?;
+        let Some(admitted) = self.admission.admit(self.source, &remote) else {
+            return Ok(Self::forbidden());
+        };
 
         tokio::task::spawn(
             async move {
@@ -129,6 +228,8 @@ impl ServerService {
                     Ok(upgraded) => {
                         if let Err(e) = self.tx.send(ProxyRequest {
                             remote,
+                            source: Some(self.source),
+                            route: admitted.route,
                             conn: ProxyConn::ForwardTcp(TcpForwarder {
                                 stream: Box::new(TokioIo::new(upgraded)),
                             }),
@@ -175,7 +276,7 @@ impl ServerService {
         } else if Self::is_http_proxy_request(&req) {
             self.handle_http_proxy(req).await
         } else {
-            Self::handle_http(req).await
+            self.handle_http(req).await
         }
     }
 }
@@ -274,17 +375,38 @@ impl Client {
         forward_conn.forward_with_monitor(s, &self.monitor).await
     }
 
-    pub async fn send(&self, req: ProxyRequest) -> Result<ProxyResponse, anyhow::Error> {
-        let s = self
-            .connector
-            .connect()
-            .await
-            .context("Failed to connect")?;
-
-        let (upload_bytes, download_bytes) = match req.conn {
-            ProxyConn::ForwardTcp(conn) => self.handle_forward_tcp(s, req.remote, conn).await?,
-            ProxyConn::ForwardHttp(conn) => self.handle_forward_http(s, conn).await?,
-            ProxyConn::ForwardUdp(_) => unreachable!(),
+    pub async fn send(&self, req: ProxyRequest) -> Result<ProxyResponse, SendError> {
+        let ProxyRequest {
+            remote,
+            source,
+            route,
+            conn,
+        } = req;
+        // the request is given back on connect errors, another egress may take it
+        let give_back = |conn| ProxyRequest {
+            remote: remote.clone(),
+            source,
+            route,
+            conn,
+        };
+        let (upload_bytes, download_bytes) = match conn {
+            ProxyConn::ForwardUdp(_) => {
+                return Err(anyhow::anyhow!("udp is not supported by http egress").into())
+            }
+            ProxyConn::ForwardTcp(conn) => match self.connector.connect().await {
+                Ok(s) => self.handle_forward_tcp(s, remote, conn).await?,
+                Err(e) => {
+                    let req = give_back(ProxyConn::ForwardTcp(conn));
+                    return Err(SendError::Connect(req, e.context("Failed to connect")));
+                }
+            },
+            ProxyConn::ForwardHttp(conn) => match self.connector.connect().await {
+                Ok(s) => self.handle_forward_http(s, conn).await?,
+                Err(e) => {
+                    let req = give_back(ProxyConn::ForwardHttp(conn));
+                    return Err(SendError::Connect(req, e.context("Failed to connect")));
+                }
+            },
         };
 
         Ok(ProxyResponse {
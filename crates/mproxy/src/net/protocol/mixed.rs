@@ -0,0 +1,187 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use tokio::{io::AsyncReadExt, sync::mpsc};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tracing::{instrument, warn};
+
+use crate::{
+    config::ingress::mixed::ServerConfig,
+    io::{BoxedAsyncIO, Rewind},
+    net::transport,
+    proxy::{Admission, ProxyRequest},
+};
+
+use super::{http, socks};
+
+const SOCKS4_VERSION: u8 = 0x04;
+const SOCKS5_VERSION: u8 = 0x05;
+
+#[derive(Debug)]
+pub struct Server {
+    acceptor: Arc<transport::Acceptor>,
+    socks: Arc<socks::ServerContext>,
+}
+
+impl Server {
+    pub async fn new(
+        config: ServerConfig,
+        admission: Arc<dyn Admission>,
+    ) -> Result<Self, anyhow::Error> {
+        Ok(Self {
+            socks: Arc::new(socks::ServerContext::new(
+                config.socks5,
+                &config.acceptor,
+                admission,
+            )),
+            acceptor: Arc::new(transport::Acceptor::new(config.acceptor).await?),
+        })
+    }
+
+    #[instrument(skip_all)]
+    async fn serve(
+        tx: mpsc::UnboundedSender<ProxyRequest>,
+        mut stream: BoxedAsyncIO,
+        socks: Arc<socks::ServerContext>,
+        source: SocketAddr,
+    ) -> Result<(), anyhow::Error> {
+        let mut first = [0u8; 1];
+        stream.read_exact(&mut first).await?;
+
+        let stream = Box::new(Rewind::new(stream, first.to_vec())) as BoxedAsyncIO;
+
+        match first[0] {
+            SOCKS4_VERSION | SOCKS5_VERSION => {
+                socks::Server::serve(tx, stream, socks, source).await
+            }
+            _ => http::Server::serve(tx, stream, source, socks.admission.clone(), None).await,
+        }
+
+        Ok(())
+    }
+
+    pub async fn incoming(&self) -> Result<UnboundedReceiverStream<ProxyRequest>, anyhow::Error> {
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(Self::run(self.acceptor.clone(), tx, self.socks.clone()));
+
+        Ok(UnboundedReceiverStream::new(rx))
+    }
+
+    async fn run(
+        acceptor: Arc<transport::Acceptor>,
+        tx: mpsc::UnboundedSender<ProxyRequest>,
+        socks: Arc<socks::ServerContext>,
+    ) {
+        loop {
+            let accepted = tokio::select! {
+                _ = tx.closed() => break,
+                accepted = acceptor.accept() => accepted,
+            };
+            match accepted {
+                Ok((stream, peer)) => {
+                    let tx = tx.clone();
+                    let acceptor = acceptor.clone();
+                    let socks = socks.clone();
+                    tokio::spawn(async move {
+                        let result = match acceptor.handshake(stream).await {
+                            Ok(io) => Self::serve(tx, io, socks, peer).await,
+                            Err(e) => Err(e),
+                        };
+                        if let Err(e) = result {
+                            warn!("{:?}", e);
+                        }
+                    });
+                }
+                Err(e) => {
+                    warn!("accept error: {:?}", e);
+                    break;
+                }
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use tokio::{
+        io::AsyncWriteExt,
+        net::{TcpListener, TcpStream},
+    };
+
+    use crate::{
+        config::{
+            ingress::socks::Socks5Config,
+            transport::{tcp, AcceptorConfig},
+        },
+        proxy::{Address, AdmitAll, NetLocation},
+    };
+
+    use super::*;
+
+    async fn serve_once() -> (TcpStream, mpsc::UnboundedReceiver<ProxyRequest>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        let socks = Arc::new(socks::ServerContext::new(
+            Socks5Config {
+                allow_udp: None,
+                auth: None,
+            },
+            &AcceptorConfig::Tcp(tcp::AcceptorConfig { listen: addr }),
+            Arc::new(AdmitAll),
+        ));
+
+        tokio::spawn(async move {
+            let (stream, peer) = listener.accept().await.unwrap();
+            Server::serve(tx, Box::new(stream), socks, peer).await
+        });
+
+        (TcpStream::connect(addr).await.unwrap(), rx)
+    }
+
+    fn remote() -> NetLocation {
+        NetLocation {
+            address: Address::Ip(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))),
+            port: 443,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_detect_socks() {
+        let (mut client, mut rx) = serve_once().await;
+
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut method = [0u8; 2];
+        client.read_exact(&mut method).await.unwrap();
+        assert_eq!(method, [0x05, 0x00]);
+
+        client
+            .write_all(&[0x05, 0x01, 0x00, 0x01, 10, 0, 0, 1, 0x01, 0xbb])
+            .await
+            .unwrap();
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..2], [0x05, 0x00]);
+
+        assert_eq!(rx.recv().await.unwrap().remote, remote());
+    }
+
+    #[tokio::test]
+    async fn test_detect_http() {
+        let (mut client, mut rx) = serve_once().await;
+
+        client
+            .write_all(b"CONNECT 10.0.0.1:443 HTTP/1.1\r\nHost: 10.0.0.1:443\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut status = [0u8; 12];
+        client.read_exact(&mut status).await.unwrap();
+        assert_eq!(&status, b"HTTP/1.1 200");
+
+        assert_eq!(rx.recv().await.unwrap().remote, remote());
+    }
+}
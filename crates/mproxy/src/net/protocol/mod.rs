@@ -1,28 +1,70 @@
 pub mod direct;
 pub mod http;
+pub mod mixed;
 pub mod socks;
 
+cfg_if::cfg_if! {
+    if #[cfg(target_os = "linux")] {
+        pub mod redirect;
+        pub mod tproxy;
+        pub mod tun;
+    }
+}
+
 use core::fmt;
+use std::sync::Arc;
 
 use futures::Stream;
 use tracing::instrument;
 
 use crate::{
     config::{egress::ClientConfig, ingress::ServerConfig},
-    proxy::{ProxyRequest, ProxyResponse},
+    proxy::{Admission, PacSource, ProxyRequest, ProxyResponse, SendError},
 };
 
 #[derive(Debug)]
 pub enum Server {
     Http(http::Server),
     Socks(socks::Server),
+    Mixed(mixed::Server),
+    #[cfg(target_os = "linux")]
+    Redirect(redirect::Server),
+    #[cfg(target_os = "linux")]
+    Tproxy(tproxy::Server),
+    #[cfg(target_os = "linux")]
+    Tun(tun::Server),
 }
 
 impl Server {
-    pub async fn new(config: ServerConfig) -> Result<Self, anyhow::Error> {
+    /// Transparent ingresses have nobody to answer, they ignore admission.
+    /// Only http ingresses serve `pac`.
+    pub async fn new(
+        config: ServerConfig,
+        admission: Arc<dyn Admission>,
+        pac: Arc<dyn PacSource>,
+    ) -> Result<Self, anyhow::Error> {
         Ok(match config {
-            ServerConfig::Http(config) => Self::Http(http::Server::new(config).await?),
-            ServerConfig::Socks(config) => Self::Socks(socks::Server::new(config).await?),
+            ServerConfig::Http(config) => {
+                Self::Http(http::Server::new(config, admission, pac).await?)
+            }
+            ServerConfig::Socks(config) => {
+                Self::Socks(socks::Server::new(config, admission).await?)
+            }
+            ServerConfig::Mixed(config) => {
+                Self::Mixed(mixed::Server::new(config, admission).await?)
+            }
+            #[cfg(target_os = "linux")]
+            ServerConfig::Redirect(config) => Self::Redirect(redirect::Server::new(config).await?),
+            #[cfg(target_os = "linux")]
+            ServerConfig::Tproxy(config) => Self::Tproxy(tproxy::Server::new(config).await?),
+            #[cfg(target_os = "linux")]
+            ServerConfig::Tun(config) => Self::Tun(tun::Server::new(config).await?),
+            #[cfg(not(target_os = "linux"))]
+            ServerConfig::Redirect(_) | ServerConfig::Tproxy(_) => {
+                anyhow::bail!("transparent proxy is only supported on linux")
+            }
+            #[cfg(not(target_os = "linux"))]
+            ServerConfig::Tun(_) => anyhow::bail!("tun is only supported on linux"),
         })
     }
 
@@ -33,6 +75,13 @@ impl Server {
         Ok(match &self {
             Server::Http(s) => Box::new(s.incoming().await?),
             Server::Socks(s) => Box::new(s.incoming().await?),
+            Server::Mixed(s) => Box::new(s.incoming().await?),
+            #[cfg(target_os = "linux")]
+            Server::Redirect(s) => Box::new(s.incoming().await?),
+            #[cfg(target_os = "linux")]
+            Server::Tproxy(s) => Box::new(s.incoming().await?),
+            #[cfg(target_os = "linux")]
+            Server::Tun(s) => Box::new(s.incoming().await?),
         })
     }
 }
@@ -41,6 +90,7 @@ impl Server {
 pub enum Client {
     Http(http::Client),
     Direct(direct::Client),
+    Socks(socks::Client),
 }
 
 impl fmt::Display for Client {
@@ -48,6 +98,7 @@ impl fmt::Display for Client {
         match self {
             Client::Http(_) => write!(f, "http"),
             Client::Direct(_) => write!(f, "direct"),
+            Client::Socks(_) => write!(f, "socks"),
         }
     }
 }
@@ -57,14 +108,17 @@ impl Client {
         Ok(match config {
             ClientConfig::Http(config) => Self::Http(http::Client::new(config).await?),
             ClientConfig::Direct(config) => Self::Direct(direct::Client::new(config).await),
+            ClientConfig::Socks(config) => Self::Socks(socks::Client::new(config).await?),
+            ClientConfig::Group(_) => anyhow::bail!("egress group isn't a client"),
         })
     }
 
     #[instrument(skip_all)]
-    pub async fn send(&self, req: ProxyRequest) -> Result<ProxyResponse, anyhow::Error> {
+    pub async fn send(&self, req: ProxyRequest) -> Result<ProxyResponse, SendError> {
         match &self {
             Client::Http(c) => c.send(req).await,
             Client::Direct(c) => c.send(req).await,
+            Client::Socks(c) => c.send(req).await,
         }
     }
 }
@@ -0,0 +1,176 @@
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    sync::Arc,
+};
+
+use nix::sys::socket::{getsockopt, sockopt};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tracing::{debug, info, warn};
+
+use crate::{
+    config::ingress::redirect::ServerConfig,
+    proxy::{ProxyConn, ProxyRequest, TcpForwarder},
+};
+
+pub type OriginalDstLookup = fn(&TcpStream) -> io::Result<SocketAddr>;
+
+pub struct Server {
+    listener: Arc<TcpListener>,
+    lookup: OriginalDstLookup,
+}
+
+impl std::fmt::Debug for Server {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Server")
+            .field("listener", &self.listener)
+            .finish()
+    }
+}
+
+impl Server {
+    pub async fn new(config: ServerConfig) -> Result<Self, anyhow::Error> {
+        Self::with_lookup(config, original_dst).await
+    }
+
+    async fn with_lookup(
+        config: ServerConfig,
+        lookup: OriginalDstLookup,
+    ) -> Result<Self, anyhow::Error> {
+        info!("Listening on {}", config.listen);
+        Ok(Self {
+            listener: Arc::new(TcpListener::bind(config.listen).await?),
+            lookup,
+        })
+    }
+
+    pub async fn incoming(&self) -> Result<UnboundedReceiverStream<ProxyRequest>, anyhow::Error> {
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(Self::run(self.listener.clone(), self.lookup, tx));
+
+        Ok(UnboundedReceiverStream::new(rx))
+    }
+
+    fn serve(
+        tx: &mpsc::UnboundedSender<ProxyRequest>,
+        stream: TcpStream,
+        lookup: OriginalDstLookup,
+    ) -> Result<(), anyhow::Error> {
+        let dst = lookup(&stream)?;
+
+        // connections that are not redirected would loop back to the listener
+        if dst == stream.local_addr()? {
+            anyhow::bail!("{} is not redirected", dst)
+        }
+
+        debug!("original destination is {}", dst);
+
+        tx.send(ProxyRequest {
+            remote: dst.into(),
+            source: stream.peer_addr().ok(),
+            route: None,
+            conn: ProxyConn::ForwardTcp(TcpForwarder {
+                stream: Box::new(stream),
+            }),
+        })
+        .map_err(|e| anyhow::anyhow!("send error: {:?}", e.0))
+    }
+
+    async fn run(
+        listener: Arc<TcpListener>,
+        lookup: OriginalDstLookup,
+        tx: mpsc::UnboundedSender<ProxyRequest>,
+    ) {
+        loop {
+            let accepted = tokio::select! {
+                _ = tx.closed() => break,
+                accepted = listener.accept() => accepted,
+            };
+            match accepted {
+                Ok((stream, _)) => {
+                    if let Err(e) = Self::serve(&tx, stream, lookup) {
+                        warn!("{:?}", e);
+                    }
+                }
+                Err(e) => {
+                    warn!("tcp accept error: {:?}", e);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Gets the destination of a connection redirected by netfilter `REDIRECT` or `DNAT`
+pub fn original_dst(stream: &TcpStream) -> io::Result<SocketAddr> {
+    match stream.local_addr()? {
+        SocketAddr::V4(_) => {
+            let addr = getsockopt(stream, sockopt::OriginalDst)?;
+            Ok(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+                u16::from_be(addr.sin_port),
+            )))
+        }
+        SocketAddr::V6(_) => {
+            let addr = getsockopt(stream, sockopt::Ip6tOriginalDst)?;
+            Ok(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                u16::from_be(addr.sin6_port),
+                addr.sin6_flowinfo,
+                addr.sin6_scope_id,
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mock_original_dst(_: &TcpStream) -> io::Result<SocketAddr> {
+        Ok(SocketAddr::from(([10, 0, 0, 1], 443)))
+    }
+
+    #[tokio::test]
+    async fn test_redirect() {
+        let server = Server::with_lookup(
+            ServerConfig {
+                listen: SocketAddr::from(([127, 0, 0, 1], 0)),
+            },
+            mock_original_dst,
+        )
+        .await
+        .unwrap();
+        let addr = server.listener.local_addr().unwrap();
+
+        let mut incoming = server.incoming().await.unwrap().into_inner();
+        let _client = TcpStream::connect(addr).await.unwrap();
+
+        let req = incoming.recv().await.unwrap();
+        assert_eq!(req.remote, SocketAddr::from(([10, 0, 0, 1], 443)).into());
+    }
+
+    #[tokio::test]
+    async fn test_not_redirected() {
+        let server = Server::new(ServerConfig {
+            listen: SocketAddr::from(([127, 0, 0, 1], 0)),
+        })
+        .await
+        .unwrap();
+        let addr = server.listener.local_addr().unwrap();
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let client = tokio::spawn(TcpStream::connect(addr));
+        let (stream, _) = server.listener.accept().await.unwrap();
+        let _client = client.await.unwrap().unwrap();
+
+        assert!(Server::serve(&tx, stream, |s| s.local_addr()).is_err());
+        drop(tx);
+        assert!(rx.recv().await.is_none());
+    }
+}
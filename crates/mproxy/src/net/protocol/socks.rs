@@ -1,90 +1,320 @@
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
 
+use anyhow::Context;
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use socksv5::{
     v4::SocksV4Command,
     v5::{SocksV5AuthMethod, SocksV5Command, SocksV5RequestStatus},
     SocksVersion,
 };
-use tokio::sync::mpsc;
+use tokio::{
+    io::AsyncReadExt as _,
+    net::{lookup_host, UdpSocket},
+    sync::mpsc::{self, error::TrySendError},
+};
 use tokio_stream::wrappers::UnboundedReceiverStream;
 use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
-use tracing::{instrument, warn};
+use tracing::{debug, instrument, warn};
 
 use crate::{
-    config::ingress::socks::{ServerConfig, Socks5Config},
-    io::BoxedAsyncIO,
+    config::{
+        egress::socks::ClientConfig,
+        ingress::socks::{AuthType, ServerConfig, Socks5Config},
+        transport::AcceptorConfig,
+    },
+    io::{BoxedAsyncIO, PacketIO},
     net::transport,
-    proxy::{Address, NetLocation, ProxyConn, ProxyRequest, TcpForwarder},
+    proxy::{
+        Address, Admission, HttpForwarder, NetLocation, ProxyConn, ProxyRequest, ProxyResponse,
+        SendError, TcpForwarder, UdpForwarder, UdpPacket,
+    },
+    stats::{TransferMonitor, TransferStats},
 };
 
+const MAX_DATAGRAM_SIZE: usize = 65535;
+const UDP_CHANNEL_SIZE: usize = 64;
+
+#[derive(Debug)]
+pub(super) struct ServerContext {
+    config: Socks5Config,
+    udp_bind: IpAddr,
+    pub(super) admission: Arc<dyn Admission>,
+}
+
+impl ServerContext {
+    pub(super) fn new(
+        config: Socks5Config,
+        acceptor: &AcceptorConfig,
+        admission: Arc<dyn Admission>,
+    ) -> Self {
+        Self {
+            config,
+            udp_bind: acceptor.listen().ip(),
+            admission,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Server {
     acceptor: Arc<transport::Acceptor>,
-    config: Arc<Socks5Config>,
+    context: Arc<ServerContext>,
 }
 
 impl Server {
-    pub async fn new(config: ServerConfig) -> Result<Self, anyhow::Error> {
+    pub async fn new(
+        config: ServerConfig,
+        admission: Arc<dyn Admission>,
+    ) -> Result<Self, anyhow::Error> {
         Ok(Self {
+            context: Arc::new(ServerContext::new(
+                config.socks5,
+                &config.acceptor,
+                admission,
+            )),
             acceptor: Arc::new(transport::Acceptor::new(config.acceptor).await?),
-            config: Arc::new(config.socks5),
         })
     }
 
+    async fn authenticate<S>(stream: &mut S, config: &Socks5Config) -> Result<(), anyhow::Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let handshake = socksv5::v5::read_handshake_skip_version(&mut *stream).await?;
+
+        let method = match &config.auth {
+            Some(AuthType::Simple { .. }) => SocksV5AuthMethod::UsernamePassword,
+            None => SocksV5AuthMethod::Noauth,
+        };
+
+        if !handshake.methods.contains(&method) {
+            socksv5::v5::write_auth_method(&mut *stream, SocksV5AuthMethod::NoAcceptableMethod)
+                .await?;
+            anyhow::bail!(
+                "no acceptable auth method, expect {:?}, offered {:?}",
+                method,
+                handshake.methods
+            )
+        }
+
+        socksv5::v5::write_auth_method(&mut *stream, method).await?;
+
+        match &config.auth {
+            Some(AuthType::Simple { user, password }) => {
+                let (req_user, req_password) = read_password_auth(&mut *stream).await?;
+
+                let granted = &req_user == user && &req_password == password;
+                write_password_auth_status(&mut *stream, granted).await?;
+
+                if !granted {
+                    anyhow::bail!("user {} authentication failed", req_user)
+                }
+            }
+            None => {}
+        }
+
+        Ok(())
+    }
+
     async fn serve_socksv5(
         tx: mpsc::UnboundedSender<ProxyRequest>,
         mut stream: Compat<BoxedAsyncIO>,
+        context: Arc<ServerContext>,
+        source: SocketAddr,
     ) -> Result<(), anyhow::Error> {
-        let _methods = socksv5::v5::read_handshake_skip_version(&mut stream).await?;
-
-        // if let Some(_auth) = &config.auth {
-        //     anyhow::bail!("auth is not supported {:?}", methods)
-        // }
-
-        socksv5::v5::write_auth_method(&mut stream, SocksV5AuthMethod::Noauth).await?;
+        Self::authenticate(&mut stream, &context.config).await?;
 
         let request = socksv5::v5::read_request(&mut stream).await?;
 
         let request = match request.command {
             SocksV5Command::Connect | SocksV5Command::Bind => {
+                let remote = NetLocation {
+                    address: Address::try_from(request.host)?,
+                    port: request.port,
+                };
+                let admitted = context.admission.admit(source, &remote);
                 socksv5::v5::write_request_status(
                     &mut stream,
-                    SocksV5RequestStatus::Success,
+                    if admitted.is_some() {
+                        SocksV5RequestStatus::Success
+                    } else {
+                        SocksV5RequestStatus::ConnectionNotAllowed
+                    },
                     socksv5::v5::SocksV5Host::Ipv4([0, 0, 0, 0]),
                     0,
                 )
                 .await?;
+                let Some(admitted) = admitted else {
+                    anyhow::bail!("{} is not allowed", remote)
+                };
 
                 ProxyRequest {
-                    remote: NetLocation {
-                        address: Address::try_from(request.host)?,
-                        port: request.port,
-                    },
+                    remote,
+                    source: Some(source),
+                    route: admitted.route,
                     conn: ProxyConn::ForwardTcp(TcpForwarder {
                         stream: stream.into_inner(),
                     }),
                 }
             }
             SocksV5Command::UdpAssociate => {
+                if !context.config.allow_udp.unwrap_or(false) {
+                    socksv5::v5::write_request_status(
+                        &mut stream,
+                        SocksV5RequestStatus::CommandNotSupported,
+                        socksv5::v5::SocksV5Host::Ipv4([0, 0, 0, 0]),
+                        0,
+                    )
+                    .await?;
+                    anyhow::bail!("{:?} is not allowed", request)
+                }
+
+                let socket = UdpSocket::bind(SocketAddr::new(context.udp_bind, 0)).await?;
+                let local = socket.local_addr()?;
+
                 socksv5::v5::write_request_status(
                     &mut stream,
-                    SocksV5RequestStatus::CommandNotSupported,
-                    socksv5::v5::SocksV5Host::Ipv4([0, 0, 0, 0]),
-                    0,
+                    SocksV5RequestStatus::Success,
+                    match local.ip() {
+                        IpAddr::V4(ip) => socksv5::v5::SocksV5Host::Ipv4(ip.octets()),
+                        IpAddr::V6(ip) => socksv5::v5::SocksV5Host::Ipv6(ip.octets()),
+                    },
+                    local.port(),
                 )
                 .await?;
-                anyhow::bail!("{:?} is not supported", request)
+
+                debug!("udp relay is listening on {}", local);
+
+                // only the client of the control connection may use the relay,
+                // from the port it gave if any
+                let client = (
+                    source.ip().to_canonical(),
+                    (request.port != 0).then_some(request.port),
+                );
+                return Self::serve_udp_associate(tx, socket, stream.into_inner(), client).await;
             }
         };
         tx.send(request)
             .map_err(|e| anyhow::anyhow!("send error: {:?}", e.0))
     }
 
+    async fn serve_udp_associate(
+        tx: mpsc::UnboundedSender<ProxyRequest>,
+        socket: UdpSocket,
+        mut control: BoxedAsyncIO,
+        (client_ip, client_port): (IpAddr, Option<u16>),
+    ) -> Result<(), anyhow::Error> {
+        let (reply_tx, mut reply_rx) = mpsc::channel(UDP_CHANNEL_SIZE);
+        let mut sessions = HashMap::new();
+        let mut client = None;
+
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        let mut control_buf = [0u8; 1];
+
+        loop {
+            tokio::select! {
+                r = socket.recv_from(&mut buf) => {
+                    let (n, from) = r?;
+                    if from.ip().to_canonical() != client_ip || client_port.is_some_and(|port| port != from.port()) {
+                        debug!("drop udp packet from {}, it's not the client", from);
+                        continue;
+                    }
+                    match client {
+                        Some(client) if client != from => {
+                            debug!("drop udp packet from unassociated {}", from);
+                            continue;
+                        }
+                        _ => client = Some(from),
+                    }
+
+                    match decode_udp_packet(&buf[..n]) {
+                        Ok(packet) => {
+                            Self::dispatch_udp_packet(&tx, &reply_tx, &mut sessions, from, packet)?
+                        }
+                        Err(e) => warn!("invalid udp packet: {:?}", e),
+                    }
+                }
+                packet = reply_rx.recv() => {
+                    if let (Some(packet), Some(client)) = (packet, client) {
+                        socket.send_to(&encode_udp_packet(&packet)?, client).await?;
+                    }
+                }
+                r = control.read(&mut control_buf) => {
+                    // the association terminates when the control connection is closed
+                    match r {
+                        Ok(0) | Err(_) => break,
+                        Ok(_) => {}
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn dispatch_udp_packet(
+        tx: &mpsc::UnboundedSender<ProxyRequest>,
+        reply_tx: &mpsc::Sender<UdpPacket>,
+        sessions: &mut HashMap<NetLocation, mpsc::Sender<UdpPacket>>,
+        source: SocketAddr,
+        mut packet: UdpPacket,
+    ) -> Result<(), anyhow::Error> {
+        if let Some(sender) = sessions.get(&packet.remote) {
+            match sender.try_send(packet) {
+                Ok(_) => return Ok(()),
+                Err(TrySendError::Full(packet)) => {
+                    debug!("drop udp packet to {}, session is busy", packet.remote);
+                    return Ok(());
+                }
+                Err(TrySendError::Closed(v)) => packet = v,
+            }
+        }
+
+        sessions.retain(|_, sender| !sender.is_closed());
+
+        let remote = packet.remote.clone();
+        let (sender, receiver) = mpsc::channel(UDP_CHANNEL_SIZE);
+        sender
+            .try_send(packet)
+            .map_err(|e| anyhow::anyhow!("send error: {:?}", e))?;
+        sessions.insert(remote.clone(), sender);
+
+        tx.send(ProxyRequest {
+            remote,
+            source: Some(source),
+            route: None,
+            conn: ProxyConn::ForwardUdp(UdpForwarder::new(receiver, reply_tx.clone())),
+        })
+        .map_err(|e| anyhow::anyhow!("send error: {:?}", e.0))
+    }
+
     async fn serve_socksv4(
         tx: mpsc::UnboundedSender<ProxyRequest>,
         mut stream: Compat<BoxedAsyncIO>,
+        context: Arc<ServerContext>,
+        source: SocketAddr,
     ) -> Result<(), anyhow::Error> {
         let request = socksv5::v4::read_request_skip_version(&mut stream).await?;
+        let remote = NetLocation {
+            address: Address::try_from(request.host)?,
+            port: request.port,
+        };
+        let Some(admitted) = context.admission.admit(source, &remote) else {
+            socksv5::v4::write_request_status(
+                &mut stream,
+                socksv5::v4::SocksV4RequestStatus::Failed,
+                [0, 0, 0, 0],
+                0,
+            )
+            .await?;
+            anyhow::bail!("{} is not allowed", remote)
+        };
         match request.command {
             SocksV4Command::Connect | SocksV4Command::Bind => {
                 socksv5::v4::write_request_status(
@@ -98,10 +328,9 @@ impl Server {
         }
 
         tx.send(ProxyRequest {
-            remote: NetLocation {
-                address: Address::try_from(request.host)?,
-                port: request.port,
-            },
+            remote,
+            source: Some(source),
+            route: admitted.route,
             conn: ProxyConn::ForwardTcp(TcpForwarder {
                 stream: stream.into_inner(),
             }),
@@ -112,23 +341,30 @@ impl Server {
     async fn serve_inner(
         tx: mpsc::UnboundedSender<ProxyRequest>,
         stream: BoxedAsyncIO,
-        _config: Arc<Socks5Config>,
+        context: Arc<ServerContext>,
+        source: SocketAddr,
     ) -> Result<(), anyhow::Error> {
         let mut stream = stream.compat();
 
         match socksv5::read_version(&mut stream).await? {
-            SocksVersion::V4 => Self::serve_socksv4(tx, stream).await,
-            SocksVersion::V5 => Self::serve_socksv5(tx, stream).await,
+            SocksVersion::V4 => {
+                if context.config.auth.is_some() {
+                    anyhow::bail!("socks4 is rejected when authentication is required")
+                }
+                Self::serve_socksv4(tx, stream, context, source).await
+            }
+            SocksVersion::V5 => Self::serve_socksv5(tx, stream, context, source).await,
         }
     }
 
     #[instrument(skip_all)]
-    async fn serve(
+    pub(super) async fn serve(
         tx: mpsc::UnboundedSender<ProxyRequest>,
         stream: BoxedAsyncIO,
-        config: Arc<Socks5Config>,
+        context: Arc<ServerContext>,
+        source: SocketAddr,
     ) {
-        if let Err(e) = Self::serve_inner(tx, stream, config).await {
+        if let Err(e) = Self::serve_inner(tx, stream, context, source).await {
             warn!("{:?}", e);
         }
     }
@@ -136,7 +372,7 @@ impl Server {
     pub async fn incoming(&self) -> Result<UnboundedReceiverStream<ProxyRequest>, anyhow::Error> {
         let (tx, rx) = mpsc::unbounded_channel();
 
-        tokio::spawn(Self::run(self.acceptor.clone(), tx, self.config.clone()));
+        tokio::spawn(Self::run(self.acceptor.clone(), tx, self.context.clone()));
 
         Ok(UnboundedReceiverStream::new(rx))
     }
@@ -144,11 +380,17 @@ impl Server {
     async fn run(
         acceptor: Arc<transport::Acceptor>,
         tx: mpsc::UnboundedSender<ProxyRequest>,
-        config: Arc<Socks5Config>,
+        context: Arc<ServerContext>,
     ) {
         loop {
-            match acceptor.accept().await {
-                Ok(stream) => tokio::spawn(Self::serve(tx.clone(), stream, config.clone())),
+            let accepted = tokio::select! {
+                _ = tx.closed() => break,
+                accepted = acceptor.accept() => accepted,
+            };
+            match accepted {
+                Ok((stream, peer)) => {
+                    tokio::spawn(Self::serve(tx.clone(), stream, context.clone(), peer))
+                }
                 Err(e) => {
                     warn!("{:?}", e);
                     break;
@@ -157,3 +399,841 @@ impl Server {
         }
     }
 }
+
+#[derive(Debug)]
+pub struct Client {
+    connector: transport::Connector,
+    server_address: String,
+    auth: Option<AuthType>,
+    udp_idle_timeout: Duration,
+    monitor: TransferMonitor,
+}
+
+impl Client {
+    pub async fn new(config: ClientConfig) -> Result<Self, anyhow::Error> {
+        Ok(Self {
+            server_address: config.connector.inner.endpoint().address().to_string(),
+            connector: transport::Connector::new(config.connector).await?,
+            auth: config.auth,
+            udp_idle_timeout: config.udp_idle_timeout,
+            monitor: TransferMonitor::new(),
+        })
+    }
+
+    async fn handshake<S>(&self, stream: &mut S) -> Result<(), anyhow::Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let method = match &self.auth {
+            Some(AuthType::Simple { .. }) => METHOD_USERNAME_PASSWORD,
+            None => METHOD_NOAUTH,
+        };
+
+        stream.write_all(&[SOCKS5_VERSION, 1, method]).await?;
+        stream.flush().await?;
+
+        let mut reply = [0u8; 2];
+        stream.read_exact(&mut reply).await?;
+        if reply[0] != SOCKS5_VERSION {
+            anyhow::bail!("unsupported socks version {}", reply[0])
+        }
+        if reply[1] != method {
+            anyhow::bail!("auth method {} is not accepted by server", method)
+        }
+
+        if let Some(AuthType::Simple { user, password }) = &self.auth {
+            write_password_auth(&mut *stream, user, password).await?;
+            if !read_password_auth_status(&mut *stream).await? {
+                anyhow::bail!("user {} authentication failed", user)
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn request<S>(
+        stream: &mut S,
+        command: u8,
+        remote: &NetLocation,
+    ) -> Result<NetLocation, anyhow::Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut buf = vec![SOCKS5_VERSION, command, 0x00];
+        encode_address(&mut buf, remote)?;
+        stream.write_all(&buf).await?;
+        stream.flush().await?;
+
+        let mut reply = [0u8; 3];
+        stream.read_exact(&mut reply).await?;
+        if reply[0] != SOCKS5_VERSION {
+            anyhow::bail!("unsupported socks version {}", reply[0])
+        }
+        if reply[1] != REPLY_SUCCEEDED {
+            anyhow::bail!("socks request to {} failed with reply {}", remote, reply[1])
+        }
+
+        read_address(&mut *stream).await
+    }
+
+    async fn connect(&self) -> Result<Compat<BoxedAsyncIO>, anyhow::Error> {
+        let mut stream = self
+            .connector
+            .connect()
+            .await
+            .context("Failed to connect")?
+            .compat();
+        self.handshake(&mut stream).await?;
+        Ok(stream)
+    }
+
+    async fn handle_forward_udp(
+        &self,
+        remote: NetLocation,
+        forward_conn: UdpForwarder,
+    ) -> Result<(u64, u64), anyhow::Error> {
+        let mut control = self.connect().await?;
+
+        let unspecified = NetLocation {
+            address: Address::Ip(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            port: 0,
+        };
+        let mut relay = Self::request(&mut control, CMD_UDP_ASSOCIATE, &unspecified).await?;
+
+        if let Address::Ip(ip) = &relay.address {
+            if ip.is_unspecified() {
+                relay.address = Address::Hostname(self.server_address.clone());
+            }
+        }
+
+        let relay = lookup_host(relay.to_string())
+            .await?
+            .next()
+            .context(format!("Failed to resolve {}", relay))?;
+
+        let socket = UdpSocket::bind(match relay {
+            SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        })
+        .await?;
+        socket.connect(relay).await?;
+
+        // the association is kept until the control connection is closed
+        let result = forward_conn
+            .forward_with_monitor(
+                remote.clone(),
+                UdpRelay { socket, remote },
+                self.udp_idle_timeout,
+                &self.monitor,
+            )
+            .await;
+
+        drop(control);
+        result
+    }
+
+    pub async fn send(&self, req: ProxyRequest) -> Result<ProxyResponse, SendError> {
+        let ProxyRequest {
+            remote,
+            source,
+            route,
+            conn,
+        } = req;
+        // the request is given back on connect errors, another egress may take it
+        let give_back = |conn| ProxyRequest {
+            remote: remote.clone(),
+            source,
+            route,
+            conn,
+        };
+        let (upload_bytes, download_bytes) = match conn {
+            ProxyConn::ForwardUdp(conn) => self.handle_forward_udp(remote, conn).await?,
+            ProxyConn::ForwardTcp(conn) => match self.connect_remote(&remote).await {
+                Ok(s) => {
+                    conn.forward_with_monitor(s.into_inner(), &self.monitor)
+                        .await?
+                }
+                Err(e) => {
+                    return Err(SendError::Connect(
+                        give_back(ProxyConn::ForwardTcp(conn)),
+                        e,
+                    ))
+                }
+            },
+            ProxyConn::ForwardHttp(conn) => match self.connect_remote(&remote).await {
+                Ok(s) => self.handle_forward_http(s.into_inner(), conn).await?,
+                Err(e) => {
+                    return Err(SendError::Connect(
+                        give_back(ProxyConn::ForwardHttp(conn)),
+                        e,
+                    ))
+                }
+            },
+        };
+
+        Ok(ProxyResponse {
+            upload_bytes,
+            download_bytes,
+        })
+    }
+
+    async fn connect_remote(
+        &self,
+        remote: &NetLocation,
+    ) -> Result<Compat<BoxedAsyncIO>, anyhow::Error> {
+        let mut s = self.connect().await?;
+        Self::request(&mut s, CMD_CONNECT, remote).await?;
+        Ok(s)
+    }
+
+    async fn handle_forward_http(
+        &self,
+        s: BoxedAsyncIO,
+        forward_conn: HttpForwarder,
+    ) -> Result<(u64, u64), anyhow::Error> {
+        forward_conn.forward_with_monitor(s, &self.monitor).await
+    }
+
+    pub async fn get_transfer_stats(&self) -> Result<TransferStats, anyhow::Error> {
+        self.monitor.get_transfer_stats().await
+    }
+}
+
+struct UdpRelay {
+    socket: UdpSocket,
+    remote: NetLocation,
+}
+
+impl PacketIO for UdpRelay {
+    async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        let packet = encode_udp_packet(&UdpPacket {
+            remote: self.remote.clone(),
+            data: buf.to_vec(),
+        })
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+        self.socket.send(&packet).await?;
+        Ok(buf.len())
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut packet = vec![0u8; MAX_DATAGRAM_SIZE];
+        loop {
+            let n = self.socket.recv(&mut packet).await?;
+            match decode_udp_packet(&packet[..n]) {
+                Ok(packet) => {
+                    let n = packet.data.len().min(buf.len());
+                    buf[..n].copy_from_slice(&packet.data[..n]);
+                    return Ok(n);
+                }
+                Err(e) => warn!("invalid udp packet from relay: {:?}", e),
+            }
+        }
+    }
+}
+
+const SOCKS5_VERSION: u8 = 0x05;
+const METHOD_NOAUTH: u8 = 0x00;
+const METHOD_USERNAME_PASSWORD: u8 = 0x02;
+const CMD_CONNECT: u8 = 0x01;
+const CMD_UDP_ASSOCIATE: u8 = 0x03;
+const REPLY_SUCCEEDED: u8 = 0x00;
+
+// RFC 1928 udp request header
+//
+// +----+------+------+----------+----------+----------+
+// |RSV | FRAG | ATYP | DST.ADDR | DST.PORT |   DATA   |
+// +----+------+------+----------+----------+----------+
+// | 2  |  1   |  1   | Variable |    2     | Variable |
+// +----+------+------+----------+----------+----------+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+fn decode_udp_packet(buf: &[u8]) -> Result<UdpPacket, anyhow::Error> {
+    let header = buf.get(..4).context("udp header is too short")?;
+    if header[2] != 0 {
+        anyhow::bail!("udp fragment is not supported")
+    }
+
+    let (address, rest) = match header[3] {
+        ATYP_IPV4 => {
+            let ip: [u8; 4] = buf.get(4..8).context("ipv4 is too short")?.try_into()?;
+            (Address::Ip(IpAddr::V4(Ipv4Addr::from(ip))), &buf[8..])
+        }
+        ATYP_IPV6 => {
+            let ip: [u8; 16] = buf.get(4..20).context("ipv6 is too short")?.try_into()?;
+            (Address::Ip(IpAddr::V6(Ipv6Addr::from(ip))), &buf[20..])
+        }
+        ATYP_DOMAIN => {
+            let len = *buf.get(4).context("domain is too short")? as usize;
+            let domain = buf.get(5..5 + len).context("domain is too short")?;
+            (
+                Address::Hostname(String::from_utf8(domain.to_vec())?),
+                &buf[5 + len..],
+            )
+        }
+        atyp => anyhow::bail!("address type {} is not supported", atyp),
+    };
+
+    let port = rest.get(..2).context("port is too short")?;
+
+    Ok(UdpPacket {
+        remote: NetLocation {
+            address,
+            port: u16::from_be_bytes([port[0], port[1]]),
+        },
+        data: rest[2..].to_vec(),
+    })
+}
+
+fn encode_udp_packet(packet: &UdpPacket) -> Result<Vec<u8>, anyhow::Error> {
+    let mut buf = vec![0, 0, 0];
+    encode_address(&mut buf, &packet.remote)?;
+    buf.extend_from_slice(&packet.data);
+    Ok(buf)
+}
+
+fn encode_address(buf: &mut Vec<u8>, location: &NetLocation) -> Result<(), anyhow::Error> {
+    match &location.address {
+        Address::Ip(IpAddr::V4(ip)) => {
+            buf.push(ATYP_IPV4);
+            buf.extend_from_slice(&ip.octets());
+        }
+        Address::Ip(IpAddr::V6(ip)) => {
+            buf.push(ATYP_IPV6);
+            buf.extend_from_slice(&ip.octets());
+        }
+        Address::Hostname(hostname) => {
+            buf.push(ATYP_DOMAIN);
+            buf.push(u8::try_from(hostname.len()).context("hostname is too long")?);
+            buf.extend_from_slice(hostname.as_bytes());
+        }
+    }
+
+    buf.extend_from_slice(&location.port.to_be_bytes());
+    Ok(())
+}
+
+async fn read_address<S>(stream: &mut S) -> Result<NetLocation, anyhow::Error>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut atyp = [0u8; 1];
+    stream.read_exact(&mut atyp).await?;
+
+    let address = match atyp[0] {
+        ATYP_IPV4 => {
+            let mut ip = [0u8; 4];
+            stream.read_exact(&mut ip).await?;
+            Address::Ip(IpAddr::V4(Ipv4Addr::from(ip)))
+        }
+        ATYP_IPV6 => {
+            let mut ip = [0u8; 16];
+            stream.read_exact(&mut ip).await?;
+            Address::Ip(IpAddr::V6(Ipv6Addr::from(ip)))
+        }
+        ATYP_DOMAIN => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len).await?;
+            let mut domain = vec![0u8; len[0] as usize];
+            stream.read_exact(&mut domain).await?;
+            Address::Hostname(String::from_utf8(domain)?)
+        }
+        atyp => anyhow::bail!("address type {} is not supported", atyp),
+    };
+
+    let mut port = [0u8; 2];
+    stream.read_exact(&mut port).await?;
+
+    Ok(NetLocation {
+        address,
+        port: u16::from_be_bytes(port),
+    })
+}
+
+// RFC 1929 username/password sub-negotiation
+const PASSWORD_AUTH_VERSION: u8 = 0x01;
+
+async fn read_password_auth<S>(stream: &mut S) -> Result<(String, String), anyhow::Error>
+where
+    S: AsyncRead + Unpin,
+{
+    async fn read_field<S>(stream: &mut S) -> Result<String, anyhow::Error>
+    where
+        S: AsyncRead + Unpin,
+    {
+        let mut len = [0u8; 1];
+        stream.read_exact(&mut len).await?;
+        let mut buf = vec![0u8; len[0] as usize];
+        stream.read_exact(&mut buf).await?;
+        String::from_utf8(buf).context("auth field is not utf8")
+    }
+
+    let mut version = [0u8; 1];
+    stream.read_exact(&mut version).await?;
+    if version[0] != PASSWORD_AUTH_VERSION {
+        anyhow::bail!("unsupported auth version {}", version[0])
+    }
+
+    Ok((read_field(stream).await?, read_field(stream).await?))
+}
+
+async fn write_password_auth<S>(
+    stream: &mut S,
+    user: &str,
+    password: &str,
+) -> Result<(), anyhow::Error>
+where
+    S: AsyncWrite + Unpin,
+{
+    let mut buf = vec![PASSWORD_AUTH_VERSION];
+    for field in [user, password] {
+        buf.push(u8::try_from(field.len()).context("auth field is too long")?);
+        buf.extend_from_slice(field.as_bytes());
+    }
+    stream.write_all(&buf).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+async fn read_password_auth_status<S>(stream: &mut S) -> Result<bool, anyhow::Error>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut status = [0u8; 2];
+    stream.read_exact(&mut status).await?;
+    if status[0] != PASSWORD_AUTH_VERSION {
+        anyhow::bail!("unsupported auth version {}", status[0])
+    }
+    Ok(status[1] == 0x00)
+}
+
+async fn write_password_auth_status<S>(stream: &mut S, granted: bool) -> Result<(), anyhow::Error>
+where
+    S: AsyncWrite + Unpin,
+{
+    stream
+        .write_all(&[PASSWORD_AUTH_VERSION, if granted { 0x00 } else { 0x01 }])
+        .await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use crate::proxy::{AdmitAll, Admitted};
+
+    use super::*;
+
+    async fn serve_once(
+        auth: Option<AuthType>,
+    ) -> (
+        TcpStream,
+        mpsc::UnboundedReceiver<ProxyRequest>,
+        tokio::task::JoinHandle<Result<(), anyhow::Error>>,
+    ) {
+        serve_once_with_config(Socks5Config {
+            allow_udp: None,
+            auth,
+        })
+        .await
+    }
+
+    async fn serve_once_with_config(
+        config: Socks5Config,
+    ) -> (
+        TcpStream,
+        mpsc::UnboundedReceiver<ProxyRequest>,
+        tokio::task::JoinHandle<Result<(), anyhow::Error>>,
+    ) {
+        serve_once_with_admission(config, Arc::new(AdmitAll)).await
+    }
+
+    async fn serve_once_with_admission(
+        config: Socks5Config,
+        admission: Arc<dyn Admission>,
+    ) -> (
+        TcpStream,
+        mpsc::UnboundedReceiver<ProxyRequest>,
+        tokio::task::JoinHandle<Result<(), anyhow::Error>>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        let context = Arc::new(ServerContext {
+            config,
+            udp_bind: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            admission,
+        });
+
+        let handle = tokio::spawn(async move {
+            let (stream, peer) = listener.accept().await.unwrap();
+            Server::serve_inner(tx, Box::new(stream), context, peer).await
+        });
+
+        (TcpStream::connect(addr).await.unwrap(), rx, handle)
+    }
+
+    fn simple_auth() -> Option<AuthType> {
+        Some(AuthType::Simple {
+            user: "user".into(),
+            password: "secret".into(),
+        })
+    }
+
+    async fn connect_request(client: &mut TcpStream) {
+        client
+            .write_all(&[0x05, 0x01, 0x00, 0x03, 11])
+            .await
+            .unwrap();
+        client.write_all(b"example.com").await.unwrap();
+        client.write_all(&443u16.to_be_bytes()).await.unwrap();
+
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..2], [0x05, 0x00]);
+    }
+
+    #[tokio::test]
+    async fn test_noauth() {
+        let (mut client, mut rx, handle) = serve_once(None).await;
+
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut method = [0u8; 2];
+        client.read_exact(&mut method).await.unwrap();
+        assert_eq!(method, [0x05, 0x00]);
+
+        connect_request(&mut client).await;
+        handle.await.unwrap().unwrap();
+
+        let req = rx.recv().await.unwrap();
+        assert_eq!(req.remote.to_string(), "example.com:443");
+    }
+
+    #[derive(Debug)]
+    struct DenyAll;
+
+    impl Admission for DenyAll {
+        fn admit(&self, _: SocketAddr, _: &NetLocation) -> Option<Admitted> {
+            None
+        }
+
+        fn permits(&self, _: SocketAddr) -> bool {
+            false
+        }
+    }
+
+    #[tokio::test]
+    async fn test_not_allowed() {
+        let config = Socks5Config {
+            allow_udp: None,
+            auth: None,
+        };
+        let (mut client, mut rx, handle) =
+            serve_once_with_admission(config, Arc::new(DenyAll)).await;
+
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut method = [0u8; 2];
+        client.read_exact(&mut method).await.unwrap();
+
+        client
+            .write_all(&[0x05, 0x01, 0x00, 0x03, 11])
+            .await
+            .unwrap();
+        client.write_all(b"example.com").await.unwrap();
+        client.write_all(&443u16.to_be_bytes()).await.unwrap();
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..2], [0x05, 0x02]);
+
+        assert!(handle.await.unwrap().is_err());
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_password_auth() {
+        let (mut client, mut rx, handle) = serve_once(simple_auth()).await;
+
+        client.write_all(&[0x05, 0x02, 0x00, 0x02]).await.unwrap();
+        let mut method = [0u8; 2];
+        client.read_exact(&mut method).await.unwrap();
+        assert_eq!(method, [0x05, 0x02]);
+
+        client.write_all(&[0x01, 4]).await.unwrap();
+        client.write_all(b"user").await.unwrap();
+        client.write_all(&[6]).await.unwrap();
+        client.write_all(b"secret").await.unwrap();
+        let mut status = [0u8; 2];
+        client.read_exact(&mut status).await.unwrap();
+        assert_eq!(status, [0x01, 0x00]);
+
+        connect_request(&mut client).await;
+        handle.await.unwrap().unwrap();
+
+        let req = rx.recv().await.unwrap();
+        assert_eq!(req.remote.to_string(), "example.com:443");
+    }
+
+    #[tokio::test]
+    async fn test_password_auth_wrong_password() {
+        let (mut client, mut rx, handle) = serve_once(simple_auth()).await;
+
+        client.write_all(&[0x05, 0x01, 0x02]).await.unwrap();
+        let mut method = [0u8; 2];
+        client.read_exact(&mut method).await.unwrap();
+        assert_eq!(method, [0x05, 0x02]);
+
+        client.write_all(&[0x01, 4]).await.unwrap();
+        client.write_all(b"user").await.unwrap();
+        client.write_all(&[5]).await.unwrap();
+        client.write_all(b"wrong").await.unwrap();
+        let mut status = [0u8; 2];
+        client.read_exact(&mut status).await.unwrap();
+        assert_eq!(status, [0x01, 0x01]);
+
+        assert!(handle.await.unwrap().is_err());
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_password_auth_reject_noauth_client() {
+        let (mut client, mut rx, handle) = serve_once(simple_auth()).await;
+
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut method = [0u8; 2];
+        client.read_exact(&mut method).await.unwrap();
+        assert_eq!(method, [0x05, 0xff]);
+
+        assert!(handle.await.unwrap().is_err());
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[test]
+    fn test_udp_packet_codec() {
+        let packet = UdpPacket {
+            remote: NetLocation {
+                address: Address::Hostname("example.com".into()),
+                port: 53,
+            },
+            data: b"hello".to_vec(),
+        };
+
+        let buf = encode_udp_packet(&packet).unwrap();
+        assert_eq!(buf[..5], [0x00, 0x00, 0x00, ATYP_DOMAIN, 11]);
+
+        let decoded = decode_udp_packet(&buf).unwrap();
+        assert_eq!(decoded.remote, packet.remote);
+        assert_eq!(decoded.data, packet.data);
+    }
+
+    #[tokio::test]
+    async fn test_udp_associate_not_allowed() {
+        let (mut client, _rx, handle) = serve_once(None).await;
+
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut method = [0u8; 2];
+        client.read_exact(&mut method).await.unwrap();
+
+        client
+            .write_all(&[0x05, 0x03, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..2], [0x05, 0x07]);
+
+        assert!(handle.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_udp_associate() {
+        let (mut client, mut rx, handle) = serve_once_with_config(Socks5Config {
+            allow_udp: Some(true),
+            auth: None,
+        })
+        .await;
+
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut method = [0u8; 2];
+        client.read_exact(&mut method).await.unwrap();
+
+        client
+            .write_all(&[0x05, 0x03, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..4], [0x05, 0x00, 0x00, ATYP_IPV4]);
+        let relay = SocketAddr::from((
+            [reply[4], reply[5], reply[6], reply[7]],
+            u16::from_be_bytes([reply[8], reply[9]]),
+        ));
+
+        let remote = NetLocation {
+            address: Address::Ip(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))),
+            port: 53,
+        };
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let request = encode_udp_packet(&UdpPacket {
+            remote: remote.clone(),
+            data: b"ping".to_vec(),
+        })
+        .unwrap();
+        udp.send_to(&request, relay).await.unwrap();
+
+        let req = rx.recv().await.unwrap();
+        assert_eq!(req.remote, remote);
+        let mut forwarder = match req.conn {
+            ProxyConn::ForwardUdp(forwarder) => forwarder,
+            _ => panic!("expect udp forwarder"),
+        };
+
+        let packet = forwarder.rx.recv().await.unwrap();
+        assert_eq!(packet.data, b"ping");
+
+        forwarder
+            .tx
+            .send(UdpPacket {
+                remote: remote.clone(),
+                data: b"pong".to_vec(),
+            })
+            .await
+            .unwrap();
+
+        let mut buf = [0u8; 64];
+        let (n, _) = udp.recv_from(&mut buf).await.unwrap();
+        let response = decode_udp_packet(&buf[..n]).unwrap();
+        assert_eq!(response.remote, remote);
+        assert_eq!(response.data, b"pong");
+
+        drop(client);
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_udp_associate_other_source() {
+        let (mut client, mut rx, _handle) = serve_once_with_config(Socks5Config {
+            allow_udp: Some(true),
+            auth: None,
+        })
+        .await;
+
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut method = [0u8; 2];
+        client.read_exact(&mut method).await.unwrap();
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = udp.local_addr().unwrap().port().to_be_bytes();
+        client
+            .write_all(&[0x05, 0x03, 0x00, 0x01, 127, 0, 0, 1, port[0], port[1]])
+            .await
+            .unwrap();
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        let relay = SocketAddr::from((
+            [reply[4], reply[5], reply[6], reply[7]],
+            u16::from_be_bytes([reply[8], reply[9]]),
+        ));
+
+        let send = |data: &[u8]| {
+            encode_udp_packet(&UdpPacket {
+                remote: "10.0.0.1:53".parse().unwrap(),
+                data: data.to_vec(),
+            })
+            .unwrap()
+        };
+        // another host, and another port of the client
+        let other_host = UdpSocket::bind("127.0.0.2:0").await.unwrap();
+        other_host.send_to(&send(b"host"), relay).await.unwrap();
+        let other_port = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        other_port.send_to(&send(b"port"), relay).await.unwrap();
+        udp.send_to(&send(b"ping"), relay).await.unwrap();
+
+        let req = rx.recv().await.unwrap();
+        let mut forwarder = match req.conn {
+            ProxyConn::ForwardUdp(forwarder) => forwarder,
+            _ => panic!("expect udp forwarder"),
+        };
+        assert_eq!(forwarder.rx.recv().await.unwrap().data, b"ping");
+    }
+
+    #[tokio::test]
+    async fn test_client_connect() {
+        use crate::config::transport::{self, ConnectorConfig, ConnectorConfigInner, Endpoint};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+
+        let context = Arc::new(ServerContext {
+            config: Socks5Config {
+                allow_udp: None,
+                auth: simple_auth(),
+            },
+            udp_bind: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            admission: Arc::new(AdmitAll),
+        });
+
+        tokio::spawn(async move {
+            let (stream, peer) = listener.accept().await.unwrap();
+            Server::serve_inner(tx, Box::new(stream), context, peer).await
+        });
+
+        let client = Client::new(ClientConfig {
+            connector: ConnectorConfig {
+                inner: ConnectorConfigInner::Tcp(transport::tcp::ConnectorConfig {
+                    endpoint: Endpoint::Single {
+                        address: addr.ip().to_string(),
+                        port: addr.port(),
+                    },
+                    fwmark: None,
+                }),
+                transport: Default::default(),
+            },
+            auth: simple_auth(),
+            udp_idle_timeout: Duration::from_secs(60),
+        })
+        .await
+        .unwrap();
+
+        let remote = NetLocation {
+            address: Address::Hostname("example.com".into()),
+            port: 443,
+        };
+
+        let mut s = client.connect().await.unwrap();
+        Client::request(&mut s, CMD_CONNECT, &remote).await.unwrap();
+
+        let req = rx.recv().await.unwrap();
+        assert_eq!(req.remote, remote);
+    }
+
+    #[tokio::test]
+    async fn test_client_request_wrong_version() {
+        let (client, mut server) = tokio::io::duplex(64);
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            let _ = server.read(&mut buf).await.unwrap();
+            server
+                .write_all(&[0x04, REPLY_SUCCEEDED, 0x00])
+                .await
+                .unwrap();
+        });
+
+        let remote = NetLocation {
+            address: Address::Ip(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            port: 80,
+        };
+        assert!(Client::request(&mut client.compat(), CMD_CONNECT, &remote)
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,449 @@
+use std::{
+    collections::HashMap,
+    io::{self, IoSliceMut},
+    mem,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    os::fd::{AsFd, AsRawFd, OwnedFd, RawFd},
+    sync::Arc,
+};
+
+use anyhow::Context;
+use nix::{
+    errno::Errno,
+    sys::socket::{
+        self, recvmsg, setsockopt, sockopt, AddressFamily, ControlMessageOwned, MsgFlags,
+        SetSockOpt, SockFlag, SockType, SockaddrStorage,
+    },
+};
+use tokio::{
+    io::Interest,
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::mpsc::{self, error::TrySendError},
+};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tracing::{debug, info, warn};
+
+use super::redirect::OriginalDstLookup;
+use crate::{
+    config::ingress::tproxy::ServerConfig,
+    proxy::{ProxyConn, ProxyRequest, TcpForwarder, UdpForwarder, UdpPacket},
+};
+
+const MAX_DATAGRAM_SIZE: usize = 65535;
+const UDP_CHANNEL_SIZE: usize = 64;
+
+/// Opens the socket sending replies as the original destination.
+type ReplySocket = fn(SocketAddr) -> Result<UdpSocket, anyhow::Error>;
+
+#[derive(Debug)]
+pub struct Server {
+    listener: Arc<TcpListener>,
+    udp: Option<Arc<UdpSocket>>,
+    lookup: OriginalDstLookup,
+}
+
+impl Server {
+    pub async fn new(config: ServerConfig) -> Result<Self, anyhow::Error> {
+        info!("Listening on {}", config.listen);
+
+        let listener = {
+            let fd = transparent_socket(config.listen, SockType::Stream)?;
+            bind(&fd, config.listen)?;
+            socket::listen(&fd, 1024).context("Failed to listen")?;
+            TcpListener::from_std(std::net::TcpListener::from(fd))?
+        };
+
+        let udp = if config.allow_udp.unwrap_or(false) {
+            let fd = transparent_socket(config.listen, SockType::Datagram)?;
+            match config.listen {
+                SocketAddr::V4(_) => setsockopt(&fd, sockopt::Ipv4OrigDstAddr, &true)?,
+                SocketAddr::V6(_) => setsockopt(&fd, sockopt::Ipv6OrigDstAddr, &true)?,
+            }
+            bind(&fd, config.listen)?;
+            Some(Arc::new(UdpSocket::from_std(std::net::UdpSocket::from(
+                fd,
+            ))?))
+        } else {
+            None
+        };
+
+        Ok(Self {
+            listener: Arc::new(listener),
+            udp,
+            // the local address of a transparent socket is the original destination
+            lookup: TcpStream::local_addr,
+        })
+    }
+
+    pub async fn incoming(&self) -> Result<UnboundedReceiverStream<ProxyRequest>, anyhow::Error> {
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(Self::run_tcp(
+            self.listener.clone(),
+            self.lookup,
+            tx.clone(),
+        ));
+
+        if let Some(udp) = &self.udp {
+            let udp = udp.clone();
+            tokio::spawn(async move {
+                if let Err(e) = Self::run_udp(udp, tx).await {
+                    warn!("udp relay error: {:?}", e);
+                }
+            });
+        }
+
+        Ok(UnboundedReceiverStream::new(rx))
+    }
+
+    fn serve(
+        tx: &mpsc::UnboundedSender<ProxyRequest>,
+        stream: TcpStream,
+        listen: SocketAddr,
+        lookup: OriginalDstLookup,
+    ) -> Result<(), anyhow::Error> {
+        let dst = lookup(&stream)?;
+
+        // connections made straight to the listener would loop back to it
+        if is_listener(dst, listen) {
+            anyhow::bail!("{} is not tproxied", dst)
+        }
+
+        debug!("original destination is {}", dst);
+
+        tx.send(ProxyRequest {
+            remote: dst.into(),
+            source: stream.peer_addr().ok(),
+            route: None,
+            conn: ProxyConn::ForwardTcp(TcpForwarder {
+                stream: Box::new(stream),
+            }),
+        })
+        .map_err(|e| anyhow::anyhow!("send error: {:?}", e.0))
+    }
+
+    async fn run_tcp(
+        listener: Arc<TcpListener>,
+        lookup: OriginalDstLookup,
+        tx: mpsc::UnboundedSender<ProxyRequest>,
+    ) {
+        let listen = match listener.local_addr() {
+            Ok(listen) => listen,
+            Err(e) => {
+                warn!("{:?}", e);
+                return;
+            }
+        };
+
+        loop {
+            let accepted = tokio::select! {
+                _ = tx.closed() => break,
+                accepted = listener.accept() => accepted,
+            };
+            match accepted {
+                Ok((stream, _)) => {
+                    if let Err(e) = Self::serve(&tx, stream, listen, lookup) {
+                        warn!("{:?}", e);
+                    }
+                }
+                Err(e) => {
+                    warn!("tcp accept error: {:?}", e);
+                    break;
+                }
+            }
+        }
+    }
+
+    async fn run_udp(
+        socket: Arc<UdpSocket>,
+        tx: mpsc::UnboundedSender<ProxyRequest>,
+    ) -> Result<(), anyhow::Error> {
+        let mut sessions = HashMap::new();
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+
+        loop {
+            let (n, src, dst) = tokio::select! {
+                _ = tx.closed() => return Ok(()),
+                received = socket.async_io(Interest::READABLE, || {
+                    recv_with_original_dst(socket.as_raw_fd(), &mut buf)
+                }) => received?,
+            };
+
+            if let Err(e) = Self::dispatch_udp_packet(
+                &tx,
+                &mut sessions,
+                src,
+                dst,
+                &buf[..n],
+                transparent_reply_socket,
+            ) {
+                warn!("{:?}", e);
+            }
+        }
+    }
+
+    fn dispatch_udp_packet(
+        tx: &mpsc::UnboundedSender<ProxyRequest>,
+        sessions: &mut HashMap<(SocketAddr, SocketAddr), mpsc::Sender<UdpPacket>>,
+        src: SocketAddr,
+        dst: SocketAddr,
+        data: &[u8],
+        reply_socket: ReplySocket,
+    ) -> Result<(), anyhow::Error> {
+        let mut packet = UdpPacket {
+            remote: dst.into(),
+            data: data.to_vec(),
+        };
+
+        if let Some(sender) = sessions.get(&(src, dst)) {
+            match sender.try_send(packet) {
+                Ok(_) => return Ok(()),
+                Err(TrySendError::Full(_)) => {
+                    debug!("drop udp packet from {} to {}, session is busy", src, dst);
+                    return Ok(());
+                }
+                Err(TrySendError::Closed(v)) => packet = v,
+            }
+        }
+
+        sessions.retain(|_, sender| !sender.is_closed());
+
+        let reply = reply_socket(dst)?;
+
+        let (sender, receiver) = mpsc::channel(UDP_CHANNEL_SIZE);
+        let (reply_tx, reply_rx) = mpsc::channel(UDP_CHANNEL_SIZE);
+
+        sender
+            .try_send(packet)
+            .map_err(|e| anyhow::anyhow!("send error: {:?}", e))?;
+        sessions.insert((src, dst), sender);
+
+        tokio::spawn(Self::reply(reply, src, reply_rx));
+
+        tx.send(ProxyRequest {
+            remote: dst.into(),
+            source: Some(src),
+            route: None,
+            conn: ProxyConn::ForwardUdp(UdpForwarder::new(receiver, reply_tx)),
+        })
+        .map_err(|e| anyhow::anyhow!("send error: {:?}", e.0))
+    }
+
+    async fn reply(socket: UdpSocket, client: SocketAddr, mut rx: mpsc::Receiver<UdpPacket>) {
+        while let Some(packet) = rx.recv().await {
+            if let Err(e) = socket.send_to(&packet.data, client).await {
+                warn!("reply to {} error: {:?}", client, e);
+                break;
+            }
+        }
+    }
+}
+
+/// Whether a connection to `dst` reaches the listener itself rather than
+/// being diverted to it. A listener on an unspecified address also takes
+/// connections to loopback.
+fn is_listener(dst: SocketAddr, listen: SocketAddr) -> bool {
+    dst.port() == listen.port()
+        && (dst.ip() == listen.ip() || (listen.ip().is_unspecified() && dst.ip().is_loopback()))
+}
+
+/// Replies must come from the original destination.
+fn transparent_reply_socket(dst: SocketAddr) -> Result<UdpSocket, anyhow::Error> {
+    let fd = transparent_socket(dst, SockType::Datagram)?;
+    bind(&fd, dst)?;
+    Ok(UdpSocket::from_std(std::net::UdpSocket::from(fd))?)
+}
+
+/// `IPV6_TRANSPARENT`, nix only has the ipv4 option.
+#[derive(Debug, Clone, Copy)]
+struct Ipv6Transparent;
+
+impl SetSockOpt for Ipv6Transparent {
+    type Val = bool;
+
+    fn set<F: AsFd>(&self, fd: &F, val: &bool) -> nix::Result<()> {
+        let val = *val as libc::c_int;
+        let ret = unsafe {
+            libc::setsockopt(
+                fd.as_fd().as_raw_fd(),
+                libc::SOL_IPV6,
+                libc::IPV6_TRANSPARENT,
+                &val as *const _ as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        Errno::result(ret).map(drop)
+    }
+}
+
+fn transparent_socket(addr: SocketAddr, ty: SockType) -> Result<OwnedFd, anyhow::Error> {
+    let family = match addr {
+        SocketAddr::V4(_) => AddressFamily::Inet,
+        SocketAddr::V6(_) => AddressFamily::Inet6,
+    };
+
+    let fd = socket::socket(
+        family,
+        ty,
+        SockFlag::SOCK_NONBLOCK | SockFlag::SOCK_CLOEXEC,
+        None,
+    )
+    .context("Failed to create socket")?;
+
+    setsockopt(&fd, sockopt::ReuseAddr, &true)?;
+    match addr {
+        SocketAddr::V4(_) => setsockopt(&fd, sockopt::IpTransparent, &true),
+        SocketAddr::V6(_) => setsockopt(&fd, Ipv6Transparent, &true),
+    }
+    .context("Failed to set IP_TRANSPARENT, CAP_NET_ADMIN is required")?;
+
+    Ok(fd)
+}
+
+fn bind(fd: &OwnedFd, addr: SocketAddr) -> Result<(), anyhow::Error> {
+    socket::bind(fd.as_raw_fd(), &SockaddrStorage::from(addr))
+        .with_context(|| format!("Failed to bind {}", addr))
+}
+
+fn recv_with_original_dst(
+    fd: RawFd,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr, SocketAddr)> {
+    let mut iov = [IoSliceMut::new(buf)];
+    let mut cmsg = nix::cmsg_space!(libc::sockaddr_in6);
+
+    let msg = recvmsg::<SockaddrStorage>(fd, &mut iov, Some(&mut cmsg), MsgFlags::empty())?;
+
+    let src = msg
+        .address
+        .and_then(|addr| {
+            if let Some(addr) = addr.as_sockaddr_in() {
+                Some(SocketAddr::V4(SocketAddrV4::from(*addr)))
+            } else {
+                addr.as_sockaddr_in6()
+                    .map(|addr| SocketAddr::V6(SocketAddrV6::from(*addr)))
+            }
+        })
+        .ok_or(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "source address is missing",
+        ))?;
+
+    let dst = msg
+        .cmsgs()
+        .find_map(|cmsg| match cmsg {
+            ControlMessageOwned::Ipv4OrigDstAddr(addr) => Some(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+                u16::from_be(addr.sin_port),
+            ))),
+            ControlMessageOwned::Ipv6OrigDstAddr(addr) => Some(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                u16::from_be(addr.sin6_port),
+                addr.sin6_flowinfo,
+                addr.sin6_scope_id,
+            ))),
+            _ => None,
+        })
+        .ok_or(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "original destination is missing",
+        ))?;
+
+    Ok((msg.bytes, src, dst))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::proxy::NetLocation;
+
+    use super::*;
+
+    fn reply_socket(_: SocketAddr) -> Result<UdpSocket, anyhow::Error> {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0")?;
+        socket.set_nonblocking(true)?;
+        Ok(UdpSocket::from_std(socket)?)
+    }
+
+    fn mock_original_dst(_: &TcpStream) -> io::Result<SocketAddr> {
+        Ok(SocketAddr::from(([10, 0, 0, 1], 443)))
+    }
+
+    #[tokio::test]
+    async fn test_tproxy() {
+        let server = Server {
+            listener: Arc::new(TcpListener::bind("127.0.0.1:0").await.unwrap()),
+            udp: None,
+            lookup: mock_original_dst,
+        };
+        let addr = server.listener.local_addr().unwrap();
+
+        let mut incoming = server.incoming().await.unwrap().into_inner();
+        let client = TcpStream::connect(addr).await.unwrap();
+
+        let req = incoming.recv().await.unwrap();
+        assert_eq!(req.remote, SocketAddr::from(([10, 0, 0, 1], 443)).into());
+        assert_eq!(req.source, Some(client.local_addr().unwrap()));
+        assert!(matches!(req.conn, ProxyConn::ForwardTcp(_)));
+    }
+
+    #[tokio::test]
+    async fn test_not_tproxied() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let client = tokio::spawn(TcpStream::connect(addr));
+        let (stream, _) = listener.accept().await.unwrap();
+        let _client = client.await.unwrap().unwrap();
+
+        assert!(Server::serve(&tx, stream, addr, TcpStream::local_addr).is_err());
+        drop(tx);
+        assert!(rx.recv().await.is_none());
+
+        let any = SocketAddr::from(([0, 0, 0, 0], addr.port()));
+        assert!(is_listener(addr, any));
+        assert!(!is_listener(
+            SocketAddr::from(([10, 0, 0, 1], addr.port())),
+            any
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_udp_sessions() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut sessions = HashMap::new();
+        let src: SocketAddr = "192.168.1.2:5000".parse().unwrap();
+        let dst: SocketAddr = "8.8.8.8:53".parse().unwrap();
+        let other: SocketAddr = "1.1.1.1:53".parse().unwrap();
+
+        let mut dispatch = |dst, data: &[u8]| {
+            Server::dispatch_udp_packet(&tx, &mut sessions, src, dst, data, reply_socket).unwrap()
+        };
+        dispatch(dst, b"first");
+        dispatch(dst, b"second");
+        dispatch(other, b"third");
+
+        let request = rx.recv().await.unwrap();
+        assert_eq!(request.remote, NetLocation::from(dst));
+        assert_eq!(request.source, Some(src));
+        let ProxyConn::ForwardUdp(mut forwarder) = request.conn else {
+            panic!("expect udp")
+        };
+        assert_eq!(forwarder.rx.recv().await.unwrap().data, b"first");
+        assert_eq!(forwarder.rx.recv().await.unwrap().data, b"second");
+
+        // packets to another destination are another session
+        let request = rx.recv().await.unwrap();
+        assert_eq!(request.remote, NetLocation::from(other));
+        assert!(rx.try_recv().is_err());
+
+        // a closed session is replaced
+        drop(forwarder);
+        dispatch(dst, b"fourth");
+        let request = rx.recv().await.unwrap();
+        let ProxyConn::ForwardUdp(mut forwarder) = request.conn else {
+            panic!("expect udp")
+        };
+        assert_eq!(forwarder.rx.recv().await.unwrap().data, b"fourth");
+    }
+}
@@ -0,0 +1,86 @@
+use std::{fmt, os::fd::AsRawFd};
+
+use ipnet::IpNet;
+use libc::{IFF_NO_PI, IFF_TUN};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tracing::{info, warn};
+
+use crate::{
+    config::ingress::tun::ServerConfig,
+    net::tun::{async_io::TunIO, Stack, Tun},
+    netcfg::{Routing, RoutingTool, MAIN_TABLE},
+    proxy::ProxyRequest,
+};
+
+const DEFAULT_MTU: usize = 1500;
+
+pub struct Server {
+    config: ServerConfig,
+    tun: Tun,
+}
+
+impl fmt::Debug for Server {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Server")
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+impl Server {
+    pub async fn new(config: ServerConfig) -> Result<Self, anyhow::Error> {
+        let tun = Tun::new(&config.name, IFF_TUN | IFF_NO_PI)?;
+        let ifindex = tun.ifindex()?;
+
+        info!("Opened tun device {}", tun.ifname()?);
+
+        let mut routing = RoutingTool::new()?;
+        for net in &config.address {
+            routing.add_address(ifindex, net.addr(), net.prefix_len())?;
+        }
+        routing.set_link_up(ifindex)?;
+        let table = config.fwmark.unwrap_or(MAIN_TABLE);
+        for net in &config.route {
+            routing.add_route(ifindex, net.network(), net.prefix_len(), table)?;
+        }
+        match config.fwmark {
+            Some(mark) => {
+                for ipv6 in [false, true] {
+                    if config
+                        .route
+                        .iter()
+                        .any(|net| matches!(net, IpNet::V6(_)) == ipv6)
+                    {
+                        routing.add_rule_unless_mark(ipv6, mark, table)?;
+                    }
+                }
+            }
+            None if !config.route.is_empty() => {
+                warn!("tun routes without fwmark also capture the traffic of egresses")
+            }
+            None => {}
+        }
+
+        Ok(Self { config, tun })
+    }
+
+    pub async fn incoming(&self) -> Result<UnboundedReceiverStream<ProxyRequest>, anyhow::Error> {
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        let io = TunIO::new(self.tun.as_raw_fd())?;
+        let stack = Stack::new(
+            &self.config.address,
+            self.config.mtu.unwrap_or(DEFAULT_MTU),
+            self.config.allow_udp.unwrap_or(false),
+        )?;
+
+        tokio::spawn(async move {
+            if let Err(e) = stack.run(io, tx).await {
+                warn!("tun stack error: {:?}", e);
+            }
+        });
+
+        Ok(UnboundedReceiverStream::new(rx))
+    }
+}
@@ -6,10 +6,16 @@ use std::{
 };
 
 use anyhow::Context;
-use tracing::warn;
+use tracing::{debug, warn};
 
 use crate::{config::transport::Endpoint, net::transport::Connect, proxy::Address};
 
+/// Returned by `open_stream` when the connections filled up after
+/// `is_open`, another connection is made to the same endpoint then.
+#[derive(Debug, thiserror::Error)]
+#[error("no connection has room for a stream")]
+pub struct NoRoom;
+
 #[derive(Debug)]
 pub struct Connector<T, S> {
     address: Address,
@@ -89,6 +95,10 @@ where
 
             match self.connector.open_stream().await {
                 Ok(s) => return Ok(s),
+                Err(e) if e.is::<NoRoom>() => {
+                    debug!("{}, connect again", e);
+                    continue;
+                }
                 Err(e) => {
                     warn!("open stream failed: {:?}", e);
                     self.connector.close().await;
@@ -99,3 +109,62 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicBool, AtomicUsize},
+        Mutex,
+    };
+
+    use super::*;
+
+    #[derive(Debug, Default)]
+    struct Conn {
+        open: AtomicBool,
+        endpoints: Mutex<Vec<SocketAddr>>,
+        streams: AtomicUsize,
+    }
+
+    impl Connect<usize> for Conn {
+        async fn is_open(&self) -> bool {
+            self.open.load(Ordering::Relaxed)
+        }
+
+        async fn connect(&self, endpoint: SocketAddr) -> Result<(), anyhow::Error> {
+            self.endpoints.lock().unwrap().push(endpoint);
+            self.open.store(true, Ordering::Relaxed);
+            Ok(())
+        }
+
+        async fn close(&self) {
+            self.open.store(false, Ordering::Relaxed);
+        }
+
+        async fn open_stream(&self) -> Result<usize, anyhow::Error> {
+            // the first connection is taken by somebody else
+            if self.streams.fetch_add(1, Ordering::Relaxed) == 0 {
+                self.open.store(false, Ordering::Relaxed);
+                return Err(NoRoom.into());
+            }
+            Ok(self.endpoints.lock().unwrap().len())
+        }
+    }
+
+    #[tokio::test]
+    async fn keep_port_without_room() {
+        let connector = Connector::new(
+            Conn::default(),
+            Endpoint::Multi {
+                address: "127.0.0.1".to_string(),
+                port_range: "1000-1001".to_string(),
+            },
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(connector.connect().await.unwrap(), 2);
+        let endpoints = connector.connector.endpoints.lock().unwrap();
+        assert!(endpoints.iter().all(|endpoint| endpoint.port() == 1000));
+    }
+}
@@ -0,0 +1,24 @@
+use std::io;
+
+cfg_if::cfg_if! {
+    if #[cfg(target_os = "linux")] {
+        use std::os::fd::AsFd;
+
+        use nix::sys::socket::{setsockopt, sockopt};
+
+        /// Marks the packets sent by a socket, so policy routing can keep
+        /// the proxy's own traffic out of the routes captured by a tun
+        /// ingress.
+        pub fn set_mark<S: AsFd>(socket: &S, mark: u32) -> io::Result<()> {
+            setsockopt(socket, sockopt::Mark, &mark)?;
+            Ok(())
+        }
+    } else {
+        pub fn set_mark<S>(_: &S, _: u32) -> io::Result<()> {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "socket mark is only supported on linux",
+            ))
+        }
+    }
+}
@@ -1 +1,3 @@
 pub mod dynamic_port;
+pub mod mark;
+pub mod pool;
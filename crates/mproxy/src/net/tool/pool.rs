@@ -0,0 +1,236 @@
+use std::{
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex, Weak},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use pin_project_lite::pin_project;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::Instant,
+};
+
+/// A connection carrying many streams.
+pub trait Session {
+    fn is_closed(&self) -> bool;
+    fn close(&self);
+}
+
+#[derive(Debug)]
+struct Usage {
+    last_used: Mutex<Instant>,
+}
+
+/// Counts a stream against its connection until dropped.
+#[derive(Debug)]
+pub struct Lease(Arc<Usage>);
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        *self.0.last_used.lock().unwrap() = Instant::now();
+    }
+}
+
+#[derive(Debug)]
+struct Entry<C> {
+    conn: Arc<C>,
+    usage: Arc<Usage>,
+}
+
+impl<C> Entry<C> {
+    fn streams(&self) -> usize {
+        Arc::strong_count(&self.usage) - 1
+    }
+
+    fn lease(&self) -> (Arc<C>, Lease) {
+        (self.conn.clone(), Lease(self.usage.clone()))
+    }
+}
+
+/// Drops closed connections and closes the ones idle for `idle_timeout`.
+fn sweep<C: Session>(entries: &mut Vec<Entry<C>>, idle_timeout: Duration) {
+    entries.retain(|entry| {
+        if entry.conn.is_closed() {
+            return false;
+        }
+        let idle =
+            entry.streams() == 0 && entry.usage.last_used.lock().unwrap().elapsed() >= idle_timeout;
+        if idle {
+            entry.conn.close();
+        }
+        !idle
+    });
+}
+
+/// Connections shared by streams, at most `max_streams` on each.
+#[derive(Debug)]
+pub struct Pool<C> {
+    entries: Arc<Mutex<Vec<Entry<C>>>>,
+    max_streams: usize,
+    idle_timeout: Duration,
+}
+
+impl<C> Pool<C>
+where
+    C: Session + Send + Sync + 'static,
+{
+    /// Idle connections are also closed by a task living as long as the
+    /// pool, so they don't wait for the next stream to go away.
+    pub fn new(max_streams: usize, idle_timeout: Duration) -> Self {
+        let entries = Arc::new(Mutex::new(Vec::new()));
+        tokio::spawn(Self::sweep_idle(Arc::downgrade(&entries), idle_timeout));
+        Self {
+            entries,
+            max_streams,
+            idle_timeout,
+        }
+    }
+
+    async fn sweep_idle(entries: Weak<Mutex<Vec<Entry<C>>>>, idle_timeout: Duration) {
+        loop {
+            tokio::time::sleep((idle_timeout / 2).max(Duration::from_secs(1))).await;
+            let Some(entries) = entries.upgrade() else {
+                break;
+            };
+            sweep(&mut entries.lock().unwrap(), idle_timeout);
+        }
+    }
+
+    /// The oldest open connection with room for another stream, so the
+    /// newer ones get idle and closed first.
+    pub fn acquire(&self) -> Option<(Arc<C>, Lease)> {
+        let mut entries = self.entries.lock().unwrap();
+        sweep(&mut entries, self.idle_timeout);
+        entries
+            .iter()
+            .find(|entry| entry.streams() < self.max_streams)
+            .map(Entry::lease)
+    }
+
+    /// Adds a new connection with the first stream leased on it.
+    pub fn insert(&self, conn: C) -> (Arc<C>, Lease) {
+        let entry = Entry {
+            conn: Arc::new(conn),
+            usage: Arc::new(Usage {
+                last_used: Mutex::new(Instant::now()),
+            }),
+        };
+        let lease = entry.lease();
+        self.entries.lock().unwrap().push(entry);
+        lease
+    }
+
+    pub fn has_room(&self) -> bool {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|entry| !entry.conn.is_closed() && entry.streams() < self.max_streams)
+    }
+
+    /// Forgets the connections closed by errors.
+    pub fn remove_closed(&self) {
+        self.entries
+            .lock()
+            .unwrap()
+            .retain(|entry| !entry.conn.is_closed());
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+}
+
+pin_project! {
+    /// A stream holding the lease on its connection.
+    #[derive(Debug)]
+    pub struct Leased<S> {
+        #[pin]
+        stream: S,
+        lease: Lease,
+    }
+}
+
+impl<S> Leased<S> {
+    pub fn new(stream: S, lease: Lease) -> Self {
+        Self { stream, lease }
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for Leased<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.project().stream.poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite> AsyncWrite for Leased<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        self.project().stream.poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        self.project().stream.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        self.project().stream.poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use super::*;
+
+    #[derive(Debug, Default)]
+    struct Conn {
+        closed: AtomicBool,
+    }
+
+    impl Session for Conn {
+        fn is_closed(&self) -> bool {
+            self.closed.load(Ordering::Relaxed)
+        }
+
+        fn close(&self) {
+            self.closed.store(true, Ordering::Relaxed);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn limits() {
+        let pool = Pool::new(2, Duration::from_secs(60));
+        assert!(pool.acquire().is_none());
+
+        let (first, a) = pool.insert(Conn::default());
+        let (conn, b) = pool.acquire().unwrap();
+        assert!(Arc::ptr_eq(&first, &conn));
+        assert!(pool.acquire().is_none());
+        assert!(!pool.has_room());
+
+        let (second, _c) = pool.insert(Conn::default());
+        drop(b);
+        let (conn, _d) = pool.acquire().unwrap();
+        assert!(Arc::ptr_eq(&first, &conn));
+
+        second.close();
+        pool.remove_closed();
+        assert_eq!(pool.len(), 1);
+
+        drop((a, _d));
+        tokio::time::sleep(Duration::from_secs(61)).await;
+        assert_eq!(pool.len(), 0);
+        assert!(first.is_closed());
+    }
+}
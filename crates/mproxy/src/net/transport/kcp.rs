@@ -21,9 +21,8 @@ impl Acceptor {
         })
     }
 
-    pub async fn accept(&self) -> Result<KcpStream, anyhow::Error> {
-        let (s, _) = self.listener.lock().await.accept().await?;
-        Ok(s)
+    pub async fn accept(&self) -> Result<(KcpStream, SocketAddr), anyhow::Error> {
+        Ok(self.listener.lock().await.accept().await?)
     }
 }
 
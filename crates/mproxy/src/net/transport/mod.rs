@@ -1,7 +1,9 @@
 mod kcp;
+mod mux;
 mod quic;
 mod tcp;
 mod tls;
+mod ws;
 
 use std::net::SocketAddr;
 
@@ -18,6 +20,8 @@ pub enum Acceptor {
     Tcp(tcp::Acceptor),
     Kcp(kcp::Acceptor),
     Tls(Box<tls::Acceptor>),
+    Mux(mux::Acceptor),
+    Ws(Box<ws::Acceptor>),
 }
 
 impl Acceptor {
@@ -30,16 +34,33 @@ impl Acceptor {
             AcceptorConfig::Tls(config) => {
                 Acceptor::Tls(Box::new(tls::Acceptor::new(*config).await?))
             }
+            AcceptorConfig::Mux(config) => Acceptor::Mux(mux::Acceptor::new(*config).await?),
+            AcceptorConfig::Ws(config) => Acceptor::Ws(Box::new(ws::Acceptor::new(*config).await?)),
         })
     }
 
+    /// Returns the stream and the address of the peer.
     #[async_recursion]
-    pub async fn accept(&self) -> Result<BoxedAsyncIO, anyhow::Error> {
+    pub async fn accept(&self) -> Result<(BoxedAsyncIO, SocketAddr), anyhow::Error> {
         Ok(match self {
-            Acceptor::Quic(acceptor) => Box::new(acceptor.accept().await?) as BoxedAsyncIO,
-            Acceptor::Tcp(acceptor) => Box::new(acceptor.accept().await?),
-            Acceptor::Kcp(acceptor) => Box::new(acceptor.accept().await?),
-            Acceptor::Tls(acceptor) => Box::new(acceptor.accept().await?),
+            Acceptor::Quic(acceptor) => {
+                let (s, peer) = acceptor.accept().await?;
+                (Box::new(s) as BoxedAsyncIO, peer)
+            }
+            Acceptor::Tcp(acceptor) => {
+                let (s, peer) = acceptor.accept().await?;
+                (Box::new(s) as BoxedAsyncIO, peer)
+            }
+            Acceptor::Kcp(acceptor) => {
+                let (s, peer) = acceptor.accept().await?;
+                (Box::new(s) as BoxedAsyncIO, peer)
+            }
+            Acceptor::Tls(acceptor) => acceptor.accept().await?,
+            Acceptor::Mux(acceptor) => {
+                let (s, peer) = acceptor.accept().await?;
+                (Box::new(s) as BoxedAsyncIO, peer)
+            }
+            Acceptor::Ws(acceptor) => acceptor.accept().await?,
         })
     }
 
@@ -47,6 +68,7 @@ impl Acceptor {
     pub async fn handshake(&self, io: BoxedAsyncIO) -> Result<BoxedAsyncIO, anyhow::Error> {
         Ok(match self {
             Acceptor::Tls(acceptor) => Box::new(acceptor.handshake(io).await?) as BoxedAsyncIO,
+            Acceptor::Ws(acceptor) => Box::new(acceptor.handshake(io).await?),
             _ => io,
         })
     }
@@ -58,6 +80,8 @@ pub enum ConnectorInner {
     Tcp(tcp::Connector),
     Kcp(kcp::Connector),
     Tls(Box<tls::Connector>),
+    Mux(Box<mux::Connector>),
+    Ws(Box<ws::Connector>),
 }
 
 #[derive(Debug)]
@@ -69,10 +93,11 @@ pub struct Connector {
 impl Connector {
     #[async_recursion]
     pub async fn new(config: ConnectorConfig) -> Result<Connector, anyhow::Error> {
+        let transport = config.transport.clone();
         Ok(Self {
             inner: match config.inner.clone() {
                 ConnectorConfigInner::Quic(config) => {
-                    ConnectorInner::Quic(quic::Connector::new(config).await?)
+                    ConnectorInner::Quic(quic::Connector::new(config, &transport).await?)
                 }
                 ConnectorConfigInner::Tcp(config) => {
                     ConnectorInner::Tcp(tcp::Connector::new(config).await?)
@@ -83,24 +108,36 @@ impl Connector {
                 ConnectorConfigInner::Tls(config) => {
                     ConnectorInner::Tls(Box::new(tls::Connector::new(*config).await?))
                 }
+                ConnectorConfigInner::Mux(config) => {
+                    ConnectorInner::Mux(Box::new(mux::Connector::new(*config, &transport).await?))
+                }
+                ConnectorConfigInner::Ws(config) => {
+                    ConnectorInner::Ws(Box::new(ws::Connector::new(*config).await?))
+                }
             },
             config,
         })
     }
 
-    #[async_recursion]
     pub async fn connect(&self) -> Result<BoxedAsyncIO, anyhow::Error> {
-        let io = match &self.inner {
+        let mut io = TimeoutStream::new(self.connect_raw().await?);
+        io.set_read_timeout(Some(self.config.transport.read_timeout));
+        io.set_write_timeout(Some(self.config.transport.write_timeout));
+        Ok(Box::new(io) as BoxedAsyncIO)
+    }
+
+    /// The stream without read and write timeouts, for layers keeping it
+    /// open while they have nothing to send.
+    #[async_recursion]
+    pub async fn connect_raw(&self) -> Result<BoxedAsyncIO, anyhow::Error> {
+        Ok(match &self.inner {
             ConnectorInner::Quic(connector) => Box::new(connector.connect().await?) as BoxedAsyncIO,
             ConnectorInner::Tcp(connector) => Box::new(connector.connect().await?),
             ConnectorInner::Kcp(connector) => Box::new(connector.connect().await?),
             ConnectorInner::Tls(connector) => Box::new(connector.connect().await?),
-        };
-
-        let mut io = TimeoutStream::new(io);
-        io.set_read_timeout(Some(self.config.transport.read_timeout));
-        io.set_write_timeout(Some(self.config.transport.write_timeout));
-        Ok(Box::new(io) as BoxedAsyncIO)
+            ConnectorInner::Mux(connector) => Box::new(connector.connect().await?),
+            ConnectorInner::Ws(connector) => Box::new(connector.connect().await?),
+        })
     }
 }
 
@@ -0,0 +1,502 @@
+use std::{
+    collections::VecDeque,
+    future::poll_fn,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context as TaskContext, Poll},
+    time::Duration,
+};
+
+use anyhow::Context;
+use pin_project_lite::pin_project;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::{mpsc, oneshot, Mutex},
+    time::Instant,
+};
+use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt};
+use tracing::{debug, instrument, warn};
+
+use crate::{
+    config::transport::{
+        mux::{AcceptorConfig, ConnectorConfig},
+        TransportConfig,
+    },
+    io::{BoxedAsyncIO, TimeoutStream},
+    net::tool::pool::{Leased, Pool, Session},
+};
+
+pub type MuxStream = Compat<yamux::Stream>;
+
+#[derive(Debug)]
+pub struct Acceptor {
+    stream_rx: Mutex<mpsc::UnboundedReceiver<(MuxStream, SocketAddr)>>,
+}
+
+impl Acceptor {
+    pub async fn new(config: AcceptorConfig) -> Result<Self, anyhow::Error> {
+        let next_layer = super::Acceptor::new(config.next_layer).await?;
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(Self::run(Arc::new(next_layer), tx));
+
+        Ok(Self {
+            stream_rx: Mutex::new(rx),
+        })
+    }
+
+    pub async fn accept(&self) -> Result<(MuxStream, SocketAddr), anyhow::Error> {
+        let mut rx = self.stream_rx.lock().await;
+        rx.recv().await.context("Failed to accept")
+    }
+
+    async fn run(
+        next_layer: Arc<super::Acceptor>,
+        tx: mpsc::UnboundedSender<(MuxStream, SocketAddr)>,
+    ) {
+        loop {
+            let accepted = tokio::select! {
+                // the acceptor has been dropped, stop listening
+                _ = tx.closed() => break,
+                accepted = next_layer.accept() => accepted,
+            };
+            let (io, peer) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("accept error: {:?}", e);
+                    break;
+                }
+            };
+
+            let next_layer = next_layer.clone();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let io = match next_layer.handshake(io).await {
+                    Ok(io) => io,
+                    Err(e) => {
+                        warn!("{:?}", e);
+                        return;
+                    }
+                };
+                if let Err(e) = Self::serve(io, peer, tx).await {
+                    debug!("mux connection from {} closed: {:?}", peer, e);
+                }
+            });
+        }
+    }
+
+    async fn serve(
+        io: BoxedAsyncIO,
+        peer: SocketAddr,
+        tx: mpsc::UnboundedSender<(MuxStream, SocketAddr)>,
+    ) -> Result<(), yamux::ConnectionError> {
+        let mut conn =
+            yamux::Connection::new(io.compat(), yamux::Config::default(), yamux::Mode::Server);
+        // polling for inbound streams also moves the data of the open ones
+        while let Some(stream) = poll_fn(|cx| conn.poll_next_inbound(cx)).await {
+            if tx.send((stream?.compat(), peer)).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+enum Command {
+    Open(oneshot::Sender<Result<yamux::Stream, yamux::ConnectionError>>),
+    Close,
+}
+
+/// The connection of a session, noting when it last received anything.
+struct Watched {
+    io: BoxedAsyncIO,
+    last_read: Arc<std::sync::Mutex<Instant>>,
+}
+
+impl AsyncRead for Watched {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let r = Pin::new(&mut self.io).poll_read(cx, buf);
+        if buf.filled().len() > filled {
+            *self.last_read.lock().unwrap() = Instant::now();
+        }
+        r
+    }
+}
+
+impl AsyncWrite for Watched {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
+/// Handle of a client connection driven by its own task.
+#[derive(Debug)]
+struct MuxSession {
+    tx: mpsc::UnboundedSender<Command>,
+    closed: AtomicBool,
+    last_read: Arc<std::sync::Mutex<Instant>>,
+}
+
+impl MuxSession {
+    fn new(io: BoxedAsyncIO) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let last_read = Arc::new(std::sync::Mutex::new(Instant::now()));
+        let io = Box::new(Watched {
+            io,
+            last_read: last_read.clone(),
+        }) as BoxedAsyncIO;
+        let conn =
+            yamux::Connection::new(io.compat(), yamux::Config::default(), yamux::Mode::Client);
+        tokio::spawn(async move {
+            if let Err(e) = Self::drive(conn, rx).await {
+                debug!("mux connection closed: {:?}", e);
+            }
+        });
+
+        Self {
+            tx,
+            closed: AtomicBool::new(false),
+            last_read,
+        }
+    }
+
+    fn silent_since(&self, since: Instant) -> bool {
+        *self.last_read.lock().unwrap() <= since
+    }
+
+    async fn drive(
+        mut conn: yamux::Connection<Compat<BoxedAsyncIO>>,
+        mut rx: mpsc::UnboundedReceiver<Command>,
+    ) -> Result<(), yamux::ConnectionError> {
+        let mut opening = VecDeque::new();
+        let mut closing = false;
+        poll_fn(|cx| {
+            while !closing {
+                match rx.poll_recv(cx) {
+                    Poll::Ready(Some(Command::Open(tx))) => opening.push_back(tx),
+                    // the pool has dropped the session
+                    Poll::Ready(Some(Command::Close)) | Poll::Ready(None) => closing = true,
+                    Poll::Pending => break,
+                }
+            }
+            if closing {
+                return conn.poll_close(cx);
+            }
+
+            while !opening.is_empty() {
+                match conn.poll_new_outbound(cx) {
+                    Poll::Ready(Ok(stream)) => {
+                        if let Some(tx) = opening.pop_front() {
+                            let _ = tx.send(Ok(stream));
+                        }
+                    }
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => break,
+                }
+            }
+
+            loop {
+                match conn.poll_next_inbound(cx) {
+                    // the server side never opens streams
+                    Poll::Ready(Some(Ok(_))) => {}
+                    Poll::Ready(Some(Err(e))) => return Poll::Ready(Err(e)),
+                    Poll::Ready(None) => return Poll::Ready(Ok(())),
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+        })
+        .await
+    }
+
+    async fn open(&self) -> Result<MuxStream, anyhow::Error> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(Command::Open(tx))
+            .ok()
+            .context("mux connection is closed")?;
+        Ok(rx.await.context("mux connection is closed")??.compat())
+    }
+}
+
+impl Session for MuxSession {
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed) || self.tx.is_closed()
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        let _ = self.tx.send(Command::Close);
+    }
+}
+
+pin_project! {
+    /// A stream closing its session when it fails, or when it times out
+    /// while the whole connection got nothing either, so a dead connection
+    /// isn't leased again. yamux has no keepalive to find it.
+    #[derive(Debug)]
+    pub struct SessionStream {
+        #[pin]
+        stream: TimeoutStream<MuxStream>,
+        session: Arc<MuxSession>,
+        // when the pending read and write started waiting
+        read_since: Option<Instant>,
+        write_since: Option<Instant>,
+    }
+}
+
+/// A peer that is just quiet times a stream out too, the connection still
+/// gets the data and window updates of the other streams then.
+fn check_poll<T>(
+    r: Poll<io::Result<T>>,
+    session: &MuxSession,
+    since: &mut Option<Instant>,
+) -> Poll<io::Result<T>> {
+    match &r {
+        Poll::Pending => {
+            since.get_or_insert_with(Instant::now);
+        }
+        Poll::Ready(Err(e)) => {
+            let silent = since.is_some_and(|since| session.silent_since(since));
+            if e.kind() != io::ErrorKind::TimedOut || silent {
+                session.close();
+            }
+            *since = None;
+        }
+        Poll::Ready(Ok(_)) => *since = None,
+    }
+    r
+}
+
+impl AsyncRead for SessionStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let r = this.stream.poll_read(cx, buf);
+        check_poll(r, this.session, this.read_since)
+    }
+}
+
+impl AsyncWrite for SessionStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let r = this.stream.poll_write(cx, buf);
+        check_poll(r, this.session, this.write_since)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let r = this.stream.poll_flush(cx);
+        check_poll(r, this.session, this.write_since)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let r = this.stream.poll_shutdown(cx);
+        check_poll(r, this.session, this.write_since)
+    }
+}
+
+#[derive(Debug)]
+pub struct Connector {
+    next_layer: super::Connector,
+    pool: Pool<MuxSession>,
+    read_timeout: Duration,
+    write_timeout: Duration,
+}
+
+impl Connector {
+    pub async fn new(
+        config: ConnectorConfig,
+        transport: &TransportConfig,
+    ) -> Result<Self, anyhow::Error> {
+        Ok(Self {
+            next_layer: super::Connector::new(config.next_layer).await?,
+            pool: Pool::new(transport.max_streams, transport.idle_timeout),
+            read_timeout: transport.read_timeout,
+            write_timeout: transport.write_timeout,
+        })
+    }
+
+    fn watch(&self, stream: MuxStream, session: Arc<MuxSession>) -> SessionStream {
+        let mut stream = TimeoutStream::new(stream);
+        stream.set_read_timeout(Some(self.read_timeout));
+        stream.set_write_timeout(Some(self.write_timeout));
+        SessionStream {
+            stream,
+            session,
+            read_since: None,
+            write_since: None,
+        }
+    }
+
+    #[instrument(skip_all, fields(transport = "mux"))]
+    pub async fn connect(&self) -> Result<Leased<SessionStream>, anyhow::Error> {
+        while let Some((session, lease)) = self.pool.acquire() {
+            match session.open().await {
+                Ok(stream) => return Ok(Leased::new(self.watch(stream, session), lease)),
+                Err(e) => {
+                    warn!("open stream failed: {:?}", e);
+                    session.close();
+                }
+            }
+        }
+
+        // streams have their own timeouts, the connection may stay silent
+        // while it's idle
+        let io = self.next_layer.connect_raw().await?;
+        let (session, lease) = self.pool.insert(MuxSession::new(io));
+        let stream = session.open().await?;
+        Ok(Leased::new(self.watch(stream, session), lease))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use crate::config::transport::{
+        tcp, AcceptorConfig as Layer, ConnectorConfig as Next, ConnectorConfigInner, Endpoint,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn streams_share_connections() {
+        let listen = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let acceptor = Acceptor::new(AcceptorConfig {
+            next_layer: Layer::Tcp(tcp::AcceptorConfig { listen }),
+        })
+        .await
+        .unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = acceptor.accept().await {
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 5];
+                    stream.read_exact(&mut buf).await.unwrap();
+                    stream.write_all(&buf).await.unwrap();
+                });
+            }
+        });
+
+        let transport = TransportConfig {
+            max_streams: 2,
+            idle_timeout: Duration::from_secs(60),
+            ..Default::default()
+        };
+        let connector = Connector::new(
+            ConnectorConfig {
+                next_layer: Next {
+                    inner: ConnectorConfigInner::Tcp(tcp::ConnectorConfig {
+                        endpoint: Endpoint::Single {
+                            address: listen.ip().to_string(),
+                            port: listen.port(),
+                        },
+                        fwmark: None,
+                    }),
+                    transport: transport.clone(),
+                },
+            },
+            &transport,
+        )
+        .await
+        .unwrap();
+
+        let mut streams = Vec::new();
+        for i in 0..3u8 {
+            let mut stream = connector.connect().await.unwrap();
+            stream.write_all(&[i; 5]).await.unwrap();
+            streams.push(stream);
+        }
+        for (i, stream) in streams.iter_mut().enumerate() {
+            let mut buf = [0u8; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, [i as u8; 5]);
+        }
+        assert_eq!(connector.pool.len(), 2);
+
+        drop(streams);
+        connector.connect().await.unwrap();
+        assert_eq!(connector.pool.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn close_dead_sessions() {
+        // takes the connection but never speaks yamux
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listen = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let _conn = listener.accept().await.unwrap();
+            std::future::pending::<()>().await;
+        });
+
+        let transport = TransportConfig {
+            read_timeout: Duration::from_millis(100),
+            max_streams: 2,
+            ..Default::default()
+        };
+        let connector = Connector::new(
+            ConnectorConfig {
+                next_layer: Next {
+                    inner: ConnectorConfigInner::Tcp(tcp::ConnectorConfig {
+                        endpoint: Endpoint::Single {
+                            address: listen.ip().to_string(),
+                            port: listen.port(),
+                        },
+                        fwmark: None,
+                    }),
+                    transport: transport.clone(),
+                },
+            },
+            &transport,
+        )
+        .await
+        .unwrap();
+
+        let mut stream = connector.connect().await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        assert!(connector.pool.has_room());
+        let mut buf = [0u8; 5];
+        let e = stream.read_exact(&mut buf).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        assert!(!connector.pool.has_room());
+    }
+}
@@ -1,9 +1,12 @@
-use std::{pin::Pin, task, io, sync::Arc, net::SocketAddr};
+use std::{pin::Pin, task, io, net::SocketAddr};
 
 use quinn::{RecvStream, SendStream, VarInt};
 use tokio::io::{AsyncWrite, AsyncRead};
 
-use crate::net::transport::Connect;
+use crate::net::{
+    tool::{dynamic_port::NoRoom, pool::Leased},
+    transport::Connect,
+};
 
 use super::{ConnectorInner, record_stats};
 
@@ -59,36 +62,32 @@ impl AsyncRead for BiStream {
     }
 }
 
-impl Connect<BiStream> for ConnectorInner {
+impl Connect<Leased<BiStream>> for ConnectorInner {
     async fn is_open(&self) -> bool {
-        let conn = self.conn.read().await;
-        if let Some(conn) = conn.as_ref() {
-            conn.close_reason().is_none()
-        } else {
-            false
-        }
+        self.pool.has_room()
     }
 
     async fn connect(&self, endpoint: SocketAddr) -> Result<(), anyhow::Error> {
-        let conn = Arc::new(self.endpoint.connect(endpoint, &self.server_name)?.await?);
-
-        record_stats(self.stats.clone(), conn.clone());
+        let conn = self.endpoint.connect(endpoint, &self.server_name)?.await?;
 
-        *self.conn.write().await = Some(conn);
+        let (conn, _) = self.pool.insert(conn);
+        record_stats(self.stats.clone(), conn);
 
         Ok(())
     }
 
-    async fn open_stream(&self) -> Result<BiStream, anyhow::Error> {
-        self.open_bistream().await
+    async fn open_stream(&self) -> Result<Leased<BiStream>, anyhow::Error> {
+        let (conn, lease) = self.pool.acquire().ok_or(NoRoom)?;
+        match BiStream::open(&conn).await {
+            Ok(s) => Ok(Leased::new(s, lease)),
+            Err(e) => {
+                conn.close(VarInt::from_u32(0), &[]);
+                Err(e)
+            }
+        }
     }
 
     async fn close(&self) {
-        if !self.is_open().await {
-            return;
-        }
-        if let Some(conn) = self.conn.write().await.as_mut() {
-            conn.close(VarInt::from_u32(0), &[]);
-        }
+        self.pool.remove_closed();
     }
 }
@@ -2,15 +2,20 @@ mod bistream;
 
 use anyhow::Context;
 use quinn::{congestion::{BbrConfig, CubicConfig, NewRenoConfig}, rustls};
-use std::{sync::Arc, time::Duration};
-use tokio::sync::{mpsc, Mutex, RwLock};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tokio::sync::{mpsc, Mutex};
 use tracing::{debug_span, error, info, instrument, warn, Instrument};
 
 use crate::{
-    config::transport::quic::{
-        AcceptorConfig, CongestionType, ConnectorConfig, StatsConfig, TransportConfig,
+    config::transport::{
+        self,
+        quic::{AcceptorConfig, CongestionType, ConnectorConfig, StatsConfig, TransportConfig},
+    },
+    net::tool::{
+        dynamic_port,
+        mark::set_mark,
+        pool::{Leased, Pool, Session},
     },
-    net::tool::dynamic_port,
 };
 
 use self::bistream::BiStream;
@@ -71,7 +76,7 @@ fn record_stats(stats: Option<StatsConfig>, conn: Arc<quinn::Connection>) {
 #[derive(Debug)]
 pub struct Acceptor {
     _endpoint: quinn::Endpoint,
-    sock_rx: Mutex<mpsc::UnboundedReceiver<BiStream>>,
+    sock_rx: Mutex<mpsc::UnboundedReceiver<(BiStream, SocketAddr)>>,
 }
 
 impl Acceptor {
@@ -96,14 +101,14 @@ impl Acceptor {
         })
     }
 
-    pub async fn accept(&self) -> Result<BiStream, anyhow::Error> {
+    pub async fn accept(&self) -> Result<(BiStream, SocketAddr), anyhow::Error> {
         let mut rx = self.sock_rx.lock().await;
         rx.recv().await.context("Failed to accpet")
     }
 
     #[instrument(skip_all)]
     pub async fn run(
-        tx: mpsc::UnboundedSender<BiStream>,
+        tx: mpsc::UnboundedSender<(BiStream, SocketAddr)>,
         endpoint: quinn::Endpoint,
         stats: Option<StatsConfig>,
     ) {
@@ -127,7 +132,7 @@ impl Acceptor {
                     loop {
                         match BiStream::accept(&conn).await {
                             Ok(s) => {
-                                if let Err(e) = tx.send(s) {
+                                if let Err(e) = tx.send((s, conn.remote_address())) {
                                     warn!("{:?}", e);
                                 }
                             }
@@ -144,59 +149,82 @@ impl Acceptor {
     }
 }
 
+impl Session for quinn::Connection {
+    fn is_closed(&self) -> bool {
+        self.close_reason().is_some()
+    }
+
+    fn close(&self) {
+        quinn::Connection::close(self, quinn::VarInt::from_u32(0), &[]);
+    }
+}
+
 #[derive(Debug)]
 pub struct Connector {
-    inner: dynamic_port::Connector<ConnectorInner, BiStream>,
+    inner: dynamic_port::Connector<ConnectorInner, Leased<BiStream>>,
 }
 
 impl Connector {
-    pub async fn new(config: ConnectorConfig) -> Result<Self, anyhow::Error> {
+    pub async fn new(
+        config: ConnectorConfig,
+        limits: &transport::TransportConfig,
+    ) -> Result<Self, anyhow::Error> {
         let endpoint = config.endpoint.clone();
         Ok(Self {
-            inner: dynamic_port::Connector::new(ConnectorInner::new(config).await?, endpoint)
-                .await?,
+            inner: dynamic_port::Connector::new(
+                ConnectorInner::new(config, limits).await?,
+                endpoint,
+            )
+            .await?,
         })
     }
 
     #[instrument(skip_all, fields(transport = "quic"))]
-    pub async fn connect(&self) -> Result<BiStream, anyhow::Error> {
+    pub async fn connect(&self) -> Result<Leased<BiStream>, anyhow::Error> {
         self.inner.connect().await
     }
 }
 
+/// Streams are opened on the connections of `pool`, another connection is
+/// made when all of them are full.
 #[derive(Debug)]
 pub struct ConnectorInner {
     endpoint: quinn::Endpoint,
-    conn: RwLock<Option<Arc<quinn::Connection>>>,
+    pool: Pool<quinn::Connection>,
     server_name: String,
     stats: Option<StatsConfig>,
 }
 
 impl ConnectorInner {
-    async fn new(config: ConnectorConfig) -> Result<Self, anyhow::Error> {
+    async fn new(
+        config: ConnectorConfig,
+        limits: &transport::TransportConfig,
+    ) -> Result<Self, anyhow::Error> {
         let tls_config = rustls::ClientConfig::try_from(&config.tls)?;
 
         let mut quic_config = quinn::ClientConfig::new(Arc::new(tls_config));
         quic_config.transport_config(Arc::new(quinn::TransportConfig::from(config.transport)));
 
-        let mut endpoint = quinn::Endpoint::client(config.local.clone())?;
+        let mut endpoint = match config.fwmark {
+            None => quinn::Endpoint::client(config.local)?,
+            Some(mark) => {
+                let socket = std::net::UdpSocket::bind(config.local)?;
+                set_mark(&socket, mark)?;
+                quinn::Endpoint::new(
+                    quinn::EndpointConfig::default(),
+                    None,
+                    socket,
+                    quinn::default_runtime().context("No async runtime found")?,
+                )?
+            }
+        };
         endpoint.set_default_client_config(quic_config.clone());
 
         Ok(Self {
             endpoint,
-            conn: RwLock::new(None),
+            pool: Pool::new(limits.max_streams, limits.idle_timeout),
             server_name: config.server_name.to_string(),
             stats: config.stats,
         })
     }
 }
-
-impl ConnectorInner {
-    async fn open_bistream(&self) -> Result<BiStream, anyhow::Error> {
-        if let Some(conn) = self.conn.read().await.as_ref() {
-            BiStream::open(conn).await
-        } else {
-            anyhow::bail!("connection is invalid")
-        }
-    }
-}
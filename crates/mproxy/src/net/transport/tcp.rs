@@ -1,12 +1,15 @@
 use std::net::SocketAddr;
 
 use tokio::{
-    net::{TcpListener, TcpStream},
+    net::{TcpListener, TcpSocket, TcpStream},
     sync::RwLock,
 };
 use tracing::{info, instrument};
 
-use crate::{config::transport::tcp::{AcceptorConfig, ConnectorConfig}, net::tool::dynamic_port};
+use crate::{
+    config::transport::tcp::{AcceptorConfig, ConnectorConfig},
+    net::tool::{dynamic_port, mark::set_mark},
+};
 
 use super::Connect;
 
@@ -23,9 +26,8 @@ impl Acceptor {
         })
     }
 
-    pub async fn accept(&self) -> Result<TcpStream, anyhow::Error> {
-        let (s, _) = self.listener.accept().await?;
-        Ok(s)
+    pub async fn accept(&self) -> Result<(TcpStream, SocketAddr), anyhow::Error> {
+        Ok(self.listener.accept().await?)
     }
 }
 
@@ -37,7 +39,11 @@ pub struct Connector {
 impl Connector {
     pub async fn new(config: ConnectorConfig) -> Result<Self, anyhow::Error> {
         Ok(Self {
-            inner: dynamic_port::Connector::new(ConnectorInner::new(), config.endpoint).await?,
+            inner: dynamic_port::Connector::new(
+                ConnectorInner::new(config.fwmark),
+                config.endpoint,
+            )
+            .await?,
         })
     }
 
@@ -50,12 +56,14 @@ impl Connector {
 #[derive(Debug)]
 struct ConnectorInner {
     endpoint: RwLock<Option<SocketAddr>>,
+    fwmark: Option<u32>,
 }
 
 impl ConnectorInner {
-    fn new() -> Self {
+    fn new(fwmark: Option<u32>) -> Self {
         Self {
             endpoint: RwLock::new(None),
+            fwmark,
         }
     }
 }
@@ -72,7 +80,15 @@ impl Connect<TcpStream> for ConnectorInner {
 
     async fn open_stream(&self) -> Result<TcpStream, anyhow::Error> {
         if let Some(endpoint) = *self.endpoint.read().await {
-            Ok(TcpStream::connect(endpoint).await?)
+            let Some(mark) = self.fwmark else {
+                return Ok(TcpStream::connect(endpoint).await?);
+            };
+            let socket = match endpoint {
+                SocketAddr::V4(_) => TcpSocket::new_v4()?,
+                SocketAddr::V6(_) => TcpSocket::new_v6()?,
+            };
+            set_mark(&socket, mark)?;
+            Ok(socket.connect(endpoint).await?)
         } else {
             anyhow::bail!("connection is invalid")
         }
@@ -1,4 +1,4 @@
-use std::{sync::Arc, time::Duration};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 
 use rustls_pki_types::ServerName;
 use tokio::time::timeout;
@@ -33,7 +33,7 @@ impl Acceptor {
         })
     }
 
-    pub async fn accept(&self) -> Result<BoxedAsyncIO, anyhow::Error> {
+    pub async fn accept(&self) -> Result<(BoxedAsyncIO, SocketAddr), anyhow::Error> {
         Ok(self.next_layer.accept().await?)
     }
 
@@ -89,7 +89,8 @@ impl Connector {
             self.handshake_timeout.clone(),
             self.connector.connect(
                 ServerName::try_from(self.server_name.clone())?,
-                self.next_layer.connect().await?,
+                // the tls stream gets the timeouts of this layer
+                self.next_layer.connect_raw().await?,
             ),
         )
         .await??)
@@ -0,0 +1,330 @@
+use std::{
+    io,
+    net::{Ipv6Addr, SocketAddr},
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use futures::{Sink, Stream};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::timeout,
+};
+use tokio_tungstenite::{
+    tungstenite::{
+        client::IntoClientRequest,
+        handshake::server::{ErrorResponse, Request, Response},
+        http::{header::HOST, HeaderValue, StatusCode},
+        Message,
+    },
+    WebSocketStream,
+};
+use tracing::instrument;
+
+use crate::{
+    config::transport::ws::{AcceptorConfig, ConnectorConfig},
+    io::BoxedAsyncIO,
+};
+
+/// The bytes of binary messages as a stream, every write is sent as one
+/// message.
+pub struct WsStream {
+    inner: WebSocketStream<BoxedAsyncIO>,
+    /// rest of the last message not taken by reads
+    read_buf: Vec<u8>,
+    read_pos: usize,
+}
+
+impl WsStream {
+    fn new(inner: WebSocketStream<BoxedAsyncIO>) -> Self {
+        Self {
+            inner,
+            read_buf: Vec::new(),
+            read_pos: 0,
+        }
+    }
+}
+
+impl std::fmt::Debug for WsStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WsStream")
+            .field("buffered", &(self.read_buf.len() - self.read_pos))
+            .finish()
+    }
+}
+
+fn io_error(e: tokio_tungstenite::tungstenite::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+impl AsyncRead for WsStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        while self.read_pos == self.read_buf.len() {
+            match ready!(Pin::new(&mut self.inner).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => {
+                    self.read_buf = data;
+                    self.read_pos = 0;
+                }
+                // pings are answered by tungstenite
+                Some(Ok(Message::Ping(_) | Message::Pong(_))) => {}
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                Some(Ok(_)) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "only binary messages are expected",
+                    )))
+                }
+                Some(Err(e)) => return Poll::Ready(Err(io_error(e))),
+            }
+        }
+
+        let n = buf.remaining().min(self.read_buf.len() - self.read_pos);
+        let pos = self.read_pos;
+        buf.put_slice(&self.read_buf[pos..pos + n]);
+        self.read_pos += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for WsStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        ready!(Pin::new(&mut self.inner).poll_ready(cx)).map_err(io_error)?;
+        Pin::new(&mut self.inner)
+            .start_send(Message::Binary(buf.to_vec()))
+            .map_err(io_error)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        Pin::new(&mut self.inner).poll_flush(cx).map_err(io_error)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), io::Error>> {
+        Pin::new(&mut self.inner).poll_close(cx).map_err(io_error)
+    }
+}
+
+#[derive(Debug)]
+pub struct Acceptor {
+    next_layer: super::Acceptor,
+    path: String,
+    handshake_timeout: Duration,
+}
+
+impl Acceptor {
+    pub async fn new(config: AcceptorConfig) -> Result<Self, anyhow::Error> {
+        Ok(Self {
+            next_layer: super::Acceptor::new(config.next_layer).await?,
+            path: config.path,
+            handshake_timeout: config.handshake_timeout,
+        })
+    }
+
+    pub async fn accept(&self) -> Result<(BoxedAsyncIO, SocketAddr), anyhow::Error> {
+        self.next_layer.accept().await
+    }
+
+    pub async fn handshake(&self, io: BoxedAsyncIO) -> Result<WsStream, anyhow::Error> {
+        let io = self.next_layer.handshake(io).await?;
+        // the error type is given by tungstenite
+        #[allow(clippy::result_large_err)]
+        let check_path = |req: &Request, resp: Response| {
+            if req.uri().path() == self.path {
+                Ok(resp)
+            } else {
+                let mut resp = ErrorResponse::new(None);
+                *resp.status_mut() = StatusCode::NOT_FOUND;
+                Err(resp)
+            }
+        };
+        let stream = timeout(
+            self.handshake_timeout,
+            tokio_tungstenite::accept_hdr_async(io, check_path),
+        )
+        .await??;
+        Ok(WsStream::new(stream))
+    }
+}
+
+pub struct Connector {
+    next_layer: super::Connector,
+    url: String,
+    host: HeaderValue,
+
+    handshake_timeout: Duration,
+}
+
+impl std::fmt::Debug for Connector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Connector")
+            .field("next_layer", &self.next_layer)
+            .field("url", &self.url)
+            .finish()
+    }
+}
+
+impl Connector {
+    pub async fn new(
+        ConnectorConfig {
+            next_layer,
+            path,
+            host,
+            handshake_timeout,
+        }: ConnectorConfig,
+    ) -> Result<Self, anyhow::Error> {
+        let host = host.unwrap_or(next_layer.inner.endpoint().address().to_string());
+        let host = match host.parse::<Ipv6Addr>() {
+            Ok(_) => format!("[{}]", host),
+            Err(_) => host,
+        };
+
+        Ok(Self {
+            url: format!("ws://{}{}", host, path),
+            host: HeaderValue::from_str(&host)?,
+            next_layer: super::Connector::new(next_layer).await?,
+            handshake_timeout,
+        })
+    }
+
+    #[instrument(skip_all, fields(transport = "ws"))]
+    pub async fn connect(&self) -> Result<WsStream, anyhow::Error> {
+        let mut request = self.url.as_str().into_client_request()?;
+        request.headers_mut().insert(HOST, self.host.clone());
+
+        // the websocket stream gets the timeouts of this layer
+        let io = self.next_layer.connect_raw().await?;
+        let (stream, _) = timeout(
+            self.handshake_timeout,
+            tokio_tungstenite::client_async(request, io),
+        )
+        .await??;
+        Ok(WsStream::new(stream))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use crate::config::transport::{
+        tcp, AcceptorConfig as Layer, ConnectorConfig as Next, ConnectorConfigInner, Endpoint,
+        TransportConfig,
+    };
+
+    use super::*;
+
+    async fn acceptor(path: &str) -> (Acceptor, SocketAddr) {
+        let listen = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let acceptor = Acceptor::new(AcceptorConfig {
+            next_layer: Layer::Tcp(tcp::AcceptorConfig { listen }),
+            path: path.to_string(),
+            handshake_timeout: Duration::from_secs(5),
+        })
+        .await
+        .unwrap();
+        (acceptor, listen)
+    }
+
+    async fn connector(listen: SocketAddr, path: &str) -> Connector {
+        Connector::new(ConnectorConfig {
+            next_layer: Next {
+                inner: ConnectorConfigInner::Tcp(tcp::ConnectorConfig {
+                    endpoint: Endpoint::Single {
+                        address: listen.ip().to_string(),
+                        port: listen.port(),
+                    },
+                    fwmark: None,
+                }),
+                transport: TransportConfig::default(),
+            },
+            path: path.to_string(),
+            host: Some("example.com".to_string()),
+            handshake_timeout: Duration::from_secs(5),
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn echo() {
+        let (acceptor, listen) = acceptor("/tunnel").await;
+        tokio::spawn(async move {
+            let (io, _) = acceptor.accept().await.unwrap();
+            let mut stream = acceptor.handshake(io).await.unwrap();
+            let mut buf = [0u8; 11];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+            stream.shutdown().await.unwrap();
+        });
+
+        let mut stream = connector(listen, "/tunnel").await.connect().await.unwrap();
+        stream.write_all(b"hello ").await.unwrap();
+        stream.write_all(b"world").await.unwrap();
+        stream.flush().await.unwrap();
+        let mut echoed = Vec::new();
+        stream.read_to_end(&mut echoed).await.unwrap();
+        assert_eq!(echoed, b"hello world");
+    }
+
+    #[tokio::test]
+    async fn wrong_path() {
+        let (acceptor, listen) = acceptor("/tunnel").await;
+        tokio::spawn(async move {
+            let (io, _) = acceptor.accept().await.unwrap();
+            assert!(acceptor.handshake(io).await.is_err());
+        });
+
+        assert!(connector(listen, "/other").await.connect().await.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn silent_client() {
+        let (acceptor, listen) = acceptor("/tunnel").await;
+        let _client = TcpStream::connect(listen).await.unwrap();
+        let (io, _) = acceptor.accept().await.unwrap();
+        let e = acceptor.handshake(io).await.unwrap_err();
+        assert!(e.is::<tokio::time::error::Elapsed>());
+    }
+
+    #[tokio::test]
+    async fn ipv6_url() {
+        let connector = Connector::new(ConnectorConfig {
+            next_layer: Next {
+                inner: ConnectorConfigInner::Tcp(tcp::ConnectorConfig {
+                    endpoint: Endpoint::Single {
+                        address: "::1".to_string(),
+                        port: 80,
+                    },
+                    fwmark: None,
+                }),
+                transport: TransportConfig::default(),
+            },
+            path: "/tunnel".to_string(),
+            host: None,
+            handshake_timeout: Duration::from_secs(5),
+        })
+        .await
+        .unwrap();
+        assert_eq!(connector.url, "ws://[::1]/tunnel");
+        assert_eq!(connector.host, "[::1]");
+    }
+}
@@ -1,3 +1,7 @@
+mod stack;
+
+pub use stack::Stack;
+
 cfg_if::cfg_if! {
     if #[cfg(target_family = "unix")] {
         mod unix;
@@ -0,0 +1,793 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use ipnet::IpNet;
+use smoltcp::{
+    iface::{Config, Interface, SocketHandle, SocketSet},
+    phy::{self, ChecksumCapabilities, Device, DeviceCapabilities, Medium},
+    socket::tcp,
+    time::Instant,
+    wire::{
+        HardwareAddress, IpAddress, IpCidr, IpEndpoint, IpProtocol, IpVersion, Ipv4Packet,
+        Ipv4Repr, Ipv6Packet, Ipv6Repr, TcpPacket, UdpPacket as UdpDatagram, UdpRepr,
+    },
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream},
+    sync::{
+        mpsc::{
+            self,
+            error::{TryRecvError, TrySendError},
+        },
+        Notify,
+    },
+};
+use tracing::{debug, warn};
+
+use crate::proxy::{ProxyConn, ProxyRequest, TcpForwarder, UdpForwarder, UdpPacket};
+
+const TCP_BUFFER_SIZE: usize = 64 * 1024;
+const TCP_CHANNEL_SIZE: usize = 16;
+const UDP_CHANNEL_SIZE: usize = 64;
+const MAX_POLL_DELAY: Duration = Duration::from_secs(1);
+/// Each flow holds two socket buffers, so the number of flows is bounded.
+const MAX_TCP_FLOWS: usize = 4096;
+/// Flows that never complete the handshake are dropped after this.
+const TCP_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A datagram sent back to the client, from the original destination.
+type UdpReply = (SocketAddr, SocketAddr, Vec<u8>);
+
+/// Packet queues between the tun device and smoltcp.
+struct QueueDevice {
+    rx: VecDeque<Vec<u8>>,
+    tx: VecDeque<Vec<u8>>,
+    mtu: usize,
+}
+
+struct RxToken(Vec<u8>);
+
+impl phy::RxToken for RxToken {
+    fn consume<R, F>(mut self, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        f(&mut self.0)
+    }
+}
+
+struct TxToken<'a>(&'a mut VecDeque<Vec<u8>>);
+
+impl<'a> phy::TxToken for TxToken<'a> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut buf = vec![0u8; len];
+        let result = f(&mut buf);
+        self.0.push_back(buf);
+        result
+    }
+}
+
+impl Device for QueueDevice {
+    type RxToken<'a> = RxToken where Self: 'a;
+    type TxToken<'a> = TxToken<'a> where Self: 'a;
+
+    fn receive(&mut self, _: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        self.rx
+            .pop_front()
+            .map(|packet| (RxToken(packet), TxToken(&mut self.tx)))
+    }
+
+    fn transmit(&mut self, _: Instant) -> Option<Self::TxToken<'_>> {
+        Some(TxToken(&mut self.tx))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.medium = Medium::Ip;
+        caps.max_transmission_unit = self.mtu;
+        caps
+    }
+}
+
+struct TcpFlow {
+    key: (SocketAddr, SocketAddr),
+    created: tokio::time::Instant,
+    dispatched: bool,
+    /// data received from the client, written to the proxied stream
+    to_app: Option<mpsc::Sender<Vec<u8>>>,
+    /// data read from the proxied stream, sent to the client
+    from_app: Option<mpsc::Receiver<Vec<u8>>>,
+    pending: Vec<u8>,
+}
+
+/// A userspace TCP/IP stack terminating the flows captured by a tun device.
+///
+/// TCP is terminated by smoltcp with `any_ip` enabled, so every destination
+/// looks local to the stack. UDP never enters smoltcp, datagrams are parsed
+/// and built directly.
+pub struct Stack {
+    iface: Interface,
+    device: QueueDevice,
+    sockets: SocketSet<'static>,
+    tcp_flows: HashMap<SocketHandle, TcpFlow>,
+    tcp_keys: HashMap<(SocketAddr, SocketAddr), SocketHandle>,
+    udp_sessions: HashMap<(SocketAddr, SocketAddr), mpsc::Sender<UdpPacket>>,
+    udp_reply: (mpsc::Sender<UdpReply>, mpsc::Receiver<UdpReply>),
+    notify: Arc<Notify>,
+    allow_udp: bool,
+}
+
+impl Stack {
+    pub fn new(address: &[IpNet], mtu: usize, allow_udp: bool) -> Result<Self, anyhow::Error> {
+        let mut device = QueueDevice {
+            rx: VecDeque::new(),
+            tx: VecDeque::new(),
+            mtu,
+        };
+
+        let mut config = Config::new(HardwareAddress::Ip);
+        config.random_seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or_default();
+
+        let mut iface = Interface::new(config, &mut device, Instant::now());
+        iface.set_any_ip(true);
+        iface.update_ip_addrs(|addrs| {
+            for net in address {
+                addrs
+                    .push(IpCidr::new(net.addr().into(), net.prefix_len()))
+                    .ok();
+            }
+        });
+        // any_ip only accepts destinations routed through one of our own addresses
+        for net in address {
+            match net.addr() {
+                IpAddr::V4(addr) => iface
+                    .routes_mut()
+                    .add_default_ipv4_route(addr.into())
+                    .context("Failed to add ipv4 route")?,
+                IpAddr::V6(addr) => iface
+                    .routes_mut()
+                    .add_default_ipv6_route(addr.into())
+                    .context("Failed to add ipv6 route")?,
+            };
+        }
+
+        Ok(Self {
+            iface,
+            device,
+            sockets: SocketSet::new(vec![]),
+            tcp_flows: HashMap::new(),
+            tcp_keys: HashMap::new(),
+            udp_sessions: HashMap::new(),
+            udp_reply: mpsc::channel(UDP_CHANNEL_SIZE),
+            notify: Arc::new(Notify::new()),
+            allow_udp,
+        })
+    }
+
+    pub async fn run<T>(
+        mut self,
+        mut io: T,
+        tx: mpsc::UnboundedSender<ProxyRequest>,
+    ) -> Result<(), anyhow::Error>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut buf = vec![0u8; self.device.mtu.max(1500)];
+        let notify = self.notify.clone();
+
+        loop {
+            self.iface
+                .poll(Instant::now(), &mut self.device, &mut self.sockets);
+            self.pump_tcp(&tx);
+            self.iface
+                .poll(Instant::now(), &mut self.device, &mut self.sockets);
+
+            while let Some(packet) = self.device.tx.pop_front() {
+                io.write_all(&packet).await?;
+            }
+
+            let delay = self
+                .iface
+                .poll_delay(Instant::now(), &self.sockets)
+                .map(Duration::from)
+                .unwrap_or(MAX_POLL_DELAY)
+                .min(MAX_POLL_DELAY);
+
+            tokio::select! {
+                _ = tx.closed() => return Ok(()),
+                n = io.read(&mut buf) => {
+                    let n = n?;
+                    if n == 0 {
+                        return Ok(());
+                    }
+                    self.input(&buf[..n], &tx);
+                }
+                Some((src, dst, data)) = self.udp_reply.1.recv() => {
+                    match build_udp_packet(src, dst, &data) {
+                        Some(packet) => io.write_all(&packet).await?,
+                        None => debug!("drop udp reply from {} to {}", src, dst),
+                    }
+                }
+                _ = notify.notified() => {}
+                _ = tokio::time::sleep(delay) => {}
+            }
+        }
+    }
+
+    fn input(&mut self, packet: &[u8], tx: &mpsc::UnboundedSender<ProxyRequest>) {
+        let Some((src, dst, protocol, payload)) = parse_ip(packet) else {
+            return;
+        };
+
+        match protocol {
+            IpProtocol::Tcp => {
+                if let Ok(tcp) = TcpPacket::new_checked(payload) {
+                    if tcp.syn() && !tcp.ack() {
+                        self.listen(
+                            SocketAddr::new(src, tcp.src_port()),
+                            SocketAddr::new(dst, tcp.dst_port()),
+                        );
+                    }
+                }
+                self.device.rx.push_back(packet.to_vec());
+            }
+            IpProtocol::Udp => {
+                if !self.allow_udp {
+                    return;
+                }
+                let Ok(udp) = UdpDatagram::new_checked(payload) else {
+                    return;
+                };
+                let src = SocketAddr::new(src, udp.src_port());
+                let dst = SocketAddr::new(dst, udp.dst_port());
+                if let Err(e) = self.dispatch_udp_packet(tx, src, dst, udp.payload()) {
+                    warn!("{:?}", e);
+                }
+            }
+            _ => self.device.rx.push_back(packet.to_vec()),
+        }
+    }
+
+    /// Opens a listening socket for a new flow, smoltcp only accepts a SYN
+    /// when a socket is listening on its destination.
+    fn listen(&mut self, src: SocketAddr, dst: SocketAddr) {
+        if self.tcp_keys.contains_key(&(src, dst)) {
+            return;
+        }
+        if self.tcp_flows.len() >= MAX_TCP_FLOWS {
+            debug!("drop tcp flow from {} to {}, too many flows", src, dst);
+            return;
+        }
+
+        let mut socket = tcp::Socket::new(
+            tcp::SocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+            tcp::SocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+        );
+        if let Err(e) = socket.listen(dst) {
+            warn!("listen on {} error: {:?}", dst, e);
+            return;
+        }
+
+        let handle = self.sockets.add(socket);
+        self.tcp_keys.insert((src, dst), handle);
+        self.tcp_flows.insert(
+            handle,
+            TcpFlow {
+                key: (src, dst),
+                created: tokio::time::Instant::now(),
+                dispatched: false,
+                to_app: None,
+                from_app: None,
+                pending: vec![],
+            },
+        );
+    }
+
+    fn pump_tcp(&mut self, tx: &mpsc::UnboundedSender<ProxyRequest>) {
+        let mut closed = vec![];
+
+        for (handle, flow) in self.tcp_flows.iter_mut() {
+            let socket = self.sockets.get_mut::<tcp::Socket>(*handle);
+
+            if !flow.dispatched {
+                match socket.state() {
+                    tcp::State::Listen | tcp::State::SynReceived => {
+                        if flow.created.elapsed() >= TCP_HANDSHAKE_TIMEOUT {
+                            socket.abort();
+                            closed.push(*handle);
+                        }
+                        continue;
+                    }
+                    tcp::State::Established => {
+                        let Some(dst) = socket.local_endpoint().map(to_socket_addr) else {
+                            continue;
+                        };
+                        let stream = flow.dispatch(self.notify.clone());
+                        if let Err(e) = tx.send(ProxyRequest {
+                            remote: dst.into(),
+                            source: socket.remote_endpoint().map(to_socket_addr),
+                            route: None,
+                            conn: ProxyConn::ForwardTcp(TcpForwarder {
+                                stream: Box::new(stream),
+                            }),
+                        }) {
+                            warn!("{:?}", e);
+                            socket.abort();
+                        }
+                    }
+                    _ => {
+                        closed.push(*handle);
+                        continue;
+                    }
+                }
+            }
+
+            // client -> proxied stream, data stays in the socket while the
+            // channel is full so the tcp window applies back pressure
+            if let Some(to_app) = &flow.to_app {
+                while socket.can_recv() {
+                    let permit = match to_app.try_reserve() {
+                        Ok(permit) => permit,
+                        Err(TrySendError::Full(_)) => break,
+                        Err(TrySendError::Closed(_)) => {
+                            socket.abort();
+                            break;
+                        }
+                    };
+                    match socket.recv(|buf| (buf.len(), buf.to_vec())) {
+                        Ok(data) => permit.send(data),
+                        Err(_) => break,
+                    }
+                }
+                if !socket.may_recv() && !socket.can_recv() {
+                    flow.to_app = None;
+                }
+            }
+
+            // proxied stream -> client
+            while let Some(from_app) = &mut flow.from_app {
+                if flow.pending.is_empty() {
+                    match from_app.try_recv() {
+                        Ok(data) => flow.pending = data,
+                        Err(TryRecvError::Empty) => break,
+                        Err(TryRecvError::Disconnected) => {
+                            flow.from_app = None;
+                            socket.close();
+                            break;
+                        }
+                    }
+                }
+                match socket.send_slice(&flow.pending) {
+                    Ok(n) => {
+                        flow.pending.drain(..n);
+                        if !flow.pending.is_empty() {
+                            break;
+                        }
+                    }
+                    Err(_) => {
+                        flow.from_app = None;
+                        socket.abort();
+                        break;
+                    }
+                }
+            }
+
+            if matches!(socket.state(), tcp::State::Closed | tcp::State::TimeWait) {
+                closed.push(*handle);
+            }
+        }
+
+        for handle in closed {
+            if let Some(flow) = self.tcp_flows.remove(&handle) {
+                self.tcp_keys.remove(&flow.key);
+            }
+            self.sockets.remove(handle);
+        }
+    }
+
+    fn dispatch_udp_packet(
+        &mut self,
+        tx: &mpsc::UnboundedSender<ProxyRequest>,
+        src: SocketAddr,
+        dst: SocketAddr,
+        data: &[u8],
+    ) -> Result<(), anyhow::Error> {
+        let mut packet = UdpPacket {
+            remote: dst.into(),
+            data: data.to_vec(),
+        };
+
+        if let Some(sender) = self.udp_sessions.get(&(src, dst)) {
+            match sender.try_send(packet) {
+                Ok(_) => return Ok(()),
+                Err(TrySendError::Full(_)) => {
+                    debug!("drop udp packet from {} to {}, session is busy", src, dst);
+                    return Ok(());
+                }
+                Err(TrySendError::Closed(v)) => packet = v,
+            }
+        }
+
+        self.udp_sessions.retain(|_, sender| !sender.is_closed());
+
+        let (sender, receiver) = mpsc::channel(UDP_CHANNEL_SIZE);
+        let (reply_tx, mut reply_rx) = mpsc::channel::<UdpPacket>(UDP_CHANNEL_SIZE);
+
+        sender
+            .try_send(packet)
+            .map_err(|e| anyhow::anyhow!("send error: {:?}", e))?;
+        self.udp_sessions.insert((src, dst), sender);
+
+        // replies must come from the original destination
+        let udp_reply = self.udp_reply.0.clone();
+        let notify = self.notify.clone();
+        tokio::spawn(async move {
+            while let Some(packet) = reply_rx.recv().await {
+                if udp_reply.send((dst, src, packet.data)).await.is_err() {
+                    break;
+                }
+                notify.notify_one();
+            }
+        });
+
+        tx.send(ProxyRequest {
+            remote: dst.into(),
+            source: Some(src),
+            route: None,
+            conn: ProxyConn::ForwardUdp(UdpForwarder::new(receiver, reply_tx)),
+        })
+        .map_err(|e| anyhow::anyhow!("send error: {:?}", e.0))
+    }
+}
+
+impl TcpFlow {
+    /// Bridges the flow to one half of a duplex stream, the other half is
+    /// handed to the egress.
+    fn dispatch(&mut self, notify: Arc<Notify>) -> DuplexStream {
+        let (stream, inner) = tokio::io::duplex(TCP_BUFFER_SIZE);
+        let (mut reader, mut writer) = tokio::io::split(inner);
+
+        let (to_app, mut to_app_rx) = mpsc::channel::<Vec<u8>>(TCP_CHANNEL_SIZE);
+        let (from_app_tx, from_app) = mpsc::channel(TCP_CHANNEL_SIZE);
+
+        let reader_notify = notify.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; TCP_BUFFER_SIZE];
+            loop {
+                match reader.read(&mut buf).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => {
+                        if from_app_tx.send(buf[..n].to_vec()).await.is_err() {
+                            break;
+                        }
+                        reader_notify.notify_one();
+                    }
+                }
+            }
+            drop(from_app_tx);
+            reader_notify.notify_one();
+        });
+
+        tokio::spawn(async move {
+            while let Some(data) = to_app_rx.recv().await {
+                if writer.write_all(&data).await.is_err() {
+                    break;
+                }
+                // the channel has room again
+                notify.notify_one();
+            }
+            drop(to_app_rx);
+            let _ = writer.shutdown().await;
+            notify.notify_one();
+        });
+
+        self.dispatched = true;
+        self.to_app = Some(to_app);
+        self.from_app = Some(from_app);
+        stream
+    }
+}
+
+fn to_socket_addr(endpoint: IpEndpoint) -> SocketAddr {
+    SocketAddr::new(endpoint.addr.into(), endpoint.port)
+}
+
+fn parse_ip(packet: &[u8]) -> Option<(IpAddr, IpAddr, IpProtocol, &[u8])> {
+    match IpVersion::of_packet(packet).ok()? {
+        IpVersion::Ipv4 => {
+            let packet = Ipv4Packet::new_checked(packet).ok()?;
+            Some((
+                IpAddress::Ipv4(packet.src_addr()).into(),
+                IpAddress::Ipv4(packet.dst_addr()).into(),
+                packet.next_header(),
+                packet.payload(),
+            ))
+        }
+        IpVersion::Ipv6 => {
+            let packet = Ipv6Packet::new_checked(packet).ok()?;
+            Some((
+                IpAddress::Ipv6(packet.src_addr()).into(),
+                IpAddress::Ipv6(packet.dst_addr()).into(),
+                packet.next_header(),
+                packet.payload(),
+            ))
+        }
+    }
+}
+
+fn build_udp_packet(src: SocketAddr, dst: SocketAddr, data: &[u8]) -> Option<Vec<u8>> {
+    let udp_repr = UdpRepr {
+        src_port: src.port(),
+        dst_port: dst.port(),
+    };
+    let payload_len = udp_repr.header_len() + data.len();
+    let caps = ChecksumCapabilities::default();
+    let src_addr = IpAddress::from(src.ip());
+    let dst_addr = IpAddress::from(dst.ip());
+
+    let mut buf;
+    let mut packet = match (src_addr, dst_addr) {
+        (IpAddress::Ipv4(src_addr), IpAddress::Ipv4(dst_addr)) => {
+            let ip_repr = Ipv4Repr {
+                src_addr,
+                dst_addr,
+                next_header: IpProtocol::Udp,
+                payload_len,
+                hop_limit: 64,
+            };
+            buf = vec![0u8; ip_repr.buffer_len() + payload_len];
+            let mut packet = Ipv4Packet::new_unchecked(&mut buf[..]);
+            ip_repr.emit(&mut packet, &caps);
+            UdpDatagram::new_unchecked(&mut buf[ip_repr.buffer_len()..])
+        }
+        (IpAddress::Ipv6(src_addr), IpAddress::Ipv6(dst_addr)) => {
+            let ip_repr = Ipv6Repr {
+                src_addr,
+                dst_addr,
+                next_header: IpProtocol::Udp,
+                payload_len,
+                hop_limit: 64,
+            };
+            buf = vec![0u8; ip_repr.buffer_len() + payload_len];
+            let mut packet = Ipv6Packet::new_unchecked(&mut buf[..]);
+            ip_repr.emit(&mut packet);
+            UdpDatagram::new_unchecked(&mut buf[ip_repr.buffer_len()..])
+        }
+        _ => return None,
+    };
+    udp_repr.emit(
+        &mut packet,
+        &src_addr,
+        &dst_addr,
+        data.len(),
+        |payload| payload.copy_from_slice(data),
+        &caps,
+    );
+    Some(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use smoltcp::wire::{TcpControl, TcpRepr, TcpSeqNumber};
+
+    use crate::proxy::NetLocation;
+
+    use super::*;
+
+    fn build_tcp_packet(src: SocketAddr, dst: SocketAddr, repr: &TcpRepr) -> Vec<u8> {
+        let (IpAddress::Ipv4(src_addr), IpAddress::Ipv4(dst_addr)) =
+            (IpAddress::from(src.ip()), IpAddress::from(dst.ip()))
+        else {
+            panic!("expected ipv4");
+        };
+        let ip_repr = Ipv4Repr {
+            src_addr,
+            dst_addr,
+            next_header: IpProtocol::Tcp,
+            payload_len: repr.buffer_len(),
+            hop_limit: 64,
+        };
+        let caps = ChecksumCapabilities::default();
+
+        let mut buf = vec![0u8; ip_repr.buffer_len() + repr.buffer_len()];
+        ip_repr.emit(&mut Ipv4Packet::new_unchecked(&mut buf[..]), &caps);
+        repr.emit(
+            &mut TcpPacket::new_unchecked(&mut buf[ip_repr.buffer_len()..]),
+            &src_addr.into(),
+            &dst_addr.into(),
+            &caps,
+        );
+        buf
+    }
+
+    /// The next packet written by the stack, a read may return more than one.
+    async fn next_packet<R>(io: &mut R, pending: &mut Vec<u8>) -> Vec<u8>
+    where
+        R: AsyncRead + Unpin,
+    {
+        loop {
+            if let Ok(packet) = Ipv4Packet::new_checked(&pending[..]) {
+                let len = packet.total_len() as usize;
+                return pending.drain(..len).collect();
+            }
+            let mut buf = vec![0u8; 1500];
+            let n = io.read(&mut buf).await.unwrap();
+            assert_ne!(n, 0);
+            pending.extend_from_slice(&buf[..n]);
+        }
+    }
+
+    #[test]
+    fn udp_packet_roundtrip() {
+        let src: SocketAddr = "198.18.0.1:5353".parse().unwrap();
+        let dst: SocketAddr = "10.0.0.2:53".parse().unwrap();
+
+        let packet = build_udp_packet(src, dst, b"hello").unwrap();
+        let (s, d, protocol, payload) = parse_ip(&packet).unwrap();
+        assert_eq!(s, src.ip());
+        assert_eq!(d, dst.ip());
+        assert_eq!(protocol, IpProtocol::Udp);
+
+        let udp = UdpDatagram::new_checked(payload).unwrap();
+        assert_eq!(udp.src_port(), 5353);
+        assert_eq!(udp.dst_port(), 53);
+        assert_eq!(udp.payload(), b"hello");
+    }
+
+    #[tokio::test]
+    async fn udp_flow_dispatch() {
+        let (tun, peer) = tokio::io::duplex(65536);
+        let (mut peer_rx, mut peer_tx) = tokio::io::split(peer);
+        let (tx, mut rx) = mpsc::unbounded_channel();
+
+        let stack = Stack::new(&["198.18.0.1/16".parse().unwrap()], 1500, true).unwrap();
+        tokio::spawn(stack.run(tun, tx));
+
+        let src: SocketAddr = "198.18.0.1:5353".parse().unwrap();
+        let dst: SocketAddr = "10.0.0.2:53".parse().unwrap();
+        peer_tx
+            .write_all(&build_udp_packet(src, dst, b"ping").unwrap())
+            .await
+            .unwrap();
+
+        let req = rx.recv().await.unwrap();
+        assert_eq!(req.remote, NetLocation::from(dst));
+        let ProxyConn::ForwardUdp(mut forwarder) = req.conn else {
+            panic!("expected udp");
+        };
+        let packet = forwarder.rx.recv().await.unwrap();
+        assert_eq!(packet.data, b"ping");
+
+        forwarder
+            .tx
+            .send(UdpPacket {
+                remote: NetLocation::from(dst),
+                data: b"pong".to_vec(),
+            })
+            .await
+            .unwrap();
+
+        let mut buf = vec![0u8; 1500];
+        let n = peer_rx.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], build_udp_packet(dst, src, b"pong").unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn tcp_handshake_timeout() {
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let mut stack = Stack::new(&["198.18.0.1/16".parse().unwrap()], 1500, false).unwrap();
+
+        let src: SocketAddr = "198.18.0.2:40000".parse().unwrap();
+        let dst: SocketAddr = "10.0.0.2:80".parse().unwrap();
+        let syn = TcpRepr {
+            src_port: src.port(),
+            dst_port: dst.port(),
+            control: TcpControl::Syn,
+            seq_number: TcpSeqNumber(1000),
+            ack_number: None,
+            window_len: 65535,
+            window_scale: None,
+            max_seg_size: Some(1460),
+            sack_permitted: false,
+            sack_ranges: [None; 3],
+            payload: &[],
+        };
+        stack.input(&build_tcp_packet(src, dst, &syn), &tx);
+        stack
+            .iface
+            .poll(Instant::now(), &mut stack.device, &mut stack.sockets);
+        stack.pump_tcp(&tx);
+        assert_eq!(stack.tcp_flows.len(), 1);
+
+        // the client never acks the syn-ack
+        tokio::time::advance(TCP_HANDSHAKE_TIMEOUT).await;
+        stack.pump_tcp(&tx);
+        assert!(stack.tcp_flows.is_empty());
+        assert!(stack.tcp_keys.is_empty());
+    }
+
+    #[tokio::test]
+    async fn tcp_flow_dispatch() {
+        let (tun, peer) = tokio::io::duplex(65536);
+        let (mut peer_rx, mut peer_tx) = tokio::io::split(peer);
+        let (tx, mut rx) = mpsc::unbounded_channel();
+
+        let stack = Stack::new(&["198.18.0.1/16".parse().unwrap()], 1500, false).unwrap();
+        tokio::spawn(stack.run(tun, tx));
+
+        let src: SocketAddr = "198.18.0.2:40000".parse().unwrap();
+        let dst: SocketAddr = "10.0.0.2:80".parse().unwrap();
+        let syn = TcpRepr {
+            src_port: src.port(),
+            dst_port: dst.port(),
+            control: TcpControl::Syn,
+            seq_number: TcpSeqNumber(1000),
+            ack_number: None,
+            window_len: 65535,
+            window_scale: None,
+            max_seg_size: Some(1460),
+            sack_permitted: false,
+            sack_ranges: [None; 3],
+            payload: &[],
+        };
+        peer_tx
+            .write_all(&build_tcp_packet(src, dst, &syn))
+            .await
+            .unwrap();
+
+        let mut pending = vec![];
+        let packet = next_packet(&mut peer_rx, &mut pending).await;
+        let ip = Ipv4Packet::new_checked(&packet[..]).unwrap();
+        let syn_ack = TcpPacket::new_checked(ip.payload()).unwrap();
+        assert!(syn_ack.syn() && syn_ack.ack());
+        assert_eq!(syn_ack.src_port(), dst.port());
+
+        // the handshake is completed by an ack carrying data
+        let data = TcpRepr {
+            control: TcpControl::None,
+            seq_number: syn.seq_number + 1,
+            ack_number: Some(syn_ack.seq_number() + 1),
+            max_seg_size: None,
+            payload: b"hello",
+            ..syn
+        };
+        peer_tx
+            .write_all(&build_tcp_packet(src, dst, &data))
+            .await
+            .unwrap();
+
+        let req = rx.recv().await.unwrap();
+        assert_eq!(req.remote, NetLocation::from(dst));
+        assert_eq!(req.source, Some(src));
+        let ProxyConn::ForwardTcp(forwarder) = req.conn else {
+            panic!("expected tcp");
+        };
+        let mut stream = forwarder.stream;
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        stream.write_all(b"world").await.unwrap();
+        loop {
+            let packet = next_packet(&mut peer_rx, &mut pending).await;
+            let ip = Ipv4Packet::new_checked(&packet[..]).unwrap();
+            let segment = TcpPacket::new_checked(ip.payload()).unwrap();
+            // pure acks of the client data come first
+            if !segment.payload().is_empty() {
+                assert_eq!(segment.payload(), b"world");
+                break;
+            }
+        }
+    }
+}
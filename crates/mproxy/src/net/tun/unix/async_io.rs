@@ -88,6 +88,7 @@ mod tests {
 
     use super::*;
 
+    #[ignore = "requires CAP_NET_ADMIN"]
     #[tokio::test]
     async fn test_io() {
         let tun = Tun::new("tun%d", IFF_TUN | IFF_NO_PI).unwrap();
@@ -87,11 +87,13 @@ mod tests {
 
     use super::*;
 
+    #[ignore = "requires CAP_NET_ADMIN"]
     #[test]
     fn test_tun_create() {
         Tun::new("tun%d", IFF_TUN | IFF_NO_PI | IFF_MULTI_QUEUE).unwrap();
     }
 
+    #[ignore = "requires CAP_NET_ADMIN"]
     #[test]
     fn test_tun_get_iff() {
         let tun = Tun::new("tun%d", IFF_TUN | IFF_NO_PI | IFF_MULTI_QUEUE).unwrap();
@@ -9,4 +9,4 @@ mod interface;
 mod routing;
 
 pub use interface::*;
-pub use routing::Routing;
+pub use routing::{Routing, MAIN_TABLE};
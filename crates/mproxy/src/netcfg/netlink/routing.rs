@@ -3,18 +3,27 @@ use std::net::IpAddr;
 use neli::{
     consts::{
         nl::NlmF,
-        rtnl::{Ifa, RtAddrFamily, RtScope, Rtm},
+        rtnl::{Arphrd, Ifa, Iff, RtAddrFamily, RtScope, RtTable, Rta, Rtm, RtmF, Rtn, Rtprot},
         socket::NlFamily,
     },
     nl::NlPayload,
     router::synchronous::NlRouter,
-    rtnl::{IfaddrmsgBuilder, RtattrBuilder},
+    rtnl::{IfaddrmsgBuilder, IfinfomsgBuilder, RtattrBuilder, Rtmsg, RtmsgBuilder},
     types::RtBuffer,
     utils::Groups, err::NlmsghdrErr,
 };
 
 use crate::netcfg::{AsIfIndex, Routing};
 
+// fib rule attributes and flags, not covered by neli
+const FRA_PRIORITY: u16 = 6;
+const FRA_FWMARK: u16 = 10;
+const FRA_TABLE: u16 = 15;
+const FIB_RULE_INVERT: u32 = 0x2;
+
+/// Rules are put ahead of the main table lookup at 32766.
+const RULE_PRIORITY: u32 = 9000;
+
 pub struct RoutingTool {
     sock: NlRouter,
 }
@@ -62,16 +71,176 @@ impl Routing for RoutingTool {
 
         let recv = self.sock.send::<_, _, Rtm, NlmsghdrErr<>>(
             Rtm::Newaddr,
-            NlmF::REQUEST | NlmF::CREATE | NlmF::EXCL,
+            NlmF::REQUEST | NlmF::CREATE | NlmF::EXCL | NlmF::ACK,
             NlPayload::Payload(ifaddrmsg),
         )?;
         for msg in recv {
-            let mut msg = msg.unwrap();
-            println!("{:?}",  msg.get_err());
+            msg?;
         }
 
         Ok(())
     }
+
+    fn add_route<IfIndex>(
+        &mut self,
+        if_index: IfIndex,
+        dst: IpAddr,
+        prefix_len: u8,
+        table: u32,
+    ) -> Result<(), anyhow::Error>
+    where
+        IfIndex: AsIfIndex,
+    {
+        let mut rtattrs = RtBuffer::new();
+
+        rtattrs.push(
+            RtattrBuilder::default()
+                .rta_type(Rta::Dst)
+                .rta_payload(match dst {
+                    IpAddr::V4(addr) => addr.octets().to_vec(),
+                    IpAddr::V6(addr) => addr.octets().to_vec(),
+                })
+                .build()?,
+        );
+        rtattrs.push(
+            RtattrBuilder::default()
+                .rta_type(Rta::Oif)
+                .rta_payload(if_index.as_if_index())
+                .build()?,
+        );
+        // the table in the header only holds ids below 256
+        rtattrs.push(
+            RtattrBuilder::default()
+                .rta_type(Rta::Table)
+                .rta_payload(table)
+                .build()?,
+        );
+
+        let rtmsg = RtmsgBuilder::default()
+            .rtm_family(if dst.is_ipv4() {
+                RtAddrFamily::Inet
+            } else {
+                RtAddrFamily::Inet6
+            })
+            .rtm_dst_len(prefix_len)
+            .rtm_src_len(0)
+            .rtm_tos(0)
+            .rtm_table(RtTable::Unspec)
+            .rtm_protocol(Rtprot::Boot)
+            .rtm_scope(RtScope::Link)
+            .rtm_type(Rtn::Unicast)
+            .rtm_flags(RtmF::empty())
+            .rtattrs(rtattrs)
+            .build()?;
+
+        let recv = self.sock.send::<_, _, Rtm, NlmsghdrErr<>>(
+            Rtm::Newroute,
+            NlmF::REQUEST | NlmF::CREATE | NlmF::REPLACE | NlmF::ACK,
+            NlPayload::Payload(rtmsg),
+        )?;
+        for msg in recv {
+            msg?;
+        }
+
+        Ok(())
+    }
+
+    fn add_rule_unless_mark(
+        &mut self,
+        ipv6: bool,
+        mark: u32,
+        table: u32,
+    ) -> Result<(), anyhow::Error> {
+        // rules outlive the process, drop the one left by a previous run
+        if let Ok(recv) = self.sock.send::<_, _, Rtm, NlmsghdrErr>(
+            Rtm::Delrule,
+            NlmF::REQUEST | NlmF::ACK,
+            NlPayload::Payload(rule_msg(ipv6, mark, table)?),
+        ) {
+            for msg in recv {
+                if msg.is_err() {
+                    break;
+                }
+            }
+        }
+
+        let recv = self.sock.send::<_, _, Rtm, NlmsghdrErr>(
+            Rtm::Newrule,
+            NlmF::REQUEST | NlmF::CREATE | NlmF::EXCL | NlmF::ACK,
+            NlPayload::Payload(rule_msg(ipv6, mark, table)?),
+        )?;
+        for msg in recv {
+            msg?;
+        }
+
+        Ok(())
+    }
+
+    fn set_link_up<IfIndex>(&mut self, if_index: IfIndex) -> Result<(), anyhow::Error>
+    where
+        IfIndex: AsIfIndex,
+    {
+        let ifinfomsg = IfinfomsgBuilder::default()
+            .ifi_family(RtAddrFamily::Unspecified)
+            .ifi_type(Arphrd::None)
+            .ifi_index(if_index.as_if_index())
+            .ifi_flags(Iff::UP)
+            .ifi_change(Iff::UP)
+            .build()?;
+
+        let recv = self.sock.send::<_, _, Rtm, NlmsghdrErr<>>(
+            Rtm::Newlink,
+            NlmF::REQUEST | NlmF::ACK,
+            NlPayload::Payload(ifinfomsg),
+        )?;
+        for msg in recv {
+            msg?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A rule sending the packets without `mark` to `table`.
+fn rule_msg(ipv6: bool, mark: u32, table: u32) -> Result<Rtmsg, anyhow::Error> {
+    let mut rtattrs = RtBuffer::new();
+
+    rtattrs.push(
+        RtattrBuilder::default()
+            .rta_type(Rta::from(FRA_PRIORITY))
+            .rta_payload(RULE_PRIORITY)
+            .build()?,
+    );
+    rtattrs.push(
+        RtattrBuilder::default()
+            .rta_type(Rta::from(FRA_FWMARK))
+            .rta_payload(mark)
+            .build()?,
+    );
+    rtattrs.push(
+        RtattrBuilder::default()
+            .rta_type(Rta::from(FRA_TABLE))
+            .rta_payload(table)
+            .build()?,
+    );
+
+    // a fib rule header has the layout of rtmsg, the type is the action
+    Ok(RtmsgBuilder::default()
+        .rtm_family(if ipv6 {
+            RtAddrFamily::Inet6
+        } else {
+            RtAddrFamily::Inet
+        })
+        .rtm_dst_len(0)
+        .rtm_src_len(0)
+        .rtm_tos(0)
+        .rtm_table(RtTable::Unspec)
+        .rtm_protocol(Rtprot::Boot)
+        .rtm_scope(RtScope::Universe)
+        .rtm_type(Rtn::Unicast)
+        .rtm_flags(RtmF::from_bits_retain(FIB_RULE_INVERT))
+        .rtattrs(rtattrs)
+        .build()?)
 }
 
 #[cfg(test)]
@@ -80,6 +249,7 @@ mod tests {
 
     use super::*;
 
+    #[ignore = "requires CAP_NET_ADMIN"]
     #[test]
     fn test_add_address() {
         let mut rt = RoutingTool::new().unwrap();
@@ -2,6 +2,9 @@ use std::net::IpAddr;
 
 use super::AsIfIndex;
 
+/// The routing table used when no other one is given.
+pub const MAIN_TABLE: u32 = 254;
+
 pub trait Routing {
     fn add_address<IfIndex>(
         &mut self,
@@ -11,4 +14,27 @@ pub trait Routing {
     ) -> Result<(), anyhow::Error>
     where
         IfIndex: AsIfIndex;
+
+    fn add_route<IfIndex>(
+        &mut self,
+        if_index: IfIndex,
+        dst: IpAddr,
+        prefix_len: u8,
+        table: u32,
+    ) -> Result<(), anyhow::Error>
+    where
+        IfIndex: AsIfIndex;
+
+    /// Looks up `table` for the packets not marked with `mark`, marked ones
+    /// skip it and go on with the following rules.
+    fn add_rule_unless_mark(
+        &mut self,
+        ipv6: bool,
+        mark: u32,
+        table: u32,
+    ) -> Result<(), anyhow::Error>;
+
+    fn set_link_up<IfIndex>(&mut self, if_index: IfIndex) -> Result<(), anyhow::Error>
+    where
+        IfIndex: AsIfIndex;
 }
@@ -0,0 +1,54 @@
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+
+/// Source addresses allowed to use an ingress. A denied network always
+/// loses, an empty allow list allows everything else.
+#[derive(Debug, Clone, Default)]
+pub struct SourceAcl {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+impl SourceAcl {
+    pub fn new(allow: Vec<IpNet>, deny: Vec<IpNet>) -> Self {
+        Self { allow, deny }
+    }
+
+    /// An unknown source passes only if there's no allow list.
+    pub fn permits(&self, source: Option<IpAddr>) -> bool {
+        let Some(ip) = source.map(|v| v.to_canonical()) else {
+            return self.allow.is_empty();
+        };
+        !self.deny.iter().any(|net| net.contains(&ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn permits() {
+        let acl = SourceAcl::new(
+            vec![
+                "192.168.0.0/16".parse().unwrap(),
+                "::1/128".parse().unwrap(),
+            ],
+            vec!["192.168.1.0/24".parse().unwrap()],
+        );
+        assert!(acl.permits(Some("192.168.0.1".parse().unwrap())));
+        assert!(acl.permits(Some("::1".parse().unwrap())));
+        // v4 clients of a dual-stack listener
+        assert!(acl.permits(Some("::ffff:192.168.0.1".parse().unwrap())));
+        assert!(!acl.permits(Some("192.168.1.1".parse().unwrap())));
+        assert!(!acl.permits(Some("10.0.0.1".parse().unwrap())));
+        assert!(!acl.permits(None));
+
+        let acl = SourceAcl::new(vec![], vec!["10.0.0.0/8".parse().unwrap()]);
+        assert!(acl.permits(Some("192.168.1.1".parse().unwrap())));
+        assert!(!acl.permits(Some("10.0.0.1".parse().unwrap())));
+        assert!(acl.permits(None));
+    }
+}
@@ -8,11 +8,11 @@ use std::{
 };
 
 use anyhow::Context;
-use http_body_util::{combinators::BoxBody, BodyExt};
+use http_body_util::{combinators::BoxBody, BodyExt, Full};
 use hyper::{
     body::{self, Bytes},
     client::conn::http1,
-    header, Request, Response, Uri,
+    header, Request, Response, StatusCode, Uri,
 };
 use hyper_util::rt::TokioIo;
 use tokio::{
@@ -21,7 +21,11 @@ use tokio::{
 };
 use tracing::warn;
 
-use crate::stats::{Copyed, GetTransferStats, TransferMonitor};
+use crate::{
+    conntrack,
+    io::{RateLimitedIO, RateLimiter},
+    stats::{Copyed, GetTransferStats, TransferMonitor},
+};
 
 type HttpForwardResp = Result<Response<BoxBody<Bytes, hyper::Error>>, anyhow::Error>;
 
@@ -30,6 +34,8 @@ pub struct HttpForwarder {
     pub req: Request<body::Incoming>,
     pub resp_tx: oneshot::Sender<HttpForwardResp>,
     pub remove_proxy_header: bool,
+    /// applied to the connection to the remote once it's made
+    pub limiters: Vec<Arc<RateLimiter>>,
 }
 
 impl HttpForwarder {
@@ -38,9 +44,21 @@ impl HttpForwarder {
             req,
             resp_tx,
             remove_proxy_header: true,
+            limiters: Vec::new(),
         }
     }
 
+    /// Answers the client with 403 instead of forwarding the request.
+    pub fn reject(self) {
+        let mut resp = Response::new(
+            Full::new(Bytes::from("The request is not allowed"))
+                .map_err(|never| match never {})
+                .boxed(),
+        );
+        *resp.status_mut() = StatusCode::FORBIDDEN;
+        let _ = self.resp_tx.send(Ok(resp));
+    }
+
     pub async fn forward_inner<StreamIO>(
         mut req: Request<body::Incoming>,
         s: StreamIO,
@@ -106,15 +124,18 @@ impl HttpForwarder {
             req,
             resp_tx,
             remove_proxy_header,
+            limiters,
         } = self;
 
         let (tx, rx) = oneshot::channel();
         let s = StreamWrapper::new(s, tx);
 
         let copyed = Arc::new(Copyed::new(s.copyed_ref()));
+        let s = RateLimitedIO::remote(s, limiters);
         if let Some(monitor) = monitor {
             monitor.bind(copyed.clone()).await;
         }
+        conntrack::bind(copyed.clone()).await;
 
         resp_tx
             .send(
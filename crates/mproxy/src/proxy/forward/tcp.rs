@@ -6,6 +6,7 @@ use tokio::io::{AsyncRead, AsyncWrite};
 use tracing::{info_span, Instrument};
 
 use crate::{
+    conntrack,
     io::{BoxedAsyncIO, CopyBidirectional},
     stats::{Copyed, TransferMonitor},
 };
@@ -55,6 +56,7 @@ impl TcpForwarder {
         if let Some(monitor) = monitor {
             monitor.bind(copyed.clone()).await;
         }
+        conntrack::bind(copyed.clone()).await;
 
         tokio::pin!(copy_bi);
 
@@ -1,17 +1,35 @@
-use std::{fmt, marker::Unpin, sync::Arc};
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
-use anyhow::Context;
-use futures::FutureExt;
-use tokio::io::{AsyncRead, AsyncWrite};
-use tracing::{info_span, Instrument};
+use tokio::sync::mpsc;
+use tracing::debug;
 
 use crate::{
-    io::{BoxedAsyncIO, CopyBidirectional},
+    conntrack,
+    io::PacketIO,
+    proxy::NetLocation,
     stats::{Copyed, TransferMonitor},
 };
 
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+#[derive(Debug, Clone)]
+pub struct UdpPacket {
+    pub remote: NetLocation,
+    pub data: Vec<u8>,
+}
+
 pub struct UdpForwarder {
-    pub stream: BoxedAsyncIO,
+    /// packets sent by the client
+    pub rx: mpsc::Receiver<UdpPacket>,
+    /// packets sent back to the client
+    pub tx: mpsc::Sender<UdpPacket>,
 }
 
 impl fmt::Debug for UdpForwarder {
@@ -21,59 +39,87 @@ impl fmt::Debug for UdpForwarder {
 }
 
 impl UdpForwarder {
-    pub async fn forward<StreamIO>(self, s: StreamIO) -> Result<(u64, u64), anyhow::Error>
+    pub fn new(rx: mpsc::Receiver<UdpPacket>, tx: mpsc::Sender<UdpPacket>) -> Self {
+        Self { rx, tx }
+    }
+
+    pub async fn forward<S>(
+        self,
+        remote: NetLocation,
+        s: S,
+        idle_timeout: Duration,
+    ) -> Result<(u64, u64), anyhow::Error>
     where
-        StreamIO: AsyncRead + AsyncWrite + Unpin,
+        S: PacketIO,
     {
-        self.forward_with_monitor_inner(s, None).await
+        self.forward_with_monitor_inner(remote, s, idle_timeout, None)
+            .await
     }
 
-    pub async fn forward_with_monitor<StreamIO>(
+    pub async fn forward_with_monitor<S>(
         self,
-        s: StreamIO,
+        remote: NetLocation,
+        s: S,
+        idle_timeout: Duration,
         monitor: &TransferMonitor,
     ) -> Result<(u64, u64), anyhow::Error>
     where
-        StreamIO: AsyncRead + AsyncWrite + Unpin,
+        S: PacketIO,
     {
-        self.forward_with_monitor_inner(s, Some(monitor)).await
+        self.forward_with_monitor_inner(remote, s, idle_timeout, Some(monitor))
+            .await
     }
 
-    async fn forward_with_monitor_inner<StreamIO>(
+    async fn forward_with_monitor_inner<S>(
         mut self,
-        mut s: StreamIO,
+        remote: NetLocation,
+        s: S,
+        idle_timeout: Duration,
         monitor: Option<&TransferMonitor>,
     ) -> Result<(u64, u64), anyhow::Error>
     where
-        StreamIO: AsyncRead + AsyncWrite + Unpin,
+        S: PacketIO,
     {
-        let proxy = &mut self.stream;
-        let remote = &mut s;
-        let copy_bi = CopyBidirectional::new(proxy, remote);
+        let (upload_bytes, download_bytes) =
+            (Arc::new(AtomicU64::new(0)), Arc::new(AtomicU64::new(0)));
 
-        let copyed = Arc::new(Copyed::new(copy_bi.copyed_ref()));
+        let copyed = Arc::new(Copyed::new((upload_bytes.clone(), download_bytes.clone())));
         if let Some(monitor) = monitor {
             monitor.bind(copyed.clone()).await;
         }
+        conntrack::bind(copyed.clone()).await;
 
-        tokio::pin!(copy_bi);
-
-        copy_bi
-            .instrument(info_span!("bidirectional_transmission"))
-            .map(|v| {
-                Ok(match v {
-                    Ok(v) => v,
-                    Err((e, copyed)) => {
-                        let (a, b) = copyed;
-                        if a == 0 && b == 0 {
-                            return Err(e);
-                        } else {
-                            (a, b)
-                        }
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        loop {
+            tokio::select! {
+                packet = self.rx.recv() => match packet {
+                    Some(packet) => {
+                        let n = s.send(&packet.data).await?;
+                        upload_bytes.fetch_add(n as u64, Ordering::Relaxed);
                     }
-                })
-            })
-            .await
-            .context("bidirectional transmission")
+                    None => break,
+                },
+                n = s.recv(&mut buf) => {
+                    let n = n?;
+                    download_bytes.fetch_add(n as u64, Ordering::Relaxed);
+                    let packet = UdpPacket {
+                        remote: remote.clone(),
+                        data: buf[..n].to_vec(),
+                    };
+                    if self.tx.send(packet).await.is_err() {
+                        break;
+                    }
+                },
+                _ = tokio::time::sleep(idle_timeout) => {
+                    debug!("udp session is idle for {:?}", idle_timeout);
+                    break;
+                }
+            }
+        }
+
+        Ok((
+            upload_bytes.load(Ordering::Relaxed),
+            download_bytes.load(Ordering::Relaxed),
+        ))
     }
 }
@@ -0,0 +1,256 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::Context;
+use tracing::warn;
+
+use crate::{
+    config::egress::group::{GroupConfig, Policy},
+    io::{LimiterSlot, RateLimitedIO, RateLimiter},
+};
+
+use super::{Egress, ProxyConn, ProxyRequest, ProxyResponse, SendError, TcpForwarder};
+
+#[derive(Debug)]
+struct Member {
+    egress: Arc<Egress>,
+    limiter: Option<Arc<RateLimiter>>,
+    active: AtomicUsize,
+}
+
+/// Decrements the active connections of a member when dropped.
+struct ActiveGuard<'a>(&'a AtomicUsize);
+
+impl<'a> ActiveGuard<'a> {
+    fn new(active: &'a AtomicUsize) -> Self {
+        active.fetch_add(1, Ordering::Relaxed);
+        Self(active)
+    }
+}
+
+impl Drop for ActiveGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A set of egresses picked by a policy, a member failing to connect is
+/// skipped and the request retried on the next one.
+#[derive(Debug)]
+pub struct EgressGroup {
+    pub id: String,
+    members: Vec<Member>,
+    policy: Policy,
+    next: AtomicUsize,
+}
+
+impl EgressGroup {
+    /// `limiters` are those of the egresses by id, a member's one applies to
+    /// the requests it takes.
+    pub fn new(
+        id: String,
+        config: GroupConfig,
+        egress: &[Arc<Egress>],
+        limiters: &HashMap<String, Arc<RateLimiter>>,
+    ) -> Result<Self, anyhow::Error> {
+        if config.members.is_empty() {
+            anyhow::bail!("Egress group {} has no member", id);
+        }
+
+        Ok(Self {
+            members: config
+                .members
+                .iter()
+                .map(|member| {
+                    Ok::<_, anyhow::Error>(Member {
+                        egress: egress
+                            .iter()
+                            .find(|egress| &egress.id == member)
+                            .context(format!("Egress {} isn't exist", member))?
+                            .clone(),
+                        limiter: limiters.get(member).cloned(),
+                        active: AtomicUsize::new(0),
+                    })
+                })
+                .try_collect()?,
+            id,
+            policy: config.policy,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Members in the order they should be tried.
+    fn candidates(&self) -> Vec<&Member> {
+        let mut members: Vec<&Member> = self.members.iter().collect();
+        match self.policy {
+            Policy::Failover => {}
+            Policy::RoundRobin => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed) % members.len();
+                members.rotate_left(start);
+            }
+            Policy::LeastConnections => {
+                members.sort_by_key(|member| member.active.load(Ordering::Relaxed));
+            }
+            Policy::LowestLatency => {
+                // unmeasured members come last
+                members.sort_by_key(|member| {
+                    let latency = member.egress.latency();
+                    (latency.is_none(), latency)
+                });
+            }
+        }
+        members
+    }
+
+    pub async fn send(&self, req: ProxyRequest) -> Result<ProxyResponse, SendError> {
+        // a tcp stream is wrapped once, the slot holds the limiter of the
+        // member being tried
+        let slot = Arc::new(LimiterSlot::default());
+        let mut req = match req.conn {
+            ProxyConn::ForwardTcp(TcpForwarder { stream })
+                if self.members.iter().any(|member| member.limiter.is_some()) =>
+            {
+                ProxyRequest {
+                    conn: ProxyConn::ForwardTcp(TcpForwarder {
+                        stream: Box::new(
+                            RateLimitedIO::new(stream, Vec::new()).with_slot(slot.clone()),
+                        ),
+                    }),
+                    ..req
+                }
+            }
+            _ => req,
+        };
+
+        for member in self.candidates() {
+            let _active = ActiveGuard::new(&member.active);
+            slot.set(member.limiter.clone());
+            // an http forwarder takes the limiter along, it's taken back off
+            // if the member can't connect
+            let limited = match (&mut req.conn, &member.limiter) {
+                (ProxyConn::ForwardHttp(forwarder), Some(limiter)) => {
+                    forwarder.limiters.push(limiter.clone());
+                    true
+                }
+                _ => false,
+            };
+            match member.egress.send(req).await {
+                Err(SendError::Connect(mut r, e)) => {
+                    warn!(
+                        "{} of group {} failed to connect, trying next member: {:?}",
+                        member.egress.id, self.id, e
+                    );
+                    if let (ProxyConn::ForwardHttp(forwarder), true) = (&mut r.conn, limited) {
+                        forwarder.limiters.pop();
+                    }
+                    req = r;
+                }
+                result => return result,
+            }
+        }
+        Err(anyhow::anyhow!("all members of group {} failed to connect", self.id).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use crate::{
+        config::egress::ClientConfig,
+        proxy::test_support::{dead_egress, egress},
+    };
+
+    use super::*;
+
+    fn group(policy: Policy, members: &[&Arc<Egress>]) -> EgressGroup {
+        let egress: Vec<Arc<Egress>> = members.iter().map(|v| (*v).clone()).collect();
+        EgressGroup::new(
+            "group".to_string(),
+            GroupConfig {
+                members: egress.iter().map(|v| v.id.clone()).collect(),
+                policy,
+            },
+            &egress,
+            &HashMap::new(),
+        )
+        .unwrap()
+    }
+
+    fn ids(group: &EgressGroup) -> Vec<String> {
+        group
+            .candidates()
+            .iter()
+            .map(|member| member.egress.id.clone())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn failover() {
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut s, _) = echo.accept().await.unwrap();
+            let mut buf = [0u8; 4];
+            s.read_exact(&mut buf).await.unwrap();
+            s.write_all(&buf).await.unwrap();
+        });
+
+        let dead = dead_egress("dead").await;
+        let direct = egress("direct", ClientConfig::Direct(Default::default())).await;
+        let group = group(Policy::Failover, &[&dead, &direct]);
+
+        let (stream, mut client) = tokio::io::duplex(1024);
+        let send = tokio::spawn(async move {
+            group
+                .send(ProxyRequest {
+                    remote: echo_addr.into(),
+                    source: None,
+                    route: None,
+                    conn: ProxyConn::ForwardTcp(TcpForwarder {
+                        stream: Box::new(stream),
+                    }),
+                })
+                .await
+        });
+
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        drop(client);
+
+        let resp = send.await.unwrap().unwrap();
+        assert_eq!(resp.upload_bytes, 4);
+    }
+
+    #[tokio::test]
+    async fn policies() {
+        let a = dead_egress("a").await;
+        let b = dead_egress("b").await;
+        let c = dead_egress("c").await;
+
+        let rr = group(Policy::RoundRobin, &[&a, &b, &c]);
+        assert_eq!(ids(&rr), ["a", "b", "c"]);
+        assert_eq!(ids(&rr), ["b", "c", "a"]);
+
+        let lc = group(Policy::LeastConnections, &[&a, &b, &c]);
+        let _active = ActiveGuard::new(&lc.members[0].active);
+        assert_eq!(ids(&lc), ["b", "c", "a"]);
+
+        a.set_latency(Some(Duration::from_millis(30)));
+        c.set_latency(Some(Duration::from_millis(10)));
+        let ll = group(Policy::LowestLatency, &[&a, &b, &c]);
+        assert_eq!(ids(&ll), ["c", "a", "b"]);
+    }
+}
@@ -1,20 +1,83 @@
+mod acl;
 mod forward;
+mod group;
 mod net_location;
+mod sniff;
+#[cfg(test)]
+pub(crate) mod test_support;
+
+use std::{
+    fmt,
+    net::SocketAddr,
+    ops::Deref,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
 
-use std::ops::Deref;
-
+pub use acl::*;
 pub use forward::*;
+pub use group::*;
 pub use net_location::*;
+pub use sniff::*;
 
 use crate::{
     config::{egress::EgressConfig, ingress::IngressConfig},
+    io::RateLimiter,
     net::protocol,
+    router::RouteDecision,
     stats::TransferStats,
 };
 
+/// Destination of rules that block their requests, it's not a real egress.
+pub const REJECT: &str = "reject";
+
+/// Asked by an ingress before it tells its client a request succeeded, so a
+/// refused request can be answered in the client's protocol.
+pub trait Admission: fmt::Debug + Send + Sync {
+    /// `None` refuses the request.
+    fn admit(&self, source: SocketAddr, remote: &NetLocation) -> Option<Admitted>;
+
+    /// Whether `source` may be answered at all, for requests that aren't
+    /// proxied like the PAC script.
+    fn permits(&self, source: SocketAddr) -> bool;
+}
+
+/// Renders the current rules as a proxy auto-config script.
+pub trait PacSource: fmt::Debug + Send + Sync {
+    /// Proxied requests are sent to `proxy`.
+    fn pac(&self, proxy: &str) -> String;
+}
+
+#[cfg(test)]
+#[derive(Debug)]
+pub(crate) struct AdmitAll;
+
+#[cfg(test)]
+impl Admission for AdmitAll {
+    fn admit(&self, _: SocketAddr, _: &NetLocation) -> Option<Admitted> {
+        Some(Admitted::default())
+    }
+
+    fn permits(&self, _: SocketAddr) -> bool {
+        true
+    }
+}
+
+/// An admitted request, the ingress passes it along with the request.
+#[derive(Debug, Default)]
+pub struct Admitted {
+    /// route decided while admitting, so the request isn't routed twice
+    pub route: Option<RouteDecision>,
+}
+
 #[derive(Debug)]
 pub struct ProxyRequest {
     pub remote: NetLocation,
+    /// address of the client, if the ingress knows it
+    pub source: Option<SocketAddr>,
+    /// decided when the ingress admitted the request, dispatch routes the
+    /// request itself otherwise
+    pub route: Option<RouteDecision>,
     pub conn: ProxyConn,
 }
 
@@ -24,6 +87,16 @@ pub struct ProxyResponse {
     pub download_bytes: u64,
 }
 
+#[derive(Debug, thiserror::Error)]
+pub enum SendError {
+    /// The egress couldn't reach its upstream, the request is untouched and
+    /// may be sent through another egress.
+    #[error("connect failed: {1:?}")]
+    Connect(ProxyRequest, anyhow::Error),
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
 #[derive(Debug)]
 pub enum ProxyConn {
     ForwardTcp(TcpForwarder),
@@ -34,14 +107,27 @@ pub enum ProxyConn {
 #[derive(Debug)]
 pub struct Ingress {
     pub id: String,
+    pub sniff: bool,
+    pub limiter: Option<Arc<RateLimiter>>,
     server: protocol::Server,
 }
 
 impl Ingress {
-    pub async fn new(config: IngressConfig) -> Result<Self, anyhow::Error> {
+    pub async fn new(
+        config: IngressConfig,
+        admission: Arc<dyn Admission>,
+        pac: Arc<dyn PacSource>,
+    ) -> Result<Self, anyhow::Error> {
         Ok(Self {
             id: config.id,
-            server: protocol::Server::new(config.server).await?,
+            sniff: config.sniff,
+            limiter: config
+                .limit
+                .as_ref()
+                .map(RateLimiter::new)
+                .transpose()?
+                .map(Arc::new),
+            server: protocol::Server::new(config.server, admission, pac).await?,
         })
     }
 }
@@ -59,6 +145,7 @@ pub struct Egress {
     pub id: String,
 
     client: protocol::Client,
+    latency: RwLock<Option<Duration>>,
 }
 
 impl Egress {
@@ -66,13 +153,28 @@ impl Egress {
         Ok(Self {
             id: config.id,
             client: protocol::Client::new(config.client).await?,
+            latency: RwLock::new(None),
         })
     }
 
+    /// Last measured round-trip latency, `None` if unknown or unreachable.
+    pub fn latency(&self) -> Option<Duration> {
+        *self.latency.read().unwrap()
+    }
+
+    pub fn set_latency(&self, latency: Option<Duration>) {
+        *self.latency.write().unwrap() = latency;
+    }
+
+    pub fn is_direct(&self) -> bool {
+        matches!(self.client, protocol::Client::Direct(_))
+    }
+
     pub async fn get_transfor_stats(&self) -> Result<TransferStats, anyhow::Error> {
         match &self.client {
             protocol::Client::Http(c) => c.get_transfer_stats().await,
             protocol::Client::Direct(c) => c.get_transfer_stats().await,
+            protocol::Client::Socks(c) => c.get_transfer_stats().await,
         }
     }
 }
@@ -84,3 +186,19 @@ impl Deref for Egress {
         &self.client
     }
 }
+
+/// What a routing rule points at.
+#[derive(Debug, Clone)]
+pub enum Outbound {
+    Egress(Arc<Egress>),
+    Group(Arc<EgressGroup>),
+}
+
+impl Outbound {
+    pub async fn send(&self, req: ProxyRequest) -> Result<ProxyResponse, SendError> {
+        match self {
+            Outbound::Egress(egress) => egress.send(req).await,
+            Outbound::Group(group) => group.send(req).await,
+        }
+    }
+}
@@ -33,7 +33,7 @@ impl FromStr for Address {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub struct NetLocation {
     pub address: Address,
     pub port: u16,
@@ -0,0 +1,299 @@
+use std::{net::IpAddr, time::Duration};
+
+use tokio::{io::AsyncReadExt, time::Instant};
+use tracing::debug;
+
+use crate::io::Rewind;
+
+use super::{Address, ProxyConn, ProxyRequest, TcpForwarder};
+
+/// Bytes buffered at most while looking for a domain.
+const SNIFF_BUFFER_SIZE: usize = 4096;
+/// How long to wait for the client to speak first.
+const SNIFF_TIMEOUT: Duration = Duration::from_millis(300);
+
+/// Peeks the first bytes of a tcp request and, if they are a TLS ClientHello
+/// with SNI or an HTTP/1 request with a `Host` header, replaces the remote ip
+/// with that domain. The peeked bytes are replayed to the egress. A route
+/// decided for the ip is dropped once the domain is known.
+pub async fn sniff(req: ProxyRequest) -> ProxyRequest {
+    let (mut remote, source, mut route, mut stream) = match req {
+        ProxyRequest {
+            remote,
+            source,
+            route,
+            conn: ProxyConn::ForwardTcp(TcpForwarder { stream }),
+        } => (remote, source, route, stream),
+        req => return req,
+    };
+
+    let mut buf = Vec::with_capacity(SNIFF_BUFFER_SIZE);
+    let deadline = Instant::now() + SNIFF_TIMEOUT;
+    let domain = loop {
+        let mut chunk = [0u8; SNIFF_BUFFER_SIZE];
+        let want = SNIFF_BUFFER_SIZE - buf.len();
+        match tokio::time::timeout_at(deadline, stream.read(&mut chunk[..want])).await {
+            Ok(Ok(n)) if n > 0 => buf.extend_from_slice(&chunk[..n]),
+            _ => break None,
+        }
+        match parse(&buf) {
+            Sniffed::Domain(domain) => break Some(domain),
+            Sniffed::Unknown => break None,
+            Sniffed::Incomplete if buf.len() >= SNIFF_BUFFER_SIZE => break None,
+            Sniffed::Incomplete => {}
+        }
+    };
+
+    // a literal ip in Host tells nothing new
+    if let Some(domain) = domain.filter(|v| v.parse::<IpAddr>().is_err()) {
+        debug!("sniffed {} for {}", domain, remote);
+        remote.address = Address::Hostname(domain);
+        route = None;
+    }
+
+    ProxyRequest {
+        remote,
+        source,
+        route,
+        conn: ProxyConn::ForwardTcp(TcpForwarder {
+            stream: Box::new(Rewind::new(stream, buf)),
+        }),
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Sniffed {
+    Domain(String),
+    /// more bytes are needed to decide
+    Incomplete,
+    /// neither TLS nor HTTP, or no domain in it
+    Unknown,
+}
+
+fn parse(buf: &[u8]) -> Sniffed {
+    match buf.first() {
+        None => Sniffed::Incomplete,
+        Some(0x16) => parse_tls(buf),
+        Some(c) if c.is_ascii_uppercase() => parse_http(buf),
+        _ => Sniffed::Unknown,
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.buf.len() < n {
+            return None;
+        }
+        let (v, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Some(v)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|v| v[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|v| u16::from_be_bytes([v[0], v[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.take(3)
+            .map(|v| (v[0] as usize) << 16 | (v[1] as usize) << 8 | v[2] as usize)
+    }
+}
+
+fn parse_tls(buf: &[u8]) -> Sniffed {
+    let mut record = Reader { buf };
+    let Some(header) = record.take(5) else {
+        return Sniffed::Incomplete;
+    };
+    if header[1] != 0x03 {
+        return Sniffed::Unknown;
+    }
+
+    // the ClientHello may span several records, only the first is inspected
+    let len = u16::from_be_bytes([header[3], header[4]]) as usize;
+    let Some(body) = record.take(len) else {
+        return Sniffed::Incomplete;
+    };
+
+    parse_client_hello(Reader { buf: body })
+        .map(Sniffed::Domain)
+        .unwrap_or(Sniffed::Unknown)
+}
+
+fn parse_client_hello(mut r: Reader<'_>) -> Option<String> {
+    if r.u8()? != 0x01 {
+        return None;
+    }
+    let len = r.u24()?;
+    let mut r = Reader { buf: r.take(len)? };
+
+    // version + random
+    r.take(2 + 32)?;
+    let session_id = r.u8()? as usize;
+    r.take(session_id)?;
+    let cipher_suites = r.u16()? as usize;
+    r.take(cipher_suites)?;
+    let compression = r.u8()? as usize;
+    r.take(compression)?;
+
+    let len = r.u16()? as usize;
+    let mut extensions = Reader { buf: r.take(len)? };
+    while !extensions.buf.is_empty() {
+        let ty = extensions.u16()?;
+        let len = extensions.u16()? as usize;
+        let data = extensions.take(len)?;
+        if ty != 0x0000 {
+            continue;
+        }
+
+        let mut names = Reader { buf: data };
+        let len = names.u16()? as usize;
+        let mut names = Reader {
+            buf: names.take(len)?,
+        };
+        while !names.buf.is_empty() {
+            let ty = names.u8()?;
+            let len = names.u16()? as usize;
+            let name = names.take(len)?;
+            if ty == 0x00 {
+                return String::from_utf8(name.to_vec()).ok();
+            }
+        }
+    }
+    None
+}
+
+fn parse_http(buf: &[u8]) -> Sniffed {
+    let Some(end) = buf.windows(4).position(|v| v == b"\r\n\r\n") else {
+        return Sniffed::Incomplete;
+    };
+    let Ok(head) = std::str::from_utf8(&buf[..end]) else {
+        return Sniffed::Unknown;
+    };
+
+    let mut lines = head.split("\r\n");
+    match lines.next().and_then(|line| line.split(' ').nth(2)) {
+        Some(version) if version.starts_with("HTTP/1.") => {}
+        _ => return Sniffed::Unknown,
+    }
+
+    lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("host"))
+        .map(|(_, value)| strip_port(value.trim()))
+        .filter(|host| !host.is_empty())
+        .map(|host| Sniffed::Domain(host.to_string()))
+        .unwrap_or(Sniffed::Unknown)
+}
+
+fn strip_port(host: &str) -> &str {
+    if let Some(v6) = host.strip_prefix('[') {
+        return v6.split(']').next().unwrap_or_default();
+    }
+    match host.rsplit_once(':') {
+        Some((host, port)) if port.parse::<u16>().is_ok() => host,
+        _ => host,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use tokio::io::AsyncWriteExt;
+
+    use crate::proxy::NetLocation;
+
+    use super::*;
+
+    fn client_hello(sni: &str) -> Vec<u8> {
+        let mut server_name = vec![0x00];
+        server_name.extend_from_slice(&(sni.len() as u16).to_be_bytes());
+        server_name.extend_from_slice(sni.as_bytes());
+        let mut list = (server_name.len() as u16).to_be_bytes().to_vec();
+        list.extend(server_name);
+
+        let mut extensions = vec![];
+        // an unrelated extension before SNI
+        extensions.extend_from_slice(&[0x00, 0x17, 0x00, 0x00]);
+        extensions.extend_from_slice(&[0x00, 0x00]);
+        extensions.extend_from_slice(&(list.len() as u16).to_be_bytes());
+        extensions.extend(list);
+
+        let mut hello = vec![0x03, 0x03];
+        hello.extend_from_slice(&[0u8; 32]);
+        hello.extend_from_slice(&[0x00]);
+        hello.extend_from_slice(&[0x00, 0x02, 0x13, 0x01]);
+        hello.extend_from_slice(&[0x01, 0x00]);
+        hello.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        hello.extend(extensions);
+
+        let mut handshake = vec![0x01];
+        handshake.extend_from_slice(&(hello.len() as u32).to_be_bytes()[1..]);
+        handshake.extend(hello);
+
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend(handshake);
+        record
+    }
+
+    #[test]
+    fn parse_protocols() {
+        let hello = client_hello("example.com");
+        assert_eq!(parse(&hello), Sniffed::Domain("example.com".to_string()));
+        assert_eq!(parse(&hello[..20]), Sniffed::Incomplete);
+
+        assert_eq!(
+            parse(b"GET / HTTP/1.1\r\nUser-Agent: test\r\nhost: example.com:8080\r\n\r\n"),
+            Sniffed::Domain("example.com".to_string())
+        );
+        assert_eq!(parse(b"GET / HTTP/1.1\r\nHost: a"), Sniffed::Incomplete);
+        assert_eq!(parse(b"GET / HTTP/1.1\r\n\r\n"), Sniffed::Unknown);
+        assert_eq!(parse(b"\x05\x01\x00"), Sniffed::Unknown);
+    }
+
+    #[tokio::test]
+    async fn sniff_and_replay() {
+        let hello = client_hello("example.com");
+        let (stream, mut client) = tokio::io::duplex(SNIFF_BUFFER_SIZE);
+        // split the hello to make sure partial reads are reassembled
+        client.write_all(&hello[..10]).await.unwrap();
+        let rest = hello[10..].to_vec();
+        tokio::spawn(async move { client.write_all(&rest).await.unwrap() });
+
+        let remote = NetLocation {
+            address: Address::Ip(IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4))),
+            port: 443,
+        };
+        let req = sniff(ProxyRequest {
+            remote,
+            source: None,
+            route: None,
+            conn: ProxyConn::ForwardTcp(TcpForwarder {
+                stream: Box::new(stream),
+            }),
+        })
+        .await;
+
+        assert_eq!(
+            req.remote.address,
+            Address::Hostname("example.com".to_string())
+        );
+        assert_eq!(req.remote.port, 443);
+
+        let ProxyConn::ForwardTcp(TcpForwarder { mut stream }) = req.conn else {
+            panic!("unexpected conn");
+        };
+        let mut buf = vec![0u8; hello.len()];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, hello);
+    }
+}
@@ -0,0 +1,45 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use tokio::net::TcpListener;
+
+use super::Egress;
+use crate::config::{
+    egress::{http::ClientConfig as HttpClientConfig, ClientConfig, EgressConfig},
+    transport::{self, ConnectorConfig, ConnectorConfigInner, Endpoint},
+};
+
+pub(crate) async fn egress(id: &str, config: ClientConfig) -> Arc<Egress> {
+    Arc::new(
+        Egress::new(EgressConfig {
+            id: id.to_string(),
+            limit: None,
+            client: config,
+        })
+        .await
+        .unwrap(),
+    )
+}
+
+/// An http egress whose proxy is at `addr`.
+pub(crate) async fn http_egress(id: &str, addr: SocketAddr) -> Arc<Egress> {
+    let connector = ConnectorConfig {
+        inner: ConnectorConfigInner::Tcp(transport::tcp::ConnectorConfig {
+            endpoint: Endpoint::Single {
+                address: addr.ip().to_string(),
+                port: addr.port(),
+            },
+            fwmark: None,
+        }),
+        transport: Default::default(),
+    };
+    egress(id, ClientConfig::Http(HttpClientConfig { connector })).await
+}
+
+/// An http egress pointing at a closed port.
+pub(crate) async fn dead_egress(id: &str) -> Arc<Egress> {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    drop(listener);
+
+    http_egress(id, addr).await
+}
@@ -0,0 +1,330 @@
+//! Geosite files in the text format of v2fly `domain-list-community`: one
+//! rule per line as `[domain:|full:|regexp:|keyword:]value [@attr...]` or
+//! `include:list [@attr|@-attr...]`, `#` starts a comment. Every file of a
+//! data directory is a group named after it.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use itertools::Itertools;
+
+use crate::config::protos::geosite;
+
+use super::GeositeFile;
+
+/// Domains added and removed in a group between two files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GroupDiff {
+    pub tag: String,
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+impl GeositeFile {
+    /// A file without groups, written to `file` by `store`.
+    pub fn empty(file: PathBuf) -> Self {
+        Self {
+            file,
+            sgl: geosite::SiteGroupList::new(),
+        }
+    }
+
+    /// Compiles every list of a data directory, the result is written to
+    /// `file` by `store`. Directories, hidden files and `file` itself, when
+    /// it's kept in the data directory, aren't lists.
+    pub fn compile(dir: &Path, file: PathBuf) -> Result<Self, anyhow::Error> {
+        let output = fs::canonicalize(&file).ok();
+        let mut names = Vec::new();
+        for entry in fs::read_dir(dir).context(format!("Failed to read {}", dir.display()))? {
+            let path = entry?.path();
+            let Some(name) = path.file_name().map(|v| v.to_string_lossy().to_string()) else {
+                continue;
+            };
+            if !path.is_file() || name.starts_with('.') {
+                continue;
+            }
+            if output.is_some() && fs::canonicalize(&path).ok() == output {
+                continue;
+            }
+            names.push(name);
+        }
+        names.sort();
+
+        let mut compiler = Compiler {
+            dir,
+            lists: HashMap::new(),
+            visiting: Vec::new(),
+        };
+        let mut sgl = geosite::SiteGroupList::new();
+        for name in names {
+            sgl.site_group.push(geosite::SiteGroup {
+                tag: name.to_uppercase(),
+                domain: compiler.load(&name)?,
+                ..Default::default()
+            });
+        }
+        Ok(Self { file, sgl })
+    }
+
+    pub fn site_groups(&self) -> &[geosite::SiteGroup] {
+        &self.sgl.site_group
+    }
+
+    /// Adds the groups and domains of `other` missing from this file.
+    pub fn merge(&mut self, other: &GeositeFile) {
+        for sg in &other.sgl.site_group {
+            let target = self.get_site_group_or_create_mut(&sg.tag);
+            let mut seen: HashSet<String> = target.domain.iter().map(format_domain).collect();
+            for domain in &sg.domain {
+                if seen.insert(format_domain(domain)) {
+                    target.domain.push(domain.clone());
+                }
+            }
+        }
+    }
+
+    /// Changes from this file to `other`, by group tag.
+    pub fn diff(&self, other: &GeositeFile) -> Vec<GroupDiff> {
+        let lines = |file: &GeositeFile, tag: &str| -> Vec<String> {
+            file.get_site_group(tag)
+                .map(|sg| sg.domain.iter().map(format_domain).collect())
+                .unwrap_or_default()
+        };
+
+        self.sgl
+            .site_group
+            .iter()
+            .chain(&other.sgl.site_group)
+            .map(|sg| sg.tag.as_str())
+            .unique()
+            .filter_map(|tag| {
+                let (old, new) = (lines(self, tag), lines(other, tag));
+                // kept in file order
+                let missing = |lines: &[String], from: &[String]| -> Vec<String> {
+                    let from: HashSet<&String> = from.iter().collect();
+                    lines
+                        .iter()
+                        .filter(|v| !from.contains(v))
+                        .cloned()
+                        .collect()
+                };
+                let diff = GroupDiff {
+                    tag: tag.to_string(),
+                    added: missing(&new, &old),
+                    removed: missing(&old, &new),
+                };
+                (!diff.added.is_empty() || !diff.removed.is_empty()).then_some(diff)
+            })
+            .collect()
+    }
+}
+
+/// A domain as a line of the text format.
+pub fn format_domain(domain: &geosite::Domain) -> String {
+    let prefix = match domain.type_.enum_value() {
+        Ok(geosite::domain::Type::Plain) => "keyword",
+        Ok(geosite::domain::Type::Regex) => "regexp",
+        Ok(geosite::domain::Type::Domain) => "domain",
+        Ok(geosite::domain::Type::Full) => "full",
+        Err(_) => "unknown",
+    };
+    let mut line = format!("{}:{}", prefix, domain.value);
+    for attr in &domain.attribute {
+        line.push_str(" @");
+        line.push_str(&attr.key);
+    }
+    line
+}
+
+struct Compiler<'a> {
+    dir: &'a Path,
+    /// compiled lists by name
+    lists: HashMap<String, Vec<geosite::Domain>>,
+    /// lists being compiled, to find include cycles
+    visiting: Vec<String>,
+}
+
+impl Compiler<'_> {
+    fn load(&mut self, name: &str) -> Result<Vec<geosite::Domain>, anyhow::Error> {
+        if let Some(list) = self.lists.get(name) {
+            return Ok(list.clone());
+        }
+        if self.visiting.iter().any(|v| v == name) {
+            anyhow::bail!("include cycle: {} -> {}", self.visiting.join(" -> "), name);
+        }
+
+        let path = self.dir.join(name);
+        let text =
+            fs::read_to_string(&path).context(format!("Failed to read {}", path.display()))?;
+
+        self.visiting.push(name.to_string());
+        let mut list: Vec<geosite::Domain> = Vec::new();
+        // domains are told apart by their line
+        let mut seen = HashSet::new();
+        for (i, line) in text.lines().enumerate() {
+            let domains = self
+                .parse_line(line)
+                .context(format!("{}:{}", path.display(), i + 1))?;
+            for domain in domains {
+                if seen.insert(format_domain(&domain)) {
+                    list.push(domain);
+                }
+            }
+        }
+        self.visiting.pop();
+
+        self.lists.insert(name.to_string(), list.clone());
+        Ok(list)
+    }
+
+    fn parse_line(&mut self, line: &str) -> Result<Vec<geosite::Domain>, anyhow::Error> {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut tokens = line.split_whitespace();
+        let Some(rule) = tokens.next() else {
+            return Ok(vec![]);
+        };
+        let attrs: Vec<&str> = tokens
+            .map(|token| {
+                token
+                    .strip_prefix('@')
+                    .context(format!("{} isn't an attribute", token))
+            })
+            .try_collect()?;
+
+        let (kind, value) = rule.split_once(':').unwrap_or(("domain", rule));
+        let type_ = match kind {
+            "include" => {
+                let list = self.load(value)?;
+                return Ok(list
+                    .into_iter()
+                    .filter(|domain| included(domain, &attrs))
+                    .collect());
+            }
+            "domain" => geosite::domain::Type::Domain,
+            "full" => geosite::domain::Type::Full,
+            "regexp" => geosite::domain::Type::Regex,
+            "keyword" => geosite::domain::Type::Plain,
+            _ => anyhow::bail!("{} is not supported", kind),
+        };
+
+        Ok(vec![geosite::Domain {
+            type_: type_.into(),
+            value: match type_ {
+                geosite::domain::Type::Regex => value.to_string(),
+                _ => value.to_lowercase(),
+            },
+            attribute: attrs
+                .into_iter()
+                .map(|key| {
+                    let mut attr = geosite::domain::Attribute::new();
+                    attr.key = key.to_string();
+                    attr.set_bool_value(true);
+                    attr
+                })
+                .collect(),
+            ..Default::default()
+        }])
+    }
+}
+
+/// `@attr` keeps domains having it, `@-attr` drops them.
+fn included(domain: &geosite::Domain, filters: &[&str]) -> bool {
+    let has = |key: &str| domain.attribute.iter().any(|attr| attr.key == key);
+    filters.iter().all(|filter| match filter.strip_prefix('-') {
+        Some(key) => !has(key),
+        None => has(filter),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(dir: &Path, name: &str, text: &str) {
+        fs::write(dir.join(name), text).unwrap();
+    }
+
+    fn lines(file: &GeositeFile, tag: &str) -> Vec<String> {
+        file.get_site_group(tag)
+            .unwrap()
+            .domain
+            .iter()
+            .map(format_domain)
+            .collect()
+    }
+
+    #[test]
+    fn compile_merge_diff() {
+        let dir = std::env::temp_dir().join(format!("mproxy-geosite-data-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        write(
+            &dir,
+            "example",
+            "# comment\nexample.com\nfull:www.example.org @cn\nregexp:^ads?\\. @ads\n",
+        );
+        write(
+            &dir,
+            "all",
+            "include:example @-ads\nkeyword:tracker # trailing\ninclude:example @cn\n",
+        );
+        // none of these are lists
+        write(&dir, ".hidden", "hidden.com\n");
+        write(&dir, "out.dat", "\u{0}binary\n");
+        fs::create_dir_all(dir.join("sub")).unwrap();
+
+        let compiled = GeositeFile::compile(&dir, dir.join("out.dat")).unwrap();
+        let tags: Vec<_> = compiled.site_groups().iter().map(|v| &v.tag).collect();
+        assert_eq!(tags, ["ALL", "EXAMPLE"]);
+        assert_eq!(
+            lines(&compiled, "example"),
+            [
+                "domain:example.com",
+                "full:www.example.org @cn",
+                "regexp:^ads?\\. @ads"
+            ]
+        );
+        assert_eq!(
+            lines(&compiled, "all"),
+            [
+                "domain:example.com",
+                "full:www.example.org @cn",
+                "keyword:tracker"
+            ]
+        );
+
+        write(&dir, "loop", "include:loop\n");
+        let e = GeositeFile::compile(&dir, dir.join("out.dat")).unwrap_err();
+        assert!(format!("{:#}", e).contains("include cycle"), "{:#}", e);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let mut other = GeositeFile::new(&dir.join("missing.dat")).unwrap();
+        other.insert_target("example", "f:example.net").unwrap();
+        other.insert_target("new", "d:example.io").unwrap();
+        assert_eq!(
+            compiled.diff(&other)[..2],
+            [
+                GroupDiff {
+                    tag: "ALL".to_string(),
+                    added: vec![],
+                    removed: lines(&compiled, "all"),
+                },
+                GroupDiff {
+                    tag: "EXAMPLE".to_string(),
+                    added: vec!["full:example.net".to_string()],
+                    removed: lines(&compiled, "example"),
+                },
+            ]
+        );
+
+        let mut merged = compiled;
+        merged.merge(&other);
+        assert_eq!(lines(&merged, "example").len(), 4);
+        assert_eq!(lines(&merged, "new"), ["domain:example.io"]);
+        assert!(merged.diff(&merged).is_empty());
+    }
+}
@@ -1,18 +1,30 @@
-use std::{fs::File, path::PathBuf, str::FromStr, sync::Arc};
+pub mod geosite;
+mod pac;
+
+use std::{
+    cell::OnceCell,
+    fs::File,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    ops::{Range, RangeInclusive},
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
 
 use anyhow::Context;
-use dashmap::DashMap;
 use domain_matcher::{mph::MphMatcher, DomainMatcher, MatchType};
+use ipnet::IpNet;
 use itertools::Itertools;
 use protobuf::Message;
+use regex::RegexSet;
 use tracing::{instrument, warn};
 
 use crate::{
     config::{
-        protos::geosite,
-        routing::{RoutingConfig, RuleConfig},
+        protos::{geoip, geosite},
+        routing::{RoutingConfig, RuleConfig, ANY_SRC},
     },
-    proxy::Address,
+    proxy::{Address, NetLocation},
 };
 
 #[derive(Debug)]
@@ -77,7 +89,8 @@ impl GeositeFile {
                 RuleType::Domain => geosite::domain::Type::Domain,
                 RuleType::Full => geosite::domain::Type::Full,
                 RuleType::SubStr => geosite::domain::Type::Plain,
-                RuleType::Geosite => anyhow::bail!("geosite rule is not supported"),
+                RuleType::Regex => geosite::domain::Type::Regex,
+                _ => anyhow::bail!("{} rule is not supported", rule_type),
             }
             .into(),
             value: value.to_string(),
@@ -113,6 +126,11 @@ impl GeositeFile {
         self.remove_with_domain(tag, &domain)
     }
 
+    pub fn remove_target(&mut self, tag: &str, target: &str) -> Result<(), anyhow::Error> {
+        let (rule_type, value) = parse_target(target)?;
+        self.remove(tag, rule_type, value)
+    }
+
     pub fn remove_with_domain(
         &mut self,
         tag: &str,
@@ -149,15 +167,52 @@ impl GeositeResource {
     }
 }
 
+/// v2ray-format geoip.dat
+#[derive(Debug)]
+pub struct GeoipFile {
+    list: geoip::GeoIPList,
+}
+
+impl GeoipFile {
+    pub fn new(file: &PathBuf) -> Result<Self, anyhow::Error> {
+        let mut f = File::open(file).context(format!("Failed to open {}", file.display()))?;
+        Ok(Self {
+            list: geoip::GeoIPList::parse_from_reader(&mut f).context("Failed to parse geoip")?,
+        })
+    }
+
+    pub fn get_geoip(&self, code: &str) -> Option<&geoip::GeoIP> {
+        self.list
+            .entry
+            .iter()
+            .find(|entry| entry.country_code.eq_ignore_ascii_case(code))
+    }
+}
+
+#[derive(Debug)]
+struct GeoipResource {
+    list: Vec<GeoipFile>,
+}
+
+impl GeoipResource {
+    fn new(path: Vec<PathBuf>) -> Result<Self, anyhow::Error> {
+        Ok(Self {
+            list: path.iter().map(|file| GeoipFile::new(file)).try_collect()?,
+        })
+    }
+}
+
 #[derive(Debug)]
 pub struct Resource {
     geosite: GeositeResource,
+    geoip: GeoipResource,
 }
 
 impl Resource {
-    fn new(path: Vec<PathBuf>) -> Result<Self, anyhow::Error> {
+    fn new(geosite: Vec<PathBuf>, geoip: Vec<PathBuf>) -> Result<Self, anyhow::Error> {
         Ok(Self {
-            geosite: GeositeResource::new(path)?,
+            geosite: GeositeResource::new(geosite)?,
+            geoip: GeoipResource::new(geoip)?,
         })
     }
 
@@ -168,62 +223,238 @@ impl Resource {
             .find_map(|file| file.get_site_group(tag))
             .context(format!("geosite:{} isn't exist", tag))
     }
+
+    fn get_geoip(&self, code: &str) -> Result<&geoip::GeoIP, anyhow::Error> {
+        self.geoip
+            .list
+            .iter()
+            .find_map(|file| file.get_geoip(code))
+            .context(format!("geoip:{} isn't exist", code))
+    }
+}
+
+/// Why a remote is sent where it is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteDecision {
+    pub rule: String,
+    pub dest: String,
+    /// target of the rule that matched, `None` if it matched by port only
+    pub target: Option<String>,
+    /// no rule matched, the default one was taken
+    pub fallback: bool,
 }
 
 #[derive(Debug)]
 pub struct Router {
     _res: Arc<Resource>,
-    rules: DashMap<String, Rule>,
+    /// evaluated in declared order
+    rules: RwLock<Vec<Rule>>,
     default_rule: String,
 }
 
 impl Router {
     pub fn new(config: RoutingConfig) -> Result<Self, anyhow::Error> {
-        let res = Arc::new(Resource::new(config.resource)?);
+        let res = Arc::new(Resource::new(config.resource, config.geoip)?);
 
-        let rules: DashMap<String, Rule> = config
+        let rules: Vec<Rule> = config
             .rule
             .into_iter()
-            .map(|config| {
-                Ok::<_, anyhow::Error>((config.id.clone(), Rule::new(config, res.clone())?))
-            })
+            .map(|config| Rule::new(config, res.clone()))
             .try_collect()?;
 
+        if !rules.iter().any(|rule| rule.id == config.default_rule) {
+            anyhow::bail!("default rule {} is not exist", config.default_rule);
+        }
+
         Ok(Self {
             _res: res,
-            rules,
+            rules: RwLock::new(rules),
             default_rule: config.default_rule,
         })
     }
 
+    /// The first rule matching the remote, or the default rule flagged as
+    /// the fallback.
+    fn find_rule<'a>(
+        &self,
+        rules: &'a [Rule],
+        src: &String,
+        remote: &NetLocation,
+    ) -> Result<(&'a Rule, bool), anyhow::Error> {
+        if let Some(rule) = rules.iter().find(|rule| rule.matches(src, remote)) {
+            return Ok((rule, false));
+        }
+        rules
+            .iter()
+            .find(|rule| rule.id == self.default_rule)
+            .map(|rule| (rule, true))
+            .context("default rule is not exist")
+    }
+
     #[instrument(skip(self))]
-    pub fn route(&self, src: &String, address: &Address) -> Result<String, anyhow::Error> {
-        Ok(self
-            .rules
+    pub fn route(&self, src: &String, remote: &NetLocation) -> Result<String, anyhow::Error> {
+        Ok(self.explain(src, remote)?.dest)
+    }
+
+    /// Returns the id of the rule matching the remote, or the default rule.
+    pub fn match_rule(&self, src: &String, remote: &NetLocation) -> String {
+        self.explain(src, remote)
+            .map(|decision| decision.rule)
+            .unwrap_or(self.default_rule.clone())
+    }
+
+    /// Like `route`, with the rule and target that decided it.
+    pub fn explain(
+        &self,
+        src: &String,
+        remote: &NetLocation,
+    ) -> Result<RouteDecision, anyhow::Error> {
+        let rules = self.rules.read().unwrap();
+        let (rule, fallback) = self.find_rule(&rules, src, remote)?;
+        Ok(RouteDecision {
+            rule: rule.id.clone(),
+            dest: rule.dest.clone(),
+            target: if fallback {
+                None
+            } else {
+                rule.matched_target(remote)
+            },
+            fallback,
+        })
+    }
+
+    /// Loads the resources and every rule target of a config, reporting all
+    /// failures instead of skipping bad targets like `new` does.
+    pub fn check(config: &RoutingConfig) -> Vec<anyhow::Error> {
+        let mut errors = Vec::new();
+        let mut ok = |result: Result<_, anyhow::Error>, context: String| match result {
+            Ok(v) => Some(v),
+            Err(e) => {
+                errors.push(e.context(context));
+                None
+            }
+        };
+
+        let res = Arc::new(Resource {
+            geosite: GeositeResource {
+                list: config
+                    .resource
+                    .iter()
+                    .filter_map(|file| {
+                        ok(
+                            GeositeFile::new(file),
+                            format!("geosite {}", file.display()),
+                        )
+                    })
+                    .collect(),
+            },
+            geoip: GeoipResource {
+                list: config
+                    .geoip
+                    .iter()
+                    .filter_map(|file| {
+                        ok(GeoipFile::new(file), format!("geoip {}", file.display()))
+                    })
+                    .collect(),
+            },
+        });
+
+        for config in &config.rule {
+            let context = || format!("rule {}", config.id);
+            let Some(mut rule) = ok(
+                Rule::new(
+                    RuleConfig {
+                        target: vec![],
+                        ..config.clone()
+                    },
+                    res.clone(),
+                ),
+                context(),
+            ) else {
+                continue;
+            };
+            for target in &config.target {
+                ok(rule.add(target), format!("{} target {}", context(), target));
+            }
+        }
+
+        let ids = config.rule.iter().map(|rule| &rule.id);
+        for id in ids.clone().duplicates() {
+            errors.push(anyhow::anyhow!("rule {} is defined more than once", id));
+        }
+        if !ids.clone().any(|id| *id == config.default_rule) {
+            errors.push(anyhow::anyhow!(
+                "default rule {} is not exist",
+                config.default_rule
+            ));
+        }
+        errors
+    }
+
+    /// Ids and destinations of the rules in evaluation order.
+    pub fn rules(&self) -> Vec<(String, String)> {
+        self.rules
+            .read()
+            .unwrap()
             .iter()
-            .find(|rule| rule.src.contains(src) && rule.matcher.reverse_query(&address.to_string()))
-            .map(|rule| rule.dest.clone())
-            .unwrap_or(
-                self.rules
-                    .get(&self.default_rule)
-                    .context("default rule is not exist")?
-                    .dest
-                    .clone(),
-            ))
+            .map(|rule| (rule.id.clone(), rule.dest.clone()))
+            .collect()
     }
 
     pub fn add_rule_target(&self, id: &str, target: &str) -> Result<(), anyhow::Error> {
         self.rules
-            .get_mut(id)
+            .write()
+            .unwrap()
+            .iter_mut()
+            .find(|rule| rule.id == id)
             .context(format!("{} is not exist", id))?
             .add(target)
     }
 }
 
+#[derive(Debug)]
+struct IpSet {
+    cidr: Vec<IpNet>,
+    reverse: bool,
+}
+
+impl IpSet {
+    fn contains(&self, ip: &IpAddr) -> bool {
+        self.cidr.iter().any(|net| net.contains(ip)) != self.reverse
+    }
+}
+
+/// What a target was inserted as, to tell which target matched.
+enum Entry {
+    /// the next three are in the rule's mph matcher too
+    Domain(String),
+    Full(String),
+    SubStr(String),
+    /// indexes into the rule's regex set
+    Regex(Range<usize>),
+    Ip(IpNet),
+    /// index into the rule's ip sets
+    IpSet(usize),
+    /// a group's own matcher, its regex domains are in the rule's regex set
+    Geosite {
+        matcher: MphMatcher,
+        regex: Range<usize>,
+    },
+}
+
 pub struct Rule {
     pub id: String,
     matcher: MphMatcher,
+    /// regex domains, evaluated after the mph matchers
+    regex: Vec<String>,
+    regex_set: RegexSet,
+    ip: Vec<IpSet>,
+    /// destination targets, a rule without any only matches by port
+    target: Vec<String>,
+    /// what each target was inserted as, in the same order
+    entries: Vec<Entry>,
     src: Vec<String>,
+    port: Vec<RangeInclusive<u16>>,
     dest: String,
 
     res: Arc<Resource>,
@@ -239,7 +470,26 @@ pub enum RuleType {
     Domain,
     Full,
     SubStr,
+    Regex,
     Geosite,
+    Ip,
+    Cidr,
+    Geoip,
+}
+
+impl std::fmt::Display for RuleType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            RuleType::Domain => "d",
+            RuleType::Full => "f",
+            RuleType::SubStr => "s",
+            RuleType::Regex => "r",
+            RuleType::Geosite => "geosite",
+            RuleType::Ip => "ip",
+            RuleType::Cidr => "cidr",
+            RuleType::Geoip => "geoip",
+        })
+    }
 }
 
 impl TryFrom<RuleType> for geosite::domain::Type {
@@ -250,7 +500,8 @@ impl TryFrom<RuleType> for geosite::domain::Type {
             RuleType::Domain => geosite::domain::Type::Domain,
             RuleType::Full => geosite::domain::Type::Full,
             RuleType::SubStr => geosite::domain::Type::Plain,
-            RuleType::Geosite => anyhow::bail!("geosite rule is not supported"),
+            RuleType::Regex => geosite::domain::Type::Regex,
+            _ => anyhow::bail!("{} rule is not supported", rule_type),
         })
     }
 }
@@ -262,8 +513,12 @@ impl FromStr for RuleType {
         Ok(match s {
             "f" => Self::Full,
             "s" => Self::SubStr,
+            "r" => Self::Regex,
             "d" => Self::Domain,
             "geosite" => Self::Geosite,
+            "ip" => Self::Ip,
+            "cidr" => Self::Cidr,
+            "geoip" => Self::Geoip,
             _ => anyhow::bail!("{} is not supported", s),
         })
     }
@@ -274,13 +529,41 @@ fn parse_target(value: &str) -> Result<(RuleType, &str), anyhow::Error> {
     Ok((RuleType::from_str(rule_type)?, value))
 }
 
+fn parse_port(value: &str) -> Result<RangeInclusive<u16>, anyhow::Error> {
+    Ok(match value.split_once('-') {
+        Some((start, end)) => start.trim().parse()?..=end.trim().parse()?,
+        None => {
+            let port = value.trim().parse()?;
+            port..=port
+        }
+    })
+}
+
+fn cidr_from_proto(cidr: &geoip::CIDR) -> Result<IpNet, anyhow::Error> {
+    let ip = match cidr.ip.len() {
+        4 => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(&cidr.ip[..])?)),
+        16 => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&cidr.ip[..])?)),
+        n => anyhow::bail!("invalid ip length {}", n),
+    };
+    Ok(IpNet::new(ip, cidr.prefix as u8)?)
+}
+
 impl Rule {
     pub fn new(config: RuleConfig, res: Arc<Resource>) -> Result<Self, anyhow::Error> {
         let matcher = MphMatcher::new(1);
         let mut this = Self {
             id: config.id,
             matcher,
+            regex: vec![],
+            regex_set: RegexSet::empty(),
+            ip: vec![IpSet {
+                cidr: vec![],
+                reverse: false,
+            }],
+            target: vec![],
+            entries: vec![],
             src: config.src,
+            port: config.port.iter().map(|port| parse_port(port)).try_collect()?,
             dest: config.dest,
 
             res,
@@ -297,33 +580,350 @@ impl Rule {
 
     pub fn add(&mut self, target: &str) -> Result<(), anyhow::Error> {
         let (rule_type, value) = parse_target(&target)?;
-        match rule_type {
-            RuleType::Domain => self.matcher.reverse_insert(value, MatchType::Domain(true)),
-            RuleType::Full => self.matcher.reverse_insert(value, MatchType::Full(true)),
-            RuleType::SubStr => self.matcher.reverse_insert(value, MatchType::SubStr(true)),
+        let entry = match rule_type {
+            RuleType::Domain => {
+                self.matcher.reverse_insert(value, MatchType::Domain(true));
+                Entry::Domain(value.to_string())
+            }
+            RuleType::Full => {
+                self.matcher.reverse_insert(value, MatchType::Full(true));
+                Entry::Full(value.to_string())
+            }
+            RuleType::SubStr => {
+                self.matcher.reverse_insert(value, MatchType::SubStr(true));
+                Entry::SubStr(value.to_string())
+            }
+            RuleType::Regex => Entry::Regex(self.insert_regex([value])?),
             RuleType::Geosite => self.insert_geosite(value)?,
-        }
+            RuleType::Ip => self.insert_ip(IpNet::from(IpAddr::from_str(value)?)),
+            RuleType::Cidr => self.insert_ip(IpNet::from_str(value)?),
+            RuleType::Geoip => self.insert_geoip(value)?,
+        };
         self.matcher.build();
+        self.target.push(target.to_string());
+        self.entries.push(entry);
         Ok(())
     }
 
-    fn insert_geosite(&mut self, tag: &str) -> Result<(), anyhow::Error> {
-        let sg = self.res.get_geosite_tag(tag)?;
+    fn matches(&self, src: &String, remote: &NetLocation) -> bool {
+        if !self.matches_src(src) {
+            return false;
+        }
+        if !self.port.is_empty() && !self.port.iter().any(|port| port.contains(&remote.port)) {
+            return false;
+        }
+        if self.target.is_empty() {
+            return !self.port.is_empty();
+        }
+
+        match &remote.address {
+            Address::Ip(ip) if self.ip.iter().any(|set| set.contains(ip)) => true,
+            address => {
+                let address = address.to_string();
+                self.matcher.reverse_query(&address)
+                    || self.entries.iter().any(|entry| match entry {
+                        Entry::Geosite { matcher, .. } => matcher.reverse_query(&address),
+                        _ => false,
+                    })
+                    || self.regex_set.is_match(&address)
+            }
+        }
+    }
+
+    fn matches_src(&self, src: &str) -> bool {
+        self.src.iter().any(|v| v == ANY_SRC || v == src)
+    }
+
+    /// The first target matching the remote, told by what it was inserted as.
+    fn matched_target(&self, remote: &NetLocation) -> Option<String> {
+        let ip = match &remote.address {
+            Address::Ip(ip) => Some(*ip),
+            _ => None,
+        };
+        let address = remote.address.to_string();
+        // only run when a regex target is reached
+        let regex_matches = OnceCell::new();
+        let regex_matched = |range: &Range<usize>| {
+            let matches = regex_matches.get_or_init(|| self.regex_set.matches(&address));
+            range.clone().any(|i| matches.matched(i))
+        };
+
+        self.target
+            .iter()
+            .zip(&self.entries)
+            .find(|(_, entry)| match entry {
+                Entry::Domain(domain) => address
+                    .strip_suffix(domain.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.ends_with('.')),
+                Entry::Full(full) => address == *full,
+                Entry::SubStr(sub) => address.contains(sub.as_str()),
+                Entry::Regex(range) => regex_matched(range),
+                Entry::Ip(net) => ip.is_some_and(|ip| net.contains(&ip)),
+                Entry::IpSet(i) => ip.is_some_and(|ip| self.ip[*i].contains(&ip)),
+                Entry::Geosite { matcher, regex } => {
+                    matcher.reverse_query(&address) || regex_matched(regex)
+                }
+            })
+            .map(|(target, _)| target.clone())
+    }
+
+    /// Returns the indexes of the inserted patterns in the regex set.
+    fn insert_regex<'a>(
+        &mut self,
+        patterns: impl IntoIterator<Item = &'a str>,
+    ) -> Result<Range<usize>, anyhow::Error> {
+        let mut regex = self.regex.clone();
+        regex.extend(patterns.into_iter().map(|pattern| pattern.to_string()));
+        self.regex_set = RegexSet::new(&regex)?;
+        let inserted = self.regex.len()..regex.len();
+        self.regex = regex;
+        Ok(inserted)
+    }
+
+    fn insert_ip(&mut self, net: IpNet) -> Entry {
+        self.ip[0].cidr.push(net);
+        Entry::Ip(net)
+    }
+
+    fn insert_geoip(&mut self, code: &str) -> Result<Entry, anyhow::Error> {
+        let geoip = self.res.get_geoip(code)?;
+        self.ip.push(IpSet {
+            cidr: geoip.cidr.iter().map(cidr_from_proto).try_collect()?,
+            reverse: geoip.reverse_match,
+        });
+        Ok(Entry::IpSet(self.ip.len() - 1))
+    }
+
+    fn insert_geosite(&mut self, tag: &str) -> Result<Entry, anyhow::Error> {
+        let res = self.res.clone();
+        let sg = res.get_geosite_tag(tag)?;
+
+        // compiled once per group, a `RegexSet` can't be extended in place
+        let regex = self.insert_regex(
+            sg.domain
+                .iter()
+                .filter(|domain| domain.type_.enum_value() == Ok(geosite::domain::Type::Regex))
+                .map(|domain| domain.value.as_str()),
+        )?;
+
+        let mut matcher = MphMatcher::new(1);
         for domain in &sg.domain {
             match domain.type_.unwrap() {
-                geosite::domain::Type::Plain => self
-                    .matcher
-                    .reverse_insert(&domain.value, MatchType::SubStr(true)),
-                geosite::domain::Type::Domain => self
-                    .matcher
-                    .reverse_insert(&domain.value, MatchType::Domain(true)),
-                geosite::domain::Type::Full => self
-                    .matcher
-                    .reverse_insert(&domain.value, MatchType::Full(true)),
+                geosite::domain::Type::Plain => {
+                    matcher.reverse_insert(&domain.value, MatchType::SubStr(true))
+                }
+                geosite::domain::Type::Domain => {
+                    matcher.reverse_insert(&domain.value, MatchType::Domain(true))
+                }
+                geosite::domain::Type::Full => {
+                    matcher.reverse_insert(&domain.value, MatchType::Full(true))
+                }
+                // inserted above
                 geosite::domain::Type::Regex => {}
             }
         }
+        matcher.build();
 
-        Ok(())
+        Ok(Entry::Geosite { matcher, regex })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(id: &str, target: &[&str], src: &[&str], port: &[&str], dest: &str) -> RuleConfig {
+        RuleConfig {
+            id: id.to_string(),
+            target: target.iter().map(|v| v.to_string()).collect(),
+            src: src.iter().map(|v| v.to_string()).collect(),
+            port: port.iter().map(|v| v.to_string()).collect(),
+            dest: dest.to_string(),
+            limit: None,
+        }
+    }
+
+    fn remote(s: &str) -> NetLocation {
+        NetLocation::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn ip_port_and_order() {
+        let router = Router::new(RoutingConfig {
+            resource: vec![],
+            geoip: vec![],
+            rule: vec![
+                rule("lan", &["cidr:10.0.0.0/8", "ip:fd00::1"], &["*"], &[], "direct"),
+                rule("ssh", &[], &["socks"], &["22", "2200-2299"], "ssh"),
+                rule("site", &["d:example.com"], &["*"], &["443"], "proxy"),
+                rule("default", &[], &["*"], &[], "fallback"),
+            ],
+            default_rule: "default".to_string(),
+        })
+        .unwrap();
+
+        let src = "socks".to_string();
+        let route = |s: &str| router.route(&src, &remote(s)).unwrap();
+        assert_eq!(route("10.1.2.3:22"), "direct");
+        assert_eq!(route("[fd00::1]:80"), "direct");
+        assert_eq!(route("11.0.0.1:2222"), "ssh");
+        assert_eq!(route("www.example.com:443"), "proxy");
+        assert_eq!(route("www.example.com:80"), "fallback");
+        assert_eq!(route("11.0.0.1:80"), "fallback");
+        assert_eq!(
+            router
+                .route(&"http".to_string(), &remote("11.0.0.1:22"))
+                .unwrap(),
+            "fallback"
+        );
+    }
+
+    #[test]
+    fn regex() {
+        let list = geosite::SiteGroupList {
+            site_group: vec![geosite::SiteGroup {
+                tag: "TEST".to_string(),
+                domain: vec![
+                    geosite::Domain {
+                        type_: geosite::domain::Type::Regex.into(),
+                        value: r"^ads?\d*\.".to_string(),
+                        ..Default::default()
+                    },
+                    geosite::Domain {
+                        type_: geosite::domain::Type::Full.into(),
+                        value: "tracker.example.com".to_string(),
+                        ..Default::default()
+                    },
+                ],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let file = std::env::temp_dir().join(format!("mproxy-geosite-{}.dat", std::process::id()));
+        list.write_to_writer(&mut File::create(&file).unwrap())
+            .unwrap();
+
+        let router = Router::new(RoutingConfig {
+            resource: vec![file.clone()],
+            geoip: vec![],
+            rule: vec![
+                rule("block", &["geosite:test", r"r:\.doubleclick\.net$"], &["*"], &[], "reject"),
+                rule("default", &[], &["*"], &[], "proxy"),
+            ],
+            default_rule: "default".to_string(),
+        })
+        .unwrap();
+        std::fs::remove_file(file).unwrap();
+
+        let src = "socks".to_string();
+        let route = |s: &str| router.route(&src, &remote(s)).unwrap();
+        assert_eq!(route("ad1.example.com:443"), "reject");
+        assert_eq!(route("ads.example.org:443"), "reject");
+        assert_eq!(route("tracker.example.com:443"), "reject");
+        assert_eq!(route("stats.g.doubleclick.net:443"), "reject");
+        assert_eq!(route("bad.example.com:443"), "proxy");
+        assert_eq!(route("www.example.com:443"), "proxy");
+    }
+
+    #[test]
+    fn geoip() {
+        let list = geoip::GeoIPList {
+            entry: vec![geoip::GeoIP {
+                country_code: "PRIVATE".to_string(),
+                cidr: vec![
+                    geoip::CIDR {
+                        ip: vec![192, 168, 0, 0],
+                        prefix: 16,
+                        ..Default::default()
+                    },
+                    geoip::CIDR {
+                        ip: Ipv6Addr::from_str("fc00::").unwrap().octets().to_vec(),
+                        prefix: 7,
+                        ..Default::default()
+                    },
+                ],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let file = std::env::temp_dir().join(format!("mproxy-geoip-{}.dat", std::process::id()));
+        list.write_to_writer(&mut File::create(&file).unwrap())
+            .unwrap();
+
+        let router = Router::new(RoutingConfig {
+            resource: vec![],
+            geoip: vec![file.clone()],
+            rule: vec![
+                rule("private", &["geoip:private"], &["*"], &[], "direct"),
+                rule("default", &[], &["*"], &[], "proxy"),
+            ],
+            default_rule: "default".to_string(),
+        })
+        .unwrap();
+        std::fs::remove_file(file).unwrap();
+
+        let src = "socks".to_string();
+        assert_eq!(router.route(&src, &remote("192.168.1.1:80")).unwrap(), "direct");
+        assert_eq!(router.route(&src, &remote("[fd00::1]:80")).unwrap(), "direct");
+        assert_eq!(router.route(&src, &remote("8.8.8.8:53")).unwrap(), "proxy");
+    }
+
+    #[test]
+    fn src() {
+        let router = Router::new(RoutingConfig {
+            resource: vec![],
+            geoip: vec![],
+            rule: vec![
+                rule("none", &["d:example.com"], &[], &[], "reject"),
+                rule("http", &["d:example.com"], &["http"], &[], "direct"),
+                rule("any", &["d:example.com"], &["*"], &[], "proxy"),
+                rule("default", &[], &["*"], &[], "fallback"),
+            ],
+            default_rule: "default".to_string(),
+        })
+        .unwrap();
+
+        let route = |src: &str| {
+            router
+                .route(&src.to_string(), &remote("example.com:443"))
+                .unwrap()
+        };
+        assert_eq!(route("http"), "direct");
+        assert_eq!(route("socks"), "proxy");
+    }
+
+    #[test]
+    fn explain() {
+        let router = Router::new(RoutingConfig {
+            resource: vec![],
+            geoip: vec![],
+            rule: vec![
+                rule("site", &["d:example.com", "d:org"], &["*"], &[], "proxy"),
+                rule("lan", &[r"r:^internal\.", "cidr:10.0.0.0/8"], &["*"], &[], "direct"),
+                rule("ssh", &[], &["*"], &["22"], "direct"),
+                rule("default", &[], &["*"], &[], "fallback"),
+            ],
+            default_rule: "default".to_string(),
+        })
+        .unwrap();
+
+        let src = "socks".to_string();
+        let explain = |s: &str| router.explain(&src, &remote(s)).unwrap();
+        assert_eq!(
+            explain("www.example.org:443"),
+            RouteDecision {
+                rule: "site".to_string(),
+                dest: "proxy".to_string(),
+                target: Some("d:org".to_string()),
+                fallback: false,
+            }
+        );
+        let lan = |s: &str| explain(s).target.unwrap();
+        assert_eq!(lan("internal.corp:80"), r"r:^internal\.");
+        assert_eq!(lan("10.0.0.1:80"), "cidr:10.0.0.0/8");
+        let ssh = explain("1.1.1.1:22");
+        assert_eq!((ssh.rule.as_str(), ssh.target), ("ssh", None));
+        let other = explain("1.1.1.1:80");
+        assert_eq!((other.dest.as_str(), other.fallback), ("fallback", true));
     }
 }
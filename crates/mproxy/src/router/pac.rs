@@ -0,0 +1,191 @@
+use serde::Serialize;
+
+use crate::config::protos::geosite;
+
+use super::{parse_target, Router, RuleType};
+
+/// Host patterns of a rule in the shape the PAC script checks them.
+#[derive(Debug, Default, Serialize)]
+struct PacRule {
+    domain: Vec<String>,
+    full: Vec<String>,
+    keyword: Vec<String>,
+    regexp: Vec<String>,
+}
+
+impl PacRule {
+    fn is_empty(&self) -> bool {
+        self.domain.is_empty()
+            && self.full.is_empty()
+            && self.keyword.is_empty()
+            && self.regexp.is_empty()
+    }
+
+    fn push(&mut self, rule_type: geosite::domain::Type, value: &str) {
+        let list = match rule_type {
+            geosite::domain::Type::Domain => &mut self.domain,
+            geosite::domain::Type::Full => &mut self.full,
+            geosite::domain::Type::Plain => &mut self.keyword,
+            geosite::domain::Type::Regex => &mut self.regexp,
+        };
+        list.push(value.to_string());
+    }
+}
+
+/// Looked up by the hosts and their parent domains, regexps are compiled
+/// once when the script is loaded.
+const PAC_SCRIPT: &str = r#"
+function set(list) {
+  var set = {};
+  for (var i = 0; i < list.length; i++) set[list[i]] = true;
+  return set;
+}
+
+function has(set, key) {
+  return Object.prototype.hasOwnProperty.call(set, key);
+}
+
+for (var i = 0; i < rules.length; i++) {
+  var rule = rules[i][1];
+  var regexp = [];
+  for (var j = 0; j < rule.regexp.length; j++) regexp.push(new RegExp(rule.regexp[j]));
+  rules[i][1] = {
+    full: set(rule.full),
+    domain: set(rule.domain),
+    keyword: rule.keyword,
+    regexp: regexp
+  };
+}
+
+function matches(host, rule) {
+  if (has(rule.full, host)) return true;
+  for (var suffix = host; ; suffix = suffix.slice(suffix.indexOf(".") + 1)) {
+    if (has(rule.domain, suffix)) return true;
+    if (suffix.indexOf(".") < 0) break;
+  }
+  for (var i = 0; i < rule.keyword.length; i++) {
+    if (host.indexOf(rule.keyword[i]) >= 0) return true;
+  }
+  for (var i = 0; i < rule.regexp.length; i++) {
+    if (rule.regexp[i].test(host)) return true;
+  }
+  return false;
+}
+
+function FindProxyForURL(url, host) {
+  host = host.toLowerCase();
+  for (var i = 0; i < rules.length; i++) {
+    if (matches(host, rules[i][1])) return rules[i][0];
+  }
+  return fallback;
+}
+"#;
+
+impl Router {
+    /// Renders the rules seen by requests from `src` as a proxy auto-config
+    /// script, `action` gives the result for a rule dest like `DIRECT` or
+    /// `PROXY host:port`. Browsers would have to resolve hosts for ip
+    /// targets and don't tell the port apart, such targets and rules limited
+    /// to ports are left out.
+    pub fn pac(&self, src: &String, action: impl Fn(&str) -> String) -> String {
+        let rules = self.rules.read().unwrap();
+
+        let mut pac_rules = Vec::new();
+        for rule in rules.iter() {
+            if !rule.matches_src(src) || !rule.port.is_empty() {
+                continue;
+            }
+
+            let mut pac_rule = PacRule::default();
+            for target in &rule.target {
+                let Ok((rule_type, value)) = parse_target(target) else {
+                    continue;
+                };
+                match rule_type {
+                    RuleType::Geosite => {
+                        if let Ok(sg) = rule.res.get_geosite_tag(value) {
+                            for domain in &sg.domain {
+                                if let Ok(rule_type) = domain.type_.enum_value() {
+                                    pac_rule.push(rule_type, &domain.value);
+                                }
+                            }
+                        }
+                    }
+                    RuleType::Ip | RuleType::Cidr | RuleType::Geoip => {}
+                    rule_type => {
+                        if let Ok(rule_type) = geosite::domain::Type::try_from(rule_type) {
+                            pac_rule.push(rule_type, value);
+                        }
+                    }
+                }
+            }
+            if !pac_rule.is_empty() {
+                pac_rules.push((action(&rule.dest), pac_rule));
+            }
+        }
+
+        let fallback = rules
+            .iter()
+            .find(|rule| rule.id == self.default_rule)
+            .map(|rule| action(&rule.dest))
+            .unwrap_or("DIRECT".to_string());
+
+        format!(
+            "var rules = {};\nvar fallback = {};\n{}",
+            serde_json::to_string(&pac_rules).unwrap_or("[]".to_string()),
+            serde_json::to_string(&fallback).unwrap_or("\"DIRECT\"".to_string()),
+            PAC_SCRIPT
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::routing::{RoutingConfig, RuleConfig};
+
+    use super::*;
+
+    #[test]
+    fn render() {
+        let rule = |id: &str, target: &[&str], port: &[&str], dest: &str| RuleConfig {
+            id: id.to_string(),
+            target: target.iter().map(|v| v.to_string()).collect(),
+            src: vec!["*".to_string()],
+            port: port.iter().map(|v| v.to_string()).collect(),
+            dest: dest.to_string(),
+            limit: None,
+        };
+        let router = Router::new(RoutingConfig {
+            resource: vec![],
+            geoip: vec![],
+            rule: vec![
+                rule("lan", &["cidr:10.0.0.0/8", "f:router.lan"], &[], "direct"),
+                rule("ssh", &["d:example.org"], &["22"], "direct"),
+                rule(
+                    "site",
+                    &["d:example.com", "s:tracker", "r:^ads?\\."],
+                    &[],
+                    "proxy",
+                ),
+                rule("default", &[], &[], "direct"),
+            ],
+            default_rule: "default".to_string(),
+        })
+        .unwrap();
+
+        let pac = router.pac(&"http".to_string(), |dest| match dest {
+            "direct" => "DIRECT".to_string(),
+            _ => "PROXY 127.0.0.1:8080".to_string(),
+        });
+        let rules = pac.lines().next().unwrap();
+        assert_eq!(
+            rules,
+            "var rules = [\
+            [\"DIRECT\",{\"domain\":[],\"full\":[\"router.lan\"],\"keyword\":[],\"regexp\":[]}],\
+            [\"PROXY 127.0.0.1:8080\",{\"domain\":[\"example.com\"],\"full\":[],\
+            \"keyword\":[\"tracker\"],\"regexp\":[\"^ads?\\\\.\"]}]];"
+        );
+        assert!(pac.contains("var fallback = \"DIRECT\";"));
+        assert!(pac.contains("function FindProxyForURL(url, host)"));
+    }
+}
@@ -1,10 +1,13 @@
 use std::{
     collections::HashMap,
-    fmt, ops,
+    fmt,
+    future::Future,
+    ops,
     sync::{
         atomic::{AtomicU64, Ordering},
         Arc, Weak,
     },
+    time::{Duration, SystemTime},
 };
 
 use tokio::sync::RwLock;
@@ -48,6 +51,89 @@ impl ops::AddAssign for TransferStats {
 #[derive(Debug, Clone, Default)]
 pub struct Stats {
     pub transfer: HashMap<String, TransferStats>,
+    /// busiest routing rules first
+    pub rules: Vec<(String, TransferStats)>,
+    /// busiest destination hosts first
+    pub hosts: Vec<(String, TransferStats)>,
+}
+
+/// Bytes credited per key, such as a rule id or a host. At most `capacity`
+/// keys are kept, the least busy one makes room for a new key.
+#[derive(Debug, Clone)]
+pub struct TopTable {
+    capacity: usize,
+    entries: HashMap<String, TransferStats>,
+}
+
+impl TopTable {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, key: &str, stats: TransferStats) {
+        if let Some(v) = self.entries.get_mut(key) {
+            *v += stats;
+            return;
+        }
+
+        if self.entries.len() >= self.capacity {
+            let least = self
+                .entries
+                .iter()
+                .min_by_key(|(_, v)| v.tx + v.rx)
+                .map(|(k, _)| k.clone());
+            if let Some(least) = least {
+                self.entries.remove(&least);
+            }
+        }
+        self.entries.insert(key.to_string(), stats);
+    }
+
+    /// Halves every entry and drops the ones down to zero, keys that stopped
+    /// forwarding fade out of the table.
+    pub fn decay(&mut self) {
+        self.entries.retain(|_, v| {
+            v.tx /= 2;
+            v.rx /= 2;
+            v.tx + v.rx > 0
+        });
+    }
+
+    /// The `n` busiest keys, by bytes in both directions.
+    pub fn top(&self, n: usize) -> Vec<(String, TransferStats)> {
+        let mut top: Vec<_> = self
+            .entries
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        top.sort_by(|(ka, a), (kb, b)| (b.tx + b.rx).cmp(&(a.tx + a.rx)).then(ka.cmp(kb)));
+        top.truncate(n);
+        top
+    }
+}
+
+/// Result of the last health check of an egress.
+#[derive(Debug, Clone)]
+pub struct HealthStatus {
+    pub healthy: bool,
+    /// round-trip time of the probe, `None` if it failed
+    pub latency: Option<Duration>,
+    pub checked_at: SystemTime,
+    pub error: Option<String>,
+}
+
+tokio::task_local! {
+    /// Set while the current task forwards traffic of its own, such as a
+    /// health probe, which no monitor should count.
+    static UNCOUNTED: ();
+}
+
+/// Runs `f` with the forwarders it binds kept out of every monitor.
+pub async fn uncounted<F: Future>(f: F) -> F::Output {
+    UNCOUNTED.scope((), f).await
 }
 
 pub trait GetTransferStats: Send + Sync {
@@ -80,7 +166,19 @@ impl TransferMonitor {
         Ok(s)
     }
 
+    /// Counters without waiting, `None` while a forwarder is being bound.
+    pub fn try_get_transfer_stats(&self) -> Option<TransferStats> {
+        let c = self.c.try_read().ok()?;
+        Some(c.iter().fold(TransferStats::new(), |mut s, elem| {
+            s += elem.get_transfer_stats();
+            s
+        }))
+    }
+
     pub async fn bind(&self, v: Arc<dyn GetTransferStats>) {
+        if UNCOUNTED.try_with(|_| ()).is_ok() {
+            return;
+        }
         self.c.write().await.insert(v);
     }
 }
@@ -102,3 +200,63 @@ impl GetTransferStats for Copyed {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(tx: usize, rx: usize) -> TransferStats {
+        TransferStats { tx, rx }
+    }
+
+    #[test]
+    fn top_table() {
+        let mut table = TopTable::new(3);
+        table.add("a", stats(10, 0));
+        table.add("b", stats(5, 20));
+        table.add("a", stats(0, 30));
+        table.add("c", stats(1, 1));
+
+        let top = table.top(2);
+        assert_eq!(top.len(), 2);
+        assert_eq!(top[0].0, "a");
+        assert_eq!((top[0].1.tx, top[0].1.rx), (10, 30));
+        assert_eq!(top[1].0, "b");
+
+        // full, the least busy key is evicted
+        table.add("d", stats(100, 0));
+        let keys: Vec<_> = table.top(10).into_iter().map(|(k, _)| k).collect();
+        assert_eq!(keys, ["d", "a", "b"]);
+
+        table.decay();
+        let top = table.top(10);
+        assert_eq!(top.len(), 3);
+        assert_eq!((top[0].1.tx, top[0].1.rx), (50, 0));
+        assert_eq!((top[1].1.tx, top[1].1.rx), (5, 15));
+        assert_eq!((top[2].1.tx, top[2].1.rx), (2, 10));
+        for _ in 0..5 {
+            table.decay();
+        }
+        let keys: Vec<_> = table.top(10).into_iter().map(|(k, _)| k).collect();
+        assert_eq!(keys, ["d"]);
+    }
+
+    #[tokio::test]
+    async fn uncounted_bind() {
+        let monitor = TransferMonitor::new();
+        let counted = Arc::new(Copyed::new((
+            Arc::new(AtomicU64::new(1)),
+            Arc::new(AtomicU64::new(2)),
+        )));
+        let probe = Arc::new(Copyed::new((
+            Arc::new(AtomicU64::new(10)),
+            Arc::new(AtomicU64::new(20)),
+        )));
+
+        monitor.bind(counted.clone()).await;
+        uncounted(monitor.bind(probe.clone())).await;
+
+        let s = monitor.get_transfer_stats().await.unwrap();
+        assert_eq!((s.tx, s.rx), (1, 2));
+    }
+}
@@ -113,6 +113,10 @@ async fn remove_proxy_rule(
     .await
 }
 
+async fn reload_proxy(app: Res<ProxyService>) -> Result<(), anyhow::Error> {
+    with_notify_result("reload proxy", || async move { Ok(app.reload().await?) }).await
+}
+
 pub async fn register(cmder: Res<Cmder>) -> Result<(), anyhow::Error> {
     cmder
         .add_command(add_proxy_rule.name("add_proxy_rule"))
@@ -120,6 +124,11 @@ pub async fn register(cmder: Res<Cmder>) -> Result<(), anyhow::Error> {
             remove_proxy_rule
                 .name("remove_proxy_rule")
                 .desc("remove proxy rule from file"),
+        )
+        .add_command(
+            reload_proxy
+                .name("reload_proxy")
+                .desc("reload proxy config without dropping connections"),
         );
 
     Ok(())
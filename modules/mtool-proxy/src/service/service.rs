@@ -1,8 +1,13 @@
-use std::{path::PathBuf, sync::Mutex};
+use std::{collections::HashMap, path::PathBuf, sync::Mutex};
 
 use anyhow::Context;
 use mapp::provider::Res;
-use mproxy::{router::GeositeFile, stats::Stats, App, AppConfig};
+use mproxy::{
+    conntrack::ConnectionInfo,
+    router::GeositeFile,
+    stats::{HealthStatus, Stats},
+    App, AppConfig,
+};
 use mtool_core::ConfigStore;
 use serde::Deserialize;
 use tokio::fs;
@@ -15,6 +20,7 @@ struct Config {
 }
 
 pub struct ProxyService {
+    config: Config,
     pub proxy_id: String,
     pub resource: Mutex<GeositeFile>,
     pub inner: App,
@@ -27,24 +33,36 @@ impl ProxyService {
             .await
             .context("Failed to parse proxy")?;
 
-        let mut app_config = toml::from_str::<AppConfig>(&fs::read_to_string(config.path).await?)?;
-
-        app_config
-            .routing
-            .resource
-            .push(config.resource_path.clone());
-
-        let app = App::new(app_config)
+        let app = App::new(Self::load_app_config(&config).await?)
             .await
             .context("Failed to create proxy service")?;
 
         Ok(Res::new(Self {
             inner: app,
-            proxy_id: config.proxy_id,
+            proxy_id: config.proxy_id.clone(),
             resource: Mutex::new(GeositeFile::new(&config.resource_path)?),
+            config,
         }))
     }
 
+    async fn load_app_config(config: &Config) -> Result<AppConfig, anyhow::Error> {
+        let mut app_config = toml::from_str::<AppConfig>(&fs::read_to_string(&config.path).await?)?;
+
+        app_config
+            .routing
+            .resource
+            .push(config.resource_path.clone());
+
+        Ok(app_config)
+    }
+
+    /// Re-reads the proxy config and applies it without dropping connections.
+    pub async fn reload(&self) -> Result<(), anyhow::Error> {
+        self.inner
+            .reload(Self::load_app_config(&self.config).await?)
+            .await
+    }
+
     pub async fn run(&self) -> Result<(), anyhow::Error> {
         self.inner.run().await
     }
@@ -52,4 +70,16 @@ impl ProxyService {
     pub async fn stats(&self) -> Result<Stats, anyhow::Error> {
         self.inner.stats().await
     }
+
+    pub async fn health(&self) -> HashMap<String, HealthStatus> {
+        self.inner.health().await
+    }
+
+    pub async fn connections(&self) -> Vec<ConnectionInfo> {
+        self.inner.connections().await
+    }
+
+    pub fn close_connection(&self, id: u64) -> bool {
+        self.inner.close_connection(id)
+    }
 }